@@ -0,0 +1,94 @@
+// DAG checkpoints
+//
+// A checkpoint holds finalised frames up to a frame number, events needed
+// to continue from there, heads of all creators and clocks of the node.
+// Events needed are those not finalised yet, along with their finalised
+// parents, and the latest event of every creator. The file is bincode
+// encoded and starts with the checkpoint format version.
+
+use crate::errors::{Error, Result};
+use crate::event::Event;
+use crate::flag_table::FlagTable;
+use crate::frame::Frame;
+use crate::hlc::HybridTime;
+use crate::lamport_time::LamportTime;
+use crate::peer::{FrameNumber, Height};
+use bincode::{deserialize, serialize};
+use core::hash::Hash;
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// Version of the checkpoint format written by this code
+pub(crate) const CHECKPOINT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CheckpointEvent<Data, P: Hash + Eq, PK, Sig>
+where
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    #[serde(bound(deserialize = "Data: Deserialize<'de>"))]
+    pub(crate) event: Event<Data, P, PK, Sig>,
+    // None if the finality engine keeps no flag tables
+    pub(crate) flag_table: Option<FlagTable>,
+    pub(crate) finalised: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Checkpoint<Data, P: Hash + Eq, PK, Sig>
+where
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    pub(crate) version: u32,
+    // the last finalised frame included
+    pub(crate) last_frame: FrameNumber,
+    // finalised frames from frame 0 up to the last frame
+    pub(crate) frames: Vec<Frame>,
+    #[serde(bound(deserialize = "Data: Deserialize<'de>"))]
+    pub(crate) events: Vec<CheckpointEvent<Data, P, PK, Sig>>,
+    // creator, height and Lamport time of the latest event of every creator
+    pub(crate) heads: Vec<(P, Height, LamportTime)>,
+    pub(crate) lamport_time: LamportTime,
+    // consensus timestamp of the last event finalised
+    pub(crate) consensus_timestamp: HybridTime,
+}
+
+// Writes the checkpoint into the file
+pub(crate) fn write_checkpoint<Data, P, PK, Sig>(
+    path: &Path,
+    checkpoint: &Checkpoint<Data, P, PK, Sig>,
+) -> Result<()>
+where
+    Data: Serialize,
+    P: Hash + Eq + Serialize,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    std::fs::write(path, serialize(checkpoint)?)?;
+    Ok(())
+}
+
+// Reads the checkpoint from the file checking its format version
+pub(crate) fn read_checkpoint<Data, P, PK, Sig>(path: &Path) -> Result<Checkpoint<Data, P, PK, Sig>>
+where
+    Data: DeserializeOwned,
+    P: Hash + Eq + DeserializeOwned,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    let bytes = std::fs::read(path)?;
+    // the version goes first whatever the format is
+    let version = deserialize::<u32>(&bytes)?;
+    if version != CHECKPOINT_VERSION {
+        return Err(Error::UnsupportedCheckpoint(format!(
+            "format version {}; supported version: {}",
+            version, CHECKPOINT_VERSION
+        ))
+        .into());
+    }
+    Ok(deserialize::<Checkpoint<Data, P, PK, Sig>>(&bytes)?)
+}
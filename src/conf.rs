@@ -1,9 +1,13 @@
 // Config module
 
+use crate::engine::FinalityType;
+use crate::ordering::OrderingMode;
 use crate::peer::DAGPeerList;
+use crate::peer_selector::PeerSelectionType;
+use crate::retention::RetentionPolicy;
+use crate::store::{DAGstoreType, Durability};
 use futures::task::Waker;
 use libcommon_rs::peer::{PeerId, PeerList};
-use libcommon_rs::store::StoreType;
 use libconsensus::ConsensusConfiguration;
 use libsignature::PublicKey;
 use libsignature::SecretKey;
@@ -20,11 +24,66 @@ where
     pub request_addr: String,
     pub reply_addr: String,
     pub transport_type: TransportType,
-    pub store_type: StoreType,
+    pub store_type: DAGstoreType,
     pub store_dir: PathBuf,
+    // when writes into the store are flushed to disk
+    pub durability: Durability,
+    // size of the store cache in bytes
+    pub store_cache_capacity: u64,
+    // print store latency histograms when the store is closed
+    pub store_profile: bool,
+    // number of recently used events and of flag tables cached in memory;
+    // 0 disables the cache
+    pub store_cache_entries: usize,
+    // checkpoint file a new node starts from; see DAG::export_checkpoint()
+    pub checkpoint: Option<PathBuf>,
     // heartbeat duration in milliseconds
     pub heartbeat: u64,
     pub(crate) proc_a_delay: u64,
+    // Sync Request timeout in milliseconds
+    pub sync_timeout: u64,
+    // number of retries of a timed out Sync Request
+    pub sync_retries: usize,
+    // base backoff delay between retries in milliseconds; doubled on each retry
+    pub sync_backoff: u64,
+    // syncs with round trip time above this value (in milliseconds) are counted as slow
+    pub slow_sync: u64,
+    // strategy used to choose the next peer to sync with
+    pub peer_selection: PeerSelectionType,
+    // misbehaviour score at which a peer gets banned
+    pub ban_threshold: usize,
+    // duration of automatic bans in seconds; 0 for permanent bans
+    pub ban_duration: u64,
+    // misbehaviour score points forgiven per hour
+    pub misbehaviour_decay: u64,
+    // maximal rate of Sync Requests served for a single peer, requests per second
+    pub peer_request_rate: u32,
+    // maximal burst of Sync Requests served for a single peer
+    pub peer_request_burst: u32,
+    // maximal number of Sync Replies formed and sent at the same time;
+    // Sync Requests above it are answered busy
+    pub max_concurrent_replies: usize,
+    // maximal size of accepted Sync Request/Reply and of Sync Reply sent, in bytes
+    pub max_message_size: u64,
+    // maximal amount by which Lamport time of an event may exceed Lamport times of
+    // its parents, and Lamport time of a message may exceed Lamport times of known events
+    pub max_lamport_jump: usize,
+    // maximal amount by which timestamp of an event received may be ahead
+    // of the clock of the node, in milliseconds
+    pub max_clock_skew: u64,
+    // number of events, over all creators, the node may lag behind a peer before
+    // it switches to fetching finalised frames in bulk; the lag is the sum of
+    // differences of creator heights in the gossip lists of the node and the
+    // peer, not a number of frames; 0 disables catch-up
+    pub catch_up_threshold: usize,
+    // maximal number of finalised frames sent in a single Sync Reply in catch-up mode
+    pub catch_up_frames: usize,
+    // ordering of events within finalised frames
+    pub ordering_mode: OrderingMode,
+    // engine deciding finality of events
+    pub finality: FinalityType,
+    // removal of old finalised events, frames and flag tables from the store
+    pub retention: RetentionPolicy,
     pub(crate) waker: Option<Waker>,
     pub peers: DAGPeerList<P, PK>,
     pub creator: P,
@@ -42,8 +101,23 @@ where
     pub fn set_heartbeat(&mut self, heartbeat: u64) {
         self.heartbeat = heartbeat;
     }
-    pub fn set_store_type(&mut self, store_type: StoreType) {
-        self.store_type = store_type;
+    pub fn set_store_type<T: Into<DAGstoreType>>(&mut self, store_type: T) {
+        self.store_type = store_type.into();
+    }
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+    pub fn set_store_cache_capacity(&mut self, store_cache_capacity: u64) {
+        self.store_cache_capacity = store_cache_capacity;
+    }
+    pub fn set_store_profile(&mut self, store_profile: bool) {
+        self.store_profile = store_profile;
+    }
+    pub fn set_store_cache_entries(&mut self, store_cache_entries: usize) {
+        self.store_cache_entries = store_cache_entries;
+    }
+    pub fn set_checkpoint(&mut self, checkpoint: Option<PathBuf>) {
+        self.checkpoint = checkpoint;
     }
     pub fn set_transport_type(&mut self, transport_type: TransportType) {
         self.transport_type = transport_type;
@@ -54,6 +128,61 @@ where
     pub fn set_request_addr(&mut self, request_addr: String) {
         self.request_addr = request_addr;
     }
+    pub fn set_sync_timeout(&mut self, sync_timeout: u64) {
+        self.sync_timeout = sync_timeout;
+    }
+    pub fn set_sync_retries(&mut self, sync_retries: usize) {
+        self.sync_retries = sync_retries;
+    }
+    pub fn set_sync_backoff(&mut self, sync_backoff: u64) {
+        self.sync_backoff = sync_backoff;
+    }
+    pub fn set_slow_sync(&mut self, slow_sync: u64) {
+        self.slow_sync = slow_sync;
+    }
+    pub fn set_peer_selection(&mut self, peer_selection: PeerSelectionType) {
+        self.peer_selection = peer_selection;
+    }
+    pub fn set_ban_threshold(&mut self, ban_threshold: usize) {
+        self.ban_threshold = ban_threshold;
+    }
+    pub fn set_ban_duration(&mut self, ban_duration: u64) {
+        self.ban_duration = ban_duration;
+    }
+    pub fn set_misbehaviour_decay(&mut self, misbehaviour_decay: u64) {
+        self.misbehaviour_decay = misbehaviour_decay;
+    }
+    pub fn set_peer_request_rate(&mut self, rate: u32, burst: u32) {
+        self.peer_request_rate = rate;
+        self.peer_request_burst = burst;
+    }
+    pub fn set_max_concurrent_replies(&mut self, max_concurrent_replies: usize) {
+        self.max_concurrent_replies = max_concurrent_replies;
+    }
+    pub fn set_max_message_size(&mut self, max_message_size: u64) {
+        self.max_message_size = max_message_size;
+    }
+    pub fn set_max_lamport_jump(&mut self, max_lamport_jump: usize) {
+        self.max_lamport_jump = max_lamport_jump;
+    }
+    pub fn set_max_clock_skew(&mut self, max_clock_skew: u64) {
+        self.max_clock_skew = max_clock_skew;
+    }
+    pub fn set_catch_up_threshold(&mut self, catch_up_threshold: usize) {
+        self.catch_up_threshold = catch_up_threshold;
+    }
+    pub fn set_catch_up_frames(&mut self, catch_up_frames: usize) {
+        self.catch_up_frames = catch_up_frames;
+    }
+    pub fn set_ordering_mode(&mut self, ordering_mode: OrderingMode) {
+        self.ordering_mode = ordering_mode;
+    }
+    pub fn set_finality(&mut self, finality: FinalityType) {
+        self.finality = finality;
+    }
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        self.retention = retention;
+    }
     pub fn get_creator(&self) -> P {
         self.creator.clone()
     }
@@ -83,9 +212,33 @@ where
             reply_addr: "localhost:12000".to_string(),
             heartbeat: 1000,
             proc_a_delay: 3000,
+            sync_timeout: 3000,
+            sync_retries: 2,
+            sync_backoff: 500,
+            slow_sync: 1000,
+            peer_selection: PeerSelectionType::default(),
+            ban_threshold: 100,
+            ban_duration: 3600,
+            misbehaviour_decay: 60,
+            peer_request_rate: 10,
+            peer_request_burst: 20,
+            max_concurrent_replies: 8,
+            max_message_size: 16 * 1024 * 1024,
+            max_lamport_jump: 1000,
+            max_clock_skew: 10000,
+            catch_up_threshold: 1000,
+            catch_up_frames: 64,
+            ordering_mode: OrderingMode::default(),
+            finality: FinalityType::default(),
+            retention: RetentionPolicy::default(),
             transport_type: TransportType::Unknown,
-            store_type: StoreType::Unknown,
+            store_type: DAGstoreType::Unknown,
             store_dir: PathBuf::from("./sled_store"),
+            durability: Durability::default(),
+            store_cache_capacity: 1024 * 1024 * 1024,
+            store_profile: true,
+            store_cache_entries: 4096,
+            checkpoint: None,
             waker: None,
             peers: DAGPeerList::new(),
             creator: Default::default(),
@@ -1,14 +1,21 @@
+use crate::checkpoint::{
+    read_checkpoint, write_checkpoint, Checkpoint, CheckpointEvent, CHECKPOINT_VERSION,
+};
 use crate::conf::DAGconfig;
-use crate::errors::Result;
-use crate::event::Event;
-use crate::flag_table::creator_flag_table_fmt;
-use crate::flag_table::flag_table_fmt;
-use crate::flag_table::FlagTable;
-use crate::flag_table::{min_frame, open_merge_flag_table, strict_merge_flag_table};
+use crate::engine::{new_finality_engine, FinalityEngine, Participants};
+use crate::errors::{Error, Result};
+use crate::event::{Event, NetEvent};
+use crate::frame::Frame;
+use crate::hlc::{physical_now, HybridClock, HybridTime};
 use crate::lamport_time::LamportTime;
+use crate::ordering::{order_frame, tx_hash, OrderingMode};
 use crate::peer::FrameNumber;
-use crate::store::DAGstore;
-use crate::store_sled::SledStore;
+use crate::peer::{now_secs, Misbehaviour, PeerBan};
+use crate::retention::RetentionPolicy;
+use crate::store::{DAGstore, DAGstoreType, StoreOptions};
+use crate::store_cached::CachedStore;
+use crate::store_mem::MemStore;
+use crate::sync::{CatchUpFrame, SyncTracker};
 use crate::transactions::InternalTransaction;
 use core::mem::swap;
 use libcommon_rs::data::DataType;
@@ -20,9 +27,34 @@ use libhash_sha3::Hash as EventHash;
 use libsignature::PublicKey;
 use libsignature::SecretKey;
 use libsignature::Signature;
-use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
+use std::net::IpAddr;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::Duration;
+
+// A message rejected because it names an unknown creator or peer,
+// refers to events unknown to the node or carries implausible Lamport time
+// or timestamp
+#[derive(Clone, Debug)]
+pub struct QuarantineRecord<P> {
+    // peer the message was received from
+    pub from: P,
+    // creator of the rejected event; None if the whole message was rejected
+    pub creator: Option<P>,
+    pub reason: String,
+}
+
+// Transactions of a finalised frame delivered together, in final order, with
+// their creators; the consensus timestamp is the one of the last event of
+// the frame, which is derived from timestamps of famous roots or witnesses
+#[derive(Clone, Debug)]
+pub struct TransactionBatch<Data, P> {
+    pub frame: FrameNumber,
+    pub consensus_timestamp: HybridTime,
+    pub transactions: Vec<(Data, P)>,
+}
 
 pub(crate) struct DAGcore<P, Data, SK, PK, Sig>
 where
@@ -37,10 +69,40 @@ where
     tx_pool: Vec<Data>,
     internal_tx_pool: Vec<InternalTransaction<P, PK>>,
     lamport_time: LamportTime,
+    // the highest Lamport time among events inserted
+    max_event_lamport_time: LamportTime,
+    // maximal acceptable Lamport time jump; see DAGconfig
+    max_lamport_jump: LamportTime,
+    clock: HybridClock,
+    // maximal acceptable clock skew in milliseconds; see DAGconfig
+    max_clock_skew: u64,
+    // consensus timestamp of the last event finalised
+    last_consensus_timestamp: HybridTime,
+    // consensus timestamp of the last transaction delivered to the consumer
+    pub(crate) last_delivered_timestamp: Option<HybridTime>,
     pub(crate) current_frame: Option<FrameNumber>,
     pub(crate) current_event: Option<usize>,
     pub(crate) current_tx: Option<usize>,
     pub(crate) last_finalised_frame: Option<FrameNumber>,
+    retention: RetentionPolicy,
+    // the lowest finalised frame not pruned yet
+    next_prune_frame: FrameNumber,
+    engine: Box<dyn FinalityEngine<Data, P, PK, Sig>>,
+    pub(crate) sync_tracker: SyncTracker<P>,
+    // the next finalised frame to fetch from peers in catch-up mode;
+    // None during normal gossip
+    catch_up: Option<FrameNumber>,
+    // peers which have pruned the next frame to fetch in catch-up mode
+    catch_up_pruned: HashSet<P>,
+    // frame catch-up was given up at, as no peer had it any more; catch-up
+    // resumes only from a later frame
+    catch_up_abandoned: Option<FrameNumber>,
+    quarantine: VecDeque<QuarantineRecord<P>>,
+    // FairOrder mode: hashes of transactions first seen since the last own event
+    tx_arrivals: Vec<EventHash>,
+    // FairOrder mode: hashes of transactions seen recently, oldest first
+    seen_txs: HashSet<EventHash>,
+    seen_txs_order: VecDeque<EventHash>,
     me_a: String,
     me_b: String,
     pub(crate) shutdown: bool,
@@ -56,30 +118,69 @@ where
 {
     // Defines maximum number of transactions in a single event
     const TRANSACTIONS_LIMIT: usize = 16000;
+    // Defines maximum number of quarantined messages kept
+    const QUARANTINE_LIMIT: usize = 1024;
+    // Defines maximum number of transaction hashes remembered as seen
+    const SEEN_TXS_LIMIT: usize = 65536;
 
-    pub(crate) fn new(conf: DAGconfig<P, Data, SK, PK>) -> DAGcore<P, Data, SK, PK, Sig> {
+    pub(crate) fn new(conf: DAGconfig<P, Data, SK, PK>) -> Result<DAGcore<P, Data, SK, PK, Sig>> {
         let store_type = conf.store_type.clone();
-        let store = {
-            match store_type {
-                libcommon_rs::store::StoreType::Unknown => panic!("unknown DAG store"),
-                libcommon_rs::store::StoreType::Sled => {
-                    let path_buf = conf.store_dir.join(conf.creator.to_string());
-                    <SledStore as DAGstore<Data, P, PK, Sig>>::new(path_buf.as_path()).unwrap()
-                }
+        let path_buf = conf.store_dir.join(conf.creator.to_string());
+        let store_options = StoreOptions {
+            durability: conf.durability.clone(),
+            cache_capacity: conf.store_cache_capacity,
+            profile: conf.store_profile,
+            cache_entries: conf.store_cache_entries,
+        };
+        let store: Arc<RwLock<dyn DAGstore<Data, P, PK, Sig>>> = match store_type {
+            DAGstoreType::Unknown => panic!("unknown DAG store"),
+            DAGstoreType::Sled => Arc::new(RwLock::new(
+                // sled store behind a cache of events and flag tables
+                <CachedStore<Data, P, PK, Sig> as DAGstore<Data, P, PK, Sig>>::new(
+                    path_buf.as_path(),
+                    &store_options,
+                )?,
+            )),
+            DAGstoreType::Memory => {
+                Arc::new(RwLock::new(<MemStore as DAGstore<Data, P, PK, Sig>>::new(
+                    path_buf.as_path(),
+                    &store_options,
+                )?))
             }
         };
         let me_a = format!("{} {}", conf.get_creator(), conf.reply_addr.clone());
         let me_b = format!("{} {}", conf.get_creator(), conf.request_addr.clone());
-        let core = DAGcore {
+        let max_lamport_jump = conf.max_lamport_jump;
+        let max_clock_skew = conf.max_clock_skew;
+        let engine = new_finality_engine(&conf.finality);
+        let retention = conf.retention.clone();
+        let mut core = DAGcore {
             conf: Arc::new(RwLock::new(conf)),
-            store: Arc::new(RwLock::new(store)),
+            store,
             tx_pool: Vec::with_capacity(1),
             internal_tx_pool: Vec::with_capacity(1),
             lamport_time: LamportTime::default(),
+            max_event_lamport_time: LamportTime::default(),
+            max_lamport_jump,
+            clock: HybridClock::default(),
+            max_clock_skew,
+            last_consensus_timestamp: HybridTime::default(),
+            last_delivered_timestamp: None,
             current_frame: None,
             current_event: Some(0),
             current_tx: Some(0),
             last_finalised_frame: None,
+            retention,
+            next_prune_frame: 0,
+            engine,
+            sync_tracker: SyncTracker::default(),
+            catch_up: None,
+            catch_up_pruned: HashSet::new(),
+            catch_up_abandoned: None,
+            quarantine: VecDeque::with_capacity(1),
+            tx_arrivals: Vec::new(),
+            seen_txs: HashSet::new(),
+            seen_txs_order: VecDeque::new(),
             me_a,
             me_b,
             shutdown: false,
@@ -90,8 +191,27 @@ where
             let creator = cfg.get_creator();
             cfg.peers.set_creator(creator);
         }
+        // Restore bans persisted in the store; expired ones are deleted
+        {
+            let bans = core.store.read().unwrap().get_bans()?;
+            let mut cfg = core.conf.write().unwrap();
+            for ban in bans.into_iter() {
+                if ban.is_active() {
+                    cfg.peers.ban(ban);
+                } else {
+                    core.store.write().unwrap().remove_ban(&ban.peer)?;
+                }
+            }
+        }
+        // Restore the bound of Lamport time of requests from events stored
+        {
+            let max_lamport_time = core.store.read().unwrap().get_max_lamport_time()?;
+            core.max_event_lamport_time = max_lamport_time;
+            core.lamport_time = max_lamport_time;
+        }
         // Create leaf events
         let peers = { core.conf.read().unwrap().peers.clone() };
+        let participants = core.participants();
         for peer in peers.iter() {
             let mut event: Event<Data, P, PK, Sig> = Event::new(
                 peer.get_id(),
@@ -99,19 +219,36 @@ where
                 EventHash::default(),
                 EventHash::default(),
                 peer.get_lamport_time(),
+                HybridTime::default(),
+                [].to_vec(),
                 [].to_vec(),
                 [].to_vec(),
             );
-            let ex = event.event_hash().unwrap();
-            let mut ft = FlagTable::new();
-            ft.insert(ex.clone(), 0);
+            event.event_hash()?;
             {
                 let mut store = core.store.write().unwrap();
-                store.set_event(event).unwrap();
-                store.set_flag_table(&ex, &ft).unwrap();
+                core.engine
+                    .add_leaf_event(&mut *store, &event, &participants)?;
+                store.set_event(event)?;
+            }
+        }
+        // Rebuild state of the finality engine from events stored earlier
+        core.rebuild_from_store()?;
+        // Continue from a checkpoint if one is configured; a store with finalised
+        // frames continues from them, as it did when the checkpoint was imported
+        let checkpoint = { core.conf.read().unwrap().checkpoint.clone() };
+        if let Some(path) = checkpoint {
+            let stored = { core.store.read().unwrap().get_last_finalised_frame()? };
+            match stored {
+                None => core.import_checkpoint(path.as_path())?,
+                Some(frame) => info!(
+                    "{}: store finalised frame {} already; checkpoint not imported",
+                    core.me_a(),
+                    frame
+                ),
             }
         }
-        core
+        Ok(core)
     }
     pub fn check_quit(&self) -> bool {
         self.shutdown
@@ -128,14 +265,62 @@ where
     pub(crate) fn get_lamport_time(&self) -> LamportTime {
         self.lamport_time
     }
-    pub(crate) fn get_next_lamport_time(&mut self) -> LamportTime {
-        self.lamport_time += 1;
-        self.lamport_time
+    // Returns Lamport time for a new event whose parents have the highest
+    // Lamport time `parent_time`; the result is kept within bounds checked
+    // by check_event() even if the clock of the node has been advanced further.
+    pub(crate) fn get_next_lamport_time(&mut self, parent_time: LamportTime) -> LamportTime {
+        self.lamport_time = self.lamport_time.saturating_add(1);
+        let time = self
+            .lamport_time
+            .max(parent_time.saturating_add(1))
+            .min(parent_time.saturating_add(self.max_lamport_jump));
+        if self.lamport_time < time {
+            self.lamport_time = time;
+        }
+        time
+    }
+    // Returns timestamp for a new event whose parents have the highest
+    // timestamp `parent_time`
+    pub(crate) fn get_next_timestamp(&mut self, parent_time: HybridTime) -> HybridTime {
+        self.clock.next(parent_time)
     }
     pub(crate) fn add_transaction(&mut self, data: Data) -> BaseResult<()> {
+        self.record_tx_arrival(&data);
         self.tx_pool.push(data);
         Ok(())
     }
+    // Records arrival of a transaction for receive-order-fair ordering
+    fn record_tx_arrival(&mut self, data: &Data) {
+        if self.conf.read().unwrap().ordering_mode != OrderingMode::FairOrder {
+            return;
+        }
+        let hash = match tx_hash(data) {
+            Ok(hash) => hash,
+            Err(e) => {
+                error!("{}: transaction hash error: {:?}", self.me_a(), e);
+                return;
+            }
+        };
+        if !self.seen_txs.insert(hash) {
+            return;
+        }
+        self.seen_txs_order.push_back(hash);
+        if self.seen_txs_order.len() > Self::SEEN_TXS_LIMIT {
+            if let Some(old) = self.seen_txs_order.pop_front() {
+                self.seen_txs.remove(&old);
+            }
+        }
+        self.tx_arrivals.push(hash);
+    }
+    pub(crate) fn next_tx_arrivals(&mut self) -> Vec<EventHash> {
+        let mut len = self.tx_arrivals.len();
+        if len > Self::TRANSACTIONS_LIMIT {
+            len = Self::TRANSACTIONS_LIMIT;
+        }
+        let mut new_arrivals = self.tx_arrivals.split_off(len);
+        swap(&mut self.tx_arrivals, &mut new_arrivals);
+        new_arrivals
+    }
     pub(crate) fn next_transactions(&mut self) -> Vec<Data> {
         let mut len = self.tx_pool.len();
         if len > Self::TRANSACTIONS_LIMIT {
@@ -162,88 +347,606 @@ where
         swap(&mut self.internal_tx_pool, &mut new_trx);
         new_trx
     }
-    pub(crate) fn update_lamport_time(&mut self, time: LamportTime) {
+    // Updates Lamport time of the node with the time received in a message.
+    // The time must not exceed the highest Lamport time of events known
+    // by more than maximal Lamport jump; otherwise it is rejected.
+    pub(crate) fn update_lamport_time(&mut self, time: LamportTime) -> Result<()> {
+        let bound = self
+            .max_event_lamport_time
+            .saturating_add(self.max_lamport_jump);
+        if time > bound {
+            return Err(Error::ImplausibleLamportTime(time, bound).into());
+        }
         if self.lamport_time < time {
             self.lamport_time = time;
         }
+        Ok(())
     }
+    // Reports an oversized message from the host to the peer on the host;
+    // messages from a host shared by several peers cannot be attributed
+    pub(crate) fn report_oversized_message(&mut self, ip: &IpAddr) -> Result<()> {
+        let peers = { self.conf.read().unwrap().peers.find_peers_by_ip(ip) };
+        match peers.as_slice() {
+            [peer] => self.report_misbehaviour(peer, Misbehaviour::OversizedMessage),
+            _ => {
+                warn!(
+                    "{}: oversized message from {} not attributed to a single peer",
+                    self.me_a(),
+                    ip
+                );
+                Ok(())
+            }
+        }
+    }
+    // Increases misbehaviour score of the peer and bans it
+    // when the score reaches the ban threshold
+    pub(crate) fn report_misbehaviour(&mut self, peer: &P, m: Misbehaviour) -> Result<()> {
+        let (score, threshold, duration, banned) = {
+            let mut cfg = self.conf.write().unwrap();
+            let decay = cfg.misbehaviour_decay;
+            let score = cfg.peers.report_misbehaviour(peer, m, decay)?;
+            (
+                score,
+                cfg.ban_threshold,
+                cfg.ban_duration,
+                cfg.peers.is_banned(peer),
+            )
+        };
+        warn!(
+            "{}: peer {} misbehaviour {}; score: {}",
+            self.me_a(),
+            peer,
+            m,
+            score
+        );
+        if score >= threshold && !banned {
+            let duration = match duration {
+                0 => None,
+                x => Some(Duration::from_secs(x)),
+            };
+            self.ban_peer(
+                peer.clone(),
+                duration,
+                format!("misbehaviour score {} reached", score),
+            )?;
+        }
+        Ok(())
+    }
+    // Bans the peer for specified duration or permanently if duration is None
+    pub(crate) fn ban_peer(
+        &mut self,
+        peer: P,
+        duration: Option<Duration>,
+        reason: String,
+    ) -> Result<()> {
+        let ban = {
+            let mut cfg = self.conf.write().unwrap();
+            // check the peer is known and is not the node itself
+            let _ = cfg.peers.find_peer(&peer)?;
+            if peer == cfg.get_creator() {
+                return Err(Error::SelfBan.into());
+            }
+            let ban = PeerBan {
+                peer,
+                until: duration.map(|d| now_secs() + d.as_secs()),
+                reason,
+            };
+            cfg.peers.ban(ban.clone());
+            ban
+        };
+        warn!(
+            "{}: peer {} banned until {:?}: {}",
+            self.me_a(),
+            ban.peer,
+            ban.until,
+            ban.reason
+        );
+        self.store.write().unwrap().set_ban(&ban)
+    }
+    pub(crate) fn unban_peer(&mut self, peer: &P) -> Result<()> {
+        {
+            self.conf.write().unwrap().peers.unban(peer);
+        }
+        self.store.write().unwrap().remove_ban(peer)
+    }
+    // Lifts expired bans; scores of the peers start from zero again
+    pub(crate) fn expire_bans(&mut self) -> Result<()> {
+        let expired = { self.conf.write().unwrap().peers.expire_bans() };
+        for peer in expired.iter() {
+            debug!("{}: ban of peer {} expired", self.me_a(), peer);
+            self.store.write().unwrap().remove_ban(peer)?;
+        }
+        Ok(())
+    }
+    pub(crate) fn get_bans(&self) -> Vec<PeerBan<P>> {
+        self.conf.read().unwrap().peers.get_bans()
+    }
+    // Records rejected message; the oldest records are dropped
+    // when quarantine limit is reached
+    pub(crate) fn quarantine(&mut self, record: QuarantineRecord<P>) {
+        error!(
+            "{}: quarantined message from {}: {}",
+            self.me_a(),
+            record.from,
+            record.reason
+        );
+        if self.quarantine.len() >= Self::QUARANTINE_LIMIT {
+            self.quarantine.pop_front();
+        }
+        self.quarantine.push_back(record);
+    }
+    pub(crate) fn get_quarantine(&self) -> Vec<QuarantineRecord<P>> {
+        self.quarantine.iter().cloned().collect()
+    }
+    // Returns Ok(false) if a signature of the event is invalid and
+    // Err() if the event names unknown creator or signatory
     pub(crate) fn check_event(&self, event: &Event<Data, P, PK, Sig>) -> Result<bool> {
         // FIXME: implement event verification:
         // - self-parеnt must be the last known event of the creator with height one minus height of the event
         // - all signatures must be verified positively
+        let cfg = self.conf.read().unwrap();
+        let peers = &cfg.peers;
+        if peers.find_peer(&event.creator).is_err() {
+            return Err(Error::UnknownPeer(format!("creator {}", event.creator)).into());
+        }
         for (signatory, signature) in event.signatures.iter() {
-            let peer = { self.conf.read().unwrap().peers.find_peer(signatory)? };
+            let peer = match peers.find_peer(signatory) {
+                Ok(peer) => peer,
+                Err(_) => return Err(Error::UnknownPeer(format!("signatory {}", signatory)).into()),
+            };
             let res = signature.verify(event.get_hash(), peer.get_public_key())?;
             if !res {
                 return Ok(false);
             }
         }
-        Ok(true)
-    }
-    pub(crate) fn insert_event(&mut self, mut event: Event<Data, P, PK, Sig>) -> Result<bool> {
-        let event_hash = event.event_hash()?;
-        let self_parent = event.self_parent;
-        let other_parent = event.other_parent;
-        let (self_parent_event, other_parent_event, self_parent_ft, other_parent_ft) = {
+        // Lamport time must be greater than Lamport times of the event parents
+        // and may exceed them at most by maximal Lamport jump
+        let (self_parent, other_parent) = {
             let store = self.store.read().unwrap();
             (
-                store.get_event(&self_parent)?,
-                store.get_event(&other_parent)?,
-                store.get_flag_table(&self_parent)?,
-                store.get_flag_table(&other_parent)?,
+                store.get_event(&event.self_parent)?,
+                store.get_event(&event.other_parent)?,
             )
         };
-        debug!("{}: * event and ft read", self.me_a());
-        let root: bool; // = false;
-        let frame: FrameNumber /* FrameNumber::default() */ =
-            match self_parent_event.frame_number.cmp(&other_parent_event.frame_number) {
-                Ordering::Equal => {
-                    let root_flag_table = strict_merge_flag_table(
-                        &self_parent_ft,
-                        &other_parent_ft,
-                        self_parent_event.frame_number,
-                    );
-                    let creator_root_flag_table = {
-                        let store = self.store.read().unwrap();
-                        store.derive_creator_flag_table(&root_flag_table, self_parent_event.frame_number)
-                    };
-                    let root_majority = { self.conf.read().unwrap().peers.root_majority() };
-                    if creator_root_flag_table.len() >= root_majority {
-                        root = true;
-                        self_parent_event.frame_number + 1
-                    } else {
-                        root = false;
-                        self_parent_event.frame_number
+        let parent_time = self_parent
+            .get_lamport_time()
+            .max(other_parent.get_lamport_time());
+        let bound = parent_time.saturating_add(self.max_lamport_jump);
+        if event.lamport_timestamp <= parent_time || event.lamport_timestamp > bound {
+            return Err(Error::ImplausibleLamportTime(event.lamport_timestamp, bound).into());
+        }
+        // timestamp must be greater than timestamps of the event parents
+        // and may be ahead of the clock of the node at most by maximal clock skew
+        let parent_timestamp = self_parent
+            .get_timestamp()
+            .max(other_parent.get_timestamp());
+        if event.timestamp <= parent_timestamp {
+            return Err(Error::ImplausibleTimestamp(format!(
+                "{} is not after parents' {}",
+                event.timestamp, parent_timestamp
+            ))
+            .into());
+        }
+        let wall_bound = physical_now().saturating_add(self.max_clock_skew);
+        if event.timestamp.wall > wall_bound {
+            return Err(Error::ImplausibleTimestamp(format!(
+                "{} is ahead of {}",
+                event.timestamp, wall_bound
+            ))
+            .into());
+        }
+        Ok(true)
+    }
+    // Orders a batch of finalised events, assigns consensus timestamps to them
+    // and stores it as the next finalised frame
+    fn commit_finalised_frame(
+        &mut self,
+        frame: FrameNumber,
+        mut batch: Frame,
+        consensus_time: Option<HybridTime>,
+        ordering_mode: &OrderingMode,
+    ) -> Result<()> {
+        {
+            let mut store = self.store.write().unwrap();
+            let supermajority = { self.conf.read().unwrap().peers.root_majority() };
+            self.last_consensus_timestamp = order_frame(
+                &*store,
+                &mut batch,
+                ordering_mode,
+                supermajority,
+                consensus_time,
+                self.last_consensus_timestamp,
+            )?;
+            debug!(
+                "{}: +finalised frame {}: {}",
+                self.me_a(),
+                frame,
+                batch.clone()
+            );
+            store.set_finalised_frame(frame, batch)?;
+        }
+        self.last_finalised_frame = Some(frame);
+        // notify consumer on next transaction in consensus availability
+        if let Some(waker) = { self.conf.write().unwrap().waker.take() } {
+            debug!("{}: calling waker", self.me_a());
+            waker.wake();
+        }
+        Ok(())
+    }
+    // Prunes finalised frames delivered to the consumer which have expired
+    // according to the retention policy
+    fn prune_frames(&mut self) -> Result<()> {
+        let (delivered, last_finalised_frame) =
+            match (self.current_frame, self.last_finalised_frame) {
+                (Some(current), Some(last)) => (current, last),
+                _ => return Ok(()),
+            };
+        let now = physical_now();
+        let mut store = self.store.write().unwrap();
+        // an empty frame is not older than the next frame with events, which
+        // is looked up once for a run of empty frames
+        let mut next_with_events: Option<(FrameNumber, u64)> = None;
+        // the frame being delivered is kept
+        while self.next_prune_frame < delivered {
+            let frame = self.next_prune_frame;
+            let frame_time = match next_with_events {
+                Some((f, time)) if f >= frame => time,
+                _ => {
+                    next_with_events = None;
+                    for f in frame..=last_finalised_frame {
+                        if let Some(record) = store.get_finalised_frame(f)?.events.last() {
+                            next_with_events = Some((f, record.consensus_timestamp.wall));
+                            break;
+                        }
+                    }
+                    match next_with_events {
+                        Some((_, time)) => time,
+                        None => break,
                     }
-                },
-                Ordering::Greater => {
-                    root = false;
-                    self_parent_event.frame_number
-                },
-                Ordering::Less => {
-                    root = true;
-                    other_parent_event.frame_number
-                },
+                }
             };
-
-        debug!("{}: * got frame number", self.me_a());
-        event.frame_number = frame;
-        let first_not_finalised_frame = match self.last_finalised_frame {
-            Some(x) => x + 1,
-            None => 0,
+            if !self
+                .retention
+                .expired(frame, last_finalised_frame, frame_time, now)
+            {
+                break;
+            }
+            store.prune_finalised_frame(frame)?;
+            debug!("{}: pruned frame {}", self.me_a(), frame);
+            self.next_prune_frame += 1;
+        }
+        Ok(())
+    }
+    // Returns the next finalised frame to fetch in catch-up mode; None during normal gossip
+    pub(crate) fn catch_up_frame(&self) -> Option<FrameNumber> {
+        self.catch_up
+    }
+    // Switches to catch-up mode when the node lags behind a peer by
+    // catch-up threshold events or more; `lag` is the number of events known
+    // to the peer and not known to the node, see gossip_lag()
+    pub(crate) fn update_catch_up(&mut self, lag: usize) {
+        let threshold = { self.conf.read().unwrap().catch_up_threshold };
+        if self.catch_up.is_some() || threshold == 0 || lag < threshold {
+            return;
+        }
+        let frame = self.last_finalised_frame.map_or(0, |frame| frame + 1);
+        if self
+            .catch_up_abandoned
+            .map_or(false, |abandoned| frame <= abandoned)
+        {
+            return;
+        }
+        info!(
+            "{}: {} events behind; catching up from frame {}",
+            self.me_a(),
+            lag,
+            frame
+        );
+        self.catch_up = Some(frame);
+    }
+    // Switches back to normal gossip
+    pub(crate) fn finish_catch_up(&mut self) {
+        self.catch_up_pruned.clear();
+        if let Some(frame) = self.catch_up.take() {
+            info!("{}: catch-up finished at frame {}", self.me_a(), frame);
+        }
+    }
+    // Records a peer replying it has pruned the next frame to fetch; once all
+    // peers eligible for sync have replied so, catch-up gives way to normal gossip
+    pub(crate) fn record_pruned_reply(&mut self, peer: &P) {
+        let frame = match self.catch_up {
+            Some(frame) => frame,
+            None => return,
+        };
+        self.catch_up_pruned.insert(peer.clone());
+        let all_pruned = {
+            let cfg = self.conf.read().unwrap();
+            let creator = cfg.get_creator();
+            cfg.peers
+                .iter()
+                .filter(|p| p.id != creator && !cfg.peers.is_banned(&p.id))
+                .all(|p| self.catch_up_pruned.contains(&p.id))
+        };
+        if all_pruned {
+            warn!(
+                "{}: no peer has frame {} any more; catch-up given up",
+                self.me_a(),
+                frame
+            );
+            self.catch_up_abandoned = Some(frame);
+            self.finish_catch_up();
+        }
+    }
+    // Returns finalised frames from the frame on, with their events, for a peer
+    // in catch-up mode; at most `count` frames of total serialised size within
+    // the size budget. Fails with PrunedFrame if events of a frame requested
+    // are pruned from the store, so the peer asks another node.
+    pub(crate) fn get_catch_up_frames(
+        &self,
+        from: FrameNumber,
+        count: usize,
+        budget: u64,
+    ) -> Result<Vec<CatchUpFrame<Data, P, PK, Sig>>> {
+        let last = match self.last_finalised_frame {
+            Some(last) => last,
+            None => return Ok(Vec::new()),
+        };
+        // records of frames pruned are removed along with their events
+        if from < self.next_prune_frame {
+            return Err(Error::PrunedFrame(from).into());
+        }
+        let store = self.store.read().unwrap();
+        let mut budget = budget;
+        let mut frames = Vec::new();
+        for frame in (from..=last).take(count) {
+            let records = store.get_finalised_frame(frame)?;
+            let mut events: Vec<NetEvent<Data, P, PK, Sig>> =
+                Vec::with_capacity(records.events.len());
+            for record in records.events.iter() {
+                match store.get_event(&record.hash) {
+                    Ok(event) => events.push(event.into()),
+                    Err(e) => match e.downcast_ref::<Error>() {
+                        Some(Error::NoneError) => return Err(Error::PrunedFrame(frame).into()),
+                        _ => return Err(e),
+                    },
+                }
+            }
+            let catch_up = CatchUpFrame {
+                frame,
+                records,
+                events,
+            };
+            let size = bincode::serialized_size(&catch_up)?;
+            if size > budget {
+                break;
+            }
+            budget -= size;
+            frames.push(catch_up);
+        }
+        Ok(frames)
+    }
+    // Verifies events of a finalised frame fetched in catch-up mode against the
+    // frame records and inserts those not known yet, parents first.
+    // Returns the number of events inserted.
+    pub(crate) fn insert_catch_up_frame(
+        &mut self,
+        catch_up: CatchUpFrame<Data, P, PK, Sig>,
+    ) -> Result<usize> {
+        let frame = catch_up.frame;
+        // frames fetched already or fetched after catch-up has finished are skipped
+        let expected = match self.catch_up {
+            Some(expected) if frame >= expected => expected,
+            _ => return Ok(0),
+        };
+        if frame != expected {
+            return Err(
+                Error::InvalidCatchUpFrame(frame, format!("frame {} expected", expected)).into(),
+            );
+        }
+        let mut events: Vec<Event<Data, P, PK, Sig>> =
+            catch_up.events.into_iter().map(|e| e.into()).collect();
+        let records: HashSet<EventHash> = catch_up.records.events.iter().map(|r| r.hash).collect();
+        let received: HashSet<EventHash> = events.iter().map(|e| e.hash).collect();
+        if events.len() != records.len() || received != records {
+            return Err(Error::InvalidCatchUpFrame(
+                frame,
+                "events do not match frame records".to_string(),
+            )
+            .into());
+        }
+        // parents precede their children in Lamport time order
+        events.sort_by(|a, b| a.lamport_timestamp.cmp(&b.lamport_timestamp));
+        let mut inserted = 0;
+        for event in events.into_iter() {
+            let hash = event.get_hash();
+            if self.store.read().unwrap().get_event(&hash).is_ok() {
+                continue;
+            }
+            if !self.check_event(&event)? {
+                return Err(Error::InvalidCatchUpFrame(
+                    frame,
+                    format!("invalid signature of event {}", hash),
+                )
+                .into());
+            }
+            let creator = event.get_creator();
+            let lamport_time = event.get_lamport_time();
+            let height = event.get_height();
+            self.insert_event(event)?;
+            if let Ok(peer) = self.conf.write().unwrap().peers.find_peer_mut(&creator) {
+                peer.update_lamport_time_and_height(lamport_time, height);
+            }
+            inserted += 1;
+        }
+        self.catch_up = Some(frame + 1);
+        self.catch_up_pruned.clear();
+        Ok(inserted)
+    }
+    // Returns transactions of the next finalised frame with events not delivered
+    // yet; a frame partially delivered by the stream is continued from the
+    // transaction next to be delivered
+    pub(crate) fn next_batch(&mut self) -> Result<Option<TransactionBatch<Data, P>>> {
+        let last_finalised_frame = match self.last_finalised_frame {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let mut frame_number = self.current_frame.unwrap_or(0);
+        let (first_event, mut first_tx) = match self.current_event {
+            Some(event) => (event, self.current_tx.unwrap_or(0)),
+            None => (0, 0),
+        };
+        let store = self.store.read().unwrap();
+        loop {
+            if self.current_event.is_none() {
+                if frame_number >= last_finalised_frame {
+                    return Ok(None);
+                }
+                frame_number += 1;
+            }
+            let frame = store.get_finalised_frame(frame_number)?;
+            self.current_frame = Some(frame_number);
+            self.current_event = None;
+            self.current_tx = Some(0);
+            // no Atropos has been elected in an empty frame, which cannot
+            // have been partially delivered
+            let consensus_timestamp = match frame.events.last() {
+                Some(record) => record.consensus_timestamp,
+                None => continue,
+            };
+            let mut transactions = Vec::new();
+            if !frame.tx_order.is_empty() {
+                // FairOrder mode: transactions in the order set at finalisation
+                for (ex, i) in frame.tx_order.iter().skip(first_event) {
+                    let event = store.get_event(ex)?;
+                    if let Some(tx) = event.transactions.get(*i) {
+                        transactions.push((tx.clone(), event.creator.clone()));
+                    }
+                }
+            } else {
+                for record in frame.events.iter().skip(first_event) {
+                    let event = store.get_event(&record.hash)?;
+                    let creator = event.creator.clone();
+                    for tx in event.transactions.into_iter().skip(first_tx) {
+                        transactions.push((tx, creator.clone()));
+                    }
+                    first_tx = 0;
+                }
+            }
+            self.last_delivered_timestamp = Some(consensus_timestamp);
+            return Ok(Some(TransactionBatch {
+                frame: frame_number,
+                consensus_timestamp,
+                transactions,
+            }));
+        }
+    }
+    // Rebuilds state of the finality engine, heights of creators and
+    // the last finalised frame from events and frames stored earlier; finalised
+    // frames kept in the store are delivered again from the first one
+    fn rebuild_from_store(&mut self) -> Result<()> {
+        let (mut events, first_finalised_frame, last_finalised_frame) = {
+            let store = self.store.read().unwrap();
+            (
+                store.get_events()?,
+                store.get_first_finalised_frame()?,
+                store.get_last_finalised_frame()?,
+            )
         };
-        let mut visibilis_flag_table =
-            open_merge_flag_table(&self_parent_ft, &other_parent_ft, first_not_finalised_frame);
-        debug!("{}: * got visibilis ft", self.me_a());
-        if root {
-            visibilis_flag_table.insert(event_hash.clone(), frame);
+        // frames below the first one stored are pruned
+        if let Some(first) = first_finalised_frame {
+            self.next_prune_frame = first;
+            if first > 0 {
+                self.current_frame = Some(first);
+            }
+        }
+        if events.iter().all(|e| e.self_parent == EventHash::default()) {
+            return Ok(());
+        }
+        events.sort_by(|a, b| {
+            a.lamport_timestamp.cmp(&b.lamport_timestamp).then_with(|| {
+                a.hash
+                    .partial_cmp(&b.hash)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+        });
+        {
+            let mut cfg = self.conf.write().unwrap();
+            for event in events.iter() {
+                if let Ok(peer) = cfg.peers.find_peer_mut(&event.creator) {
+                    peer.update_lamport_time_and_height(event.lamport_timestamp, event.height);
+                }
+            }
+        }
+        let participants = self.participants();
+        let next_frame = last_finalised_frame.map_or(0, |frame| frame + 1);
+        {
+            let mut store = self.store.write().unwrap();
+            self.engine
+                .rebuild(&mut *store, events, &participants, next_frame)?;
+            // consensus timestamps go on from the last event finalised
+            if let Some(last) = last_finalised_frame {
+                for frame in (self.next_prune_frame..=last).rev() {
+                    if let Some(record) = store.get_finalised_frame(frame)?.events.last() {
+                        self.last_consensus_timestamp = record.consensus_timestamp;
+                        break;
+                    }
+                }
+            }
+        }
+        info!(
+            "{}: rebuilt finality state; last finalised frame: {:?}",
+            self.me_a(),
+            last_finalised_frame
+        );
+        self.last_finalised_frame = last_finalised_frame;
+        self.commit_finalised_batches(&participants)
+    }
+    // Returns creators in creator order and the supermajority size
+    fn participants(&self) -> Participants<P> {
+        let cfg = self.conf.read().unwrap();
+        Participants {
+            creators: cfg.peers.iter().map(|p| p.id.clone()).collect(),
+            supermajority: cfg.peers.root_majority(),
+        }
+    }
+    pub(crate) fn insert_event(&mut self, mut event: Event<Data, P, PK, Sig>) -> Result<bool> {
+        let event_hash = event.event_hash()?;
+        let lamport_time = event.lamport_timestamp;
+        let timestamp = event.timestamp;
+        for tx in event.transactions.iter() {
+            self.record_tx_arrival(tx);
+        }
+        let participants = self.participants();
+        if let Err(e) = self.store_event(event, event_hash, &participants) {
+            // data the engine has stored for the event must not outlive it
+            self.store.write().unwrap().discard_pending(&event_hash);
+            return Err(e);
+        }
+        // events inserted advance the clock of the node
+        if self.max_event_lamport_time < lamport_time {
+            self.max_event_lamport_time = lamport_time;
         }
+        if self.lamport_time < lamport_time {
+            self.lamport_time = lamport_time;
+        }
+        self.clock.observe(timestamp);
+        self.commit_finalised_batches(&participants)?;
+        self.prune_frames()?;
+        Ok(true)
+    }
+    // Assigns the frame to the event, signs it and writes it into the store
+    fn store_event(
+        &mut self,
+        mut event: Event<Data, P, PK, Sig>,
+        event_hash: EventHash,
+        participants: &Participants<P>,
+    ) -> Result<()> {
         {
-            self.store
-                .write()
-                .unwrap()
-                .set_flag_table(&event_hash, &visibilis_flag_table)?;
+            let mut store = self.store.write().unwrap();
+            self.engine
+                .add_event(&mut *store, &mut event, participants)?;
         }
+        debug!("{}: * got frame number", self.me_a());
         debug!("{}: * sign event", self.me_a());
         {
             let cfg = self.conf.read().unwrap();
@@ -253,57 +956,536 @@ where
                 .insert(cfg.peers.get_creator_id(), signature);
         }
         debug!("{}: * insert event: {}", self.me_a(), event.clone());
-
+        self.store.write().unwrap().set_event(event)
+    }
+    // Commits batches the finality engine has finalised
+    fn commit_finalised_batches(&mut self, participants: &Participants<P>) -> Result<()> {
+        let batches = {
+            let mut store = self.store.write().unwrap();
+            self.engine.finalised_batches(&mut *store, participants)?
+        };
+        let ordering_mode = {
+            let cfg = self.conf.read().unwrap();
+            self.engine.ordering_mode(&cfg.ordering_mode)
+        };
+        for batch in batches.into_iter() {
+            let frame = Frame {
+                events: batch.events,
+                tx_order: Vec::new(),
+            };
+            self.commit_finalised_frame(batch.frame, frame, batch.consensus_time, &ordering_mode)?;
+        }
+        Ok(())
+    }
+    // Writes a checkpoint of finalised frames up to the last finalised frame
+    // and of events needed to continue from there
+    pub(crate) fn export_checkpoint(&self, path: &Path) -> Result<()> {
+        let last_frame = match self.last_finalised_frame {
+            Some(frame) => frame,
+            None => return Err(Error::NoneError.into()),
+        };
+        let peers = { self.conf.read().unwrap().peers.clone() };
+        let store = self.store.read().unwrap();
+        let mut frames = Vec::with_capacity(last_frame + 1);
+        for frame in 0..=last_frame {
+            // frames pruned are exported empty
+            if frame < self.next_prune_frame {
+                frames.push(Frame::default());
+                continue;
+            }
+            frames.push(store.get_finalised_frame(frame)?);
+        }
+        let mut events = Vec::new();
+        let mut heads = Vec::new();
+        let mut included: HashSet<EventHash> = HashSet::new();
+        let mut other_parents: Vec<EventHash> = Vec::new();
+        for peer in peers.iter() {
+            let creator = peer.get_id();
+            heads.push((creator.clone(), peer.get_height(), peer.get_lamport_time()));
+            // events of the creator from its latest one down to the first finalised
+            let mut height = peer.get_height();
+            loop {
+                let event = match store.get_event_of_creator(creator.clone(), height) {
+                    Ok(event) => event,
+                    Err(_) => break,
+                };
+                let finalised = store.is_finalised(&event.hash)?;
+                if !finalised && event.other_parent != EventHash::default() {
+                    other_parents.push(event.other_parent);
+                }
+                if included.insert(event.hash) {
+                    events.push(CheckpointEvent {
+                        flag_table: store.get_flag_table(&event.hash).ok(),
+                        event,
+                        finalised,
+                    });
+                }
+                if finalised || height == 0 {
+                    break;
+                }
+                height -= 1;
+            }
+        }
+        for ex in other_parents.into_iter() {
+            if included.insert(ex) {
+                events.push(CheckpointEvent {
+                    event: store.get_event(&ex)?,
+                    flag_table: store.get_flag_table(&ex).ok(),
+                    finalised: store.is_finalised(&ex)?,
+                });
+            }
+        }
+        let checkpoint = Checkpoint {
+            version: CHECKPOINT_VERSION,
+            last_frame,
+            frames,
+            events,
+            heads,
+            lamport_time: self.lamport_time,
+            consensus_timestamp: self.last_consensus_timestamp,
+        };
+        write_checkpoint(path, &checkpoint)
+    }
+    // Loads a checkpoint into the store of a new node; delivery of finalised
+    // transactions starts after the last frame of the checkpoint
+    fn import_checkpoint(&mut self, path: &Path) -> Result<()> {
+        let checkpoint: Checkpoint<Data, P, PK, Sig> = read_checkpoint(path)?;
+        let last_frame = checkpoint.last_frame;
+        let participants = self.participants();
+        self.engine.restore(last_frame + 1)?;
+        let mut events = checkpoint.events;
+        // parents precede their children in Lamport time order
+        events.sort_by(|a, b| a.event.lamport_timestamp.cmp(&b.event.lamport_timestamp));
+        // frames pruned before the export are empty and precede the frames kept
+        let last_pruned = checkpoint
+            .frames
+            .iter()
+            .position(|frame| !frame.events.is_empty())
+            .unwrap_or_else(|| checkpoint.frames.len())
+            .checked_sub(1);
+        // frames pruned are not stored, so they count as pruned after a restart too;
+        // the last frame is stored even if empty
+        let first_kept = last_pruned.map_or(0, |frame| frame + 1).min(last_frame);
         {
-            self.store.write().unwrap().set_event(event)?;
+            let mut store = self.store.write().unwrap();
+            // events of the frames are marked finalised in their frames
+            for (frame, records) in checkpoint.frames.into_iter().enumerate().skip(first_kept) {
+                store.set_finalised_frame(frame, records)?;
+            }
+            for item in events.into_iter() {
+                let mut event = item.event;
+                let hash = event.hash;
+                // leaf events are created already
+                if store.get_event(&hash).is_ok() {
+                    continue;
+                }
+                if self.max_event_lamport_time < event.lamport_timestamp {
+                    self.max_event_lamport_time = event.lamport_timestamp;
+                }
+                if item.finalised {
+                    if let Some(ft) = item.flag_table {
+                        store.set_flag_table(&hash, &ft)?;
+                    }
+                    store.set_event(event)?;
+                    // latest events of creators kept from pruned frames are
+                    // marked with the last pruned frame, their frame is gone
+                    if !store.is_finalised(&hash)? {
+                        store.set_finalised(&hash, last_pruned.unwrap_or(0))?;
+                    }
+                } else {
+                    self.engine
+                        .add_event(&mut *store, &mut event, &participants)?;
+                    store.set_event(event)?;
+                }
+            }
         }
-        let creator_visibilis_flag_table = {
-            let store = self.store.read().unwrap();
-            store.derive_creator_flag_table(&visibilis_flag_table, first_not_finalised_frame + 1)
+        {
+            let mut cfg = self.conf.write().unwrap();
+            for (creator, height, lamport_time) in checkpoint.heads.into_iter() {
+                if let Ok(peer) = cfg.peers.find_peer_mut(&creator) {
+                    peer.update_lamport_time_and_height(lamport_time, height);
+                }
+            }
+        }
+        if self.lamport_time < checkpoint.lamport_time {
+            self.lamport_time = checkpoint.lamport_time;
+        }
+        self.last_consensus_timestamp = checkpoint.consensus_timestamp;
+        self.clock.observe(checkpoint.consensus_timestamp);
+        self.last_finalised_frame = Some(last_frame);
+        self.current_frame = Some(last_frame);
+        self.current_event = None;
+        self.current_tx = Some(0);
+        // events of frames kept are mostly not imported, so the frames are
+        // refused to peers catching up as pruned ones are
+        self.next_prune_frame = first_kept;
+        self.commit_finalised_batches(&participants)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::FinalityType;
+    use crate::frame::FrameRecord;
+    use crate::test_utils::{
+        insert_events, new_conf, new_core, new_peers, temp_store_dir, RecordedDag, TestCore,
+        TestEvent,
+    };
+
+    #[test]
+    fn test_checkpoint_refused_by_hashgraph() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(8);
+        let dir = temp_store_dir("core-checkpoint-hashgraph");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("checkpoint");
+        let mut source = new_core(&dag, 0, None);
+        insert_events(&mut source, &dag.events);
+        source.export_checkpoint(&path).unwrap();
+        let mut conf = new_conf(&dag, 1, None);
+        conf.checkpoint = Some(path);
+        conf.finality = FinalityType::Hashgraph;
+        let err = match TestCore::new(conf) {
+            Ok(_) => panic!("Hashgraph finality starts from a checkpoint"),
+            Err(e) => e,
         };
-        let peer_size = { self.conf.read().unwrap().peers.len() };
-        debug!(
-            "{}: * peer_size: {}; visibilis_ft_size:{}",
-            self.me_a(),
-            peer_size,
-            creator_visibilis_flag_table.len()
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::UnsupportedCheckpoint(
+                "Hashgraph finality cannot resume from a checkpoint".to_string()
+            ))
         );
-        debug!(
-            "{}: * ft:{}; creator_ft:{}",
-            self.me_a(),
-            flag_table_fmt(&visibilis_flag_table),
-            creator_flag_table_fmt(&creator_visibilis_flag_table)
+    }
+
+    #[test]
+    fn test_catch_up_round_trip() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(16);
+        let mut source = new_core(&dag, 0, None);
+        insert_events(&mut source, &dag.events);
+        let last = source.last_finalised_frame.unwrap();
+        let mut target = new_core(&dag, 1, None);
+        target.update_catch_up(usize::max_value());
+        // frames are fetched a few at a time until the source has no more
+        while let Some(from) = target.catch_up_frame() {
+            let frames = source
+                .get_catch_up_frames(from, 3, u64::max_value())
+                .unwrap();
+            if frames.is_empty() {
+                target.finish_catch_up();
+            }
+            for frame in frames.into_iter() {
+                target.insert_catch_up_frame(frame).unwrap();
+            }
+        }
+        // gossip brings the events not finalised by the source
+        let rest: Vec<TestEvent> = dag
+            .events
+            .iter()
+            .filter(|e| target.store.read().unwrap().get_event(&e.hash).is_err())
+            .cloned()
+            .collect();
+        insert_events(&mut target, &rest);
+        assert_eq!(target.last_finalised_frame, Some(last));
+        let hashes = |core: &TestCore, frame: FrameNumber| -> Vec<EventHash> {
+            let store = core.store.read().unwrap();
+            let frame = store.get_finalised_frame(frame).unwrap();
+            frame.events.iter().map(|r| r.hash).collect()
+        };
+        for frame in 0..=last {
+            assert_eq!(hashes(&target, frame), hashes(&source, frame));
+        }
+    }
+
+    #[test]
+    fn test_check_event_rejects_forged_and_unknown_creators() {
+        let mut dag = RecordedDag::new(3);
+        let event = dag.add(0, 1, Vec::new());
+        let core = new_core(&dag, 1, None);
+        assert!(core.check_event(&event).unwrap());
+        // a signature not matching the event
+        let mut forged = event.clone();
+        forged.lamport_timestamp = 2;
+        forged.event_hash().unwrap();
+        assert!(!core.check_event(&forged).unwrap());
+        let (_, keys) = new_peers(1);
+        let mut stranger = event.clone();
+        stranger.signatures.clear();
+        stranger.creator = keys[0].0.clone();
+        stranger.event_hash().unwrap();
+        let err = core.check_event(&stranger).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::UnknownPeer(format!("creator {}", keys[0].0)))
         );
-        if peer_size == creator_visibilis_flag_table.len() {
-            let frame_upto = min_frame(&creator_visibilis_flag_table);
-            debug!(
-                "{}: first not finalised frame:{}; frame up to: {}",
-                self.me_a(),
-                first_not_finalised_frame,
-                frame_upto
+    }
+
+    #[test]
+    fn test_lamport_time_bounds() {
+        let dag = RecordedDag::new(3);
+        let mut core = new_core(&dag, 0, None);
+        core.max_lamport_jump = 10;
+        let err = core.update_lamport_time(11).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::ImplausibleLamportTime(11, 10))
+        );
+        core.update_lamport_time(10).unwrap();
+        assert_eq!(core.get_lamport_time(), 10);
+        // new events stay within the bound checked by peers
+        assert_eq!(core.get_next_lamport_time(0), 10);
+        assert_eq!(core.get_next_lamport_time(5), 12);
+    }
+
+    #[test]
+    fn test_check_event_lamport_bounds() {
+        let mut dag = RecordedDag::new(3);
+        let event = dag.add(0, 1, Vec::new());
+        let core = new_core(&dag, 1, None);
+        // Lamport time not after the parents' or beyond the maximal jump
+        for (time, bound) in [(0, 1000), (1001, 1000)].iter() {
+            let mut jumped = event.clone();
+            jumped.signatures.clear();
+            jumped.lamport_timestamp = *time;
+            jumped.event_hash().unwrap();
+            let err = core.check_event(&jumped).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<Error>(),
+                Some(&Error::ImplausibleLamportTime(*time, *bound))
             );
-            for frame in first_not_finalised_frame..frame_upto {
-                //self.finalise_frame(frame)
-                {
-                    let mut store = self.store.write().unwrap();
-                    let mut frame_itself = store.get_frame(frame)?;
-                    frame_itself.finalise();
-                    debug!(
-                        "{}: +finalised frame {}: {}",
-                        self.me_a(),
-                        frame,
-                        frame_itself.clone()
-                    );
-                    store.set_frame(frame, frame_itself)?;
-                }
-                self.last_finalised_frame = Some(frame);
-                // notify consumer on next transaction in consensus availability
-                if let Some(waker) = { self.conf.write().unwrap().waker.take() } {
-                    debug!("{}: calling waker", self.me_a());
-                    waker.wake();
-                }
-            }
         }
-        Ok(true)
+    }
+
+    #[test]
+    fn test_check_event_timestamp_bound() {
+        let mut dag = RecordedDag::new(3);
+        let event = dag.add(0, 1, Vec::new());
+        let core = new_core(&dag, 1, None);
+        let mut ahead = event.clone();
+        ahead.signatures.clear();
+        ahead.timestamp = HybridTime {
+            wall: physical_now() + 3_600_000,
+            logical: 0,
+        };
+        ahead.event_hash().unwrap();
+        let err = core.check_event(&ahead).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ImplausibleTimestamp(_))
+        ));
+    }
+
+    #[test]
+    fn test_restart_restores_finality_and_lamport_time() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(12);
+        let path = temp_store_dir("core-restart");
+        let (last_finalised_frame, lamport_time) = {
+            let mut core = new_core(&dag, 0, Some(&path));
+            insert_events(&mut core, &dag.events);
+            (core.last_finalised_frame, core.get_lamport_time())
+        };
+        assert!(last_finalised_frame.is_some());
+        let core = new_core(&dag, 0, Some(&path));
+        assert_eq!(core.last_finalised_frame, last_finalised_frame);
+        assert_eq!(core.get_lamport_time(), lamport_time);
+        assert_eq!(core.max_event_lamport_time, lamport_time);
+    }
+
+    #[test]
+    fn test_restart_restores_pruned_frames() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(16);
+        let half = dag.events.len() / 2;
+        let path = temp_store_dir("core-restart-pruned");
+        let conf = || {
+            let mut conf = new_conf(&dag, 0, Some(&path));
+            conf.retention = RetentionPolicy::KeepFrames(1);
+            conf
+        };
+        let (next_prune_frame, last_finalised_frame) = {
+            let mut core = TestCore::new(conf()).unwrap();
+            insert_events(&mut core, &dag.events[..half]);
+            while core.next_batch().unwrap().is_some() {}
+            insert_events(&mut core, &dag.events[half..]);
+            (core.next_prune_frame, core.last_finalised_frame)
+        };
+        assert!(next_prune_frame > 0);
+        let mut core = TestCore::new(conf()).unwrap();
+        assert_eq!(core.next_prune_frame, next_prune_frame);
+        assert_eq!(core.last_finalised_frame, last_finalised_frame);
+        {
+            let store = core.store.read().unwrap();
+            assert!(store.get_finalised_frame(next_prune_frame - 1).is_err());
+        }
+        let err = core
+            .get_catch_up_frames(0, 2, u64::max_value())
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::PrunedFrame(0)));
+        // delivery goes on from the first frame kept
+        if let Some(batch) = core.next_batch().unwrap() {
+            assert!(batch.frame >= next_prune_frame);
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_continues_finalisation() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(20);
+        let half = dag.events.len() / 2;
+        let dir = temp_store_dir("core-checkpoint");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("checkpoint");
+        let mut source = new_core(&dag, 0, None);
+        insert_events(&mut source, &dag.events[..half]);
+        let last = source.last_finalised_frame.unwrap();
+        source.export_checkpoint(&path).unwrap();
+        let mut conf = new_conf(&dag, 1, None);
+        conf.checkpoint = Some(path.clone());
+        let mut restored = TestCore::new(conf).unwrap();
+        assert_eq!(restored.last_finalised_frame, Some(last));
+        insert_events(&mut source, &dag.events[half..]);
+        insert_events(&mut restored, &dag.events[half..]);
+        assert!(source.last_finalised_frame > Some(last));
+        assert_eq!(restored.last_finalised_frame, source.last_finalised_frame);
+        let hashes = |core: &TestCore, frame: FrameNumber| -> HashSet<EventHash> {
+            let store = core.store.read().unwrap();
+            let frame = store.get_finalised_frame(frame).unwrap();
+            frame.events.iter().map(|r| r.hash).collect()
+        };
+        for frame in last + 1..=source.last_finalised_frame.unwrap() {
+            assert_eq!(hashes(&restored, frame), hashes(&source, frame));
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_imported_once() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(24);
+        let half = dag.events.len() / 2;
+        let three_quarters = dag.events.len() * 3 / 4;
+        let dir = temp_store_dir("core-checkpoint-once");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("checkpoint");
+        let mut source = new_core(&dag, 0, None);
+        insert_events(&mut source, &dag.events[..half]);
+        let last = source.last_finalised_frame.unwrap();
+        source.export_checkpoint(&path).unwrap();
+        let store_dir = temp_store_dir("core-checkpoint-once-store");
+        let conf = || {
+            let mut conf = new_conf(&dag, 1, Some(&store_dir));
+            conf.checkpoint = Some(path.clone());
+            conf
+        };
+        let before_restart = {
+            let mut restored = TestCore::new(conf()).unwrap();
+            insert_events(&mut restored, &dag.events[half..three_quarters]);
+            restored.last_finalised_frame
+        };
+        assert!(before_restart > Some(last));
+        // the restart keeps frames finalised since the import
+        let mut restarted = TestCore::new(conf()).unwrap();
+        assert_eq!(restarted.last_finalised_frame, before_restart);
+        insert_events(&mut restarted, &dag.events[three_quarters..]);
+        insert_events(&mut source, &dag.events[half..]);
+        assert!(restarted.last_finalised_frame > before_restart);
+        assert_eq!(restarted.last_finalised_frame, source.last_finalised_frame);
+    }
+
+    #[test]
+    fn test_catch_up_given_up_when_all_peers_pruned() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(12);
+        let mut source = new_core(&dag, 0, None);
+        insert_events(&mut source, &dag.events);
+        source.next_prune_frame = 1;
+        let mut target = new_core(&dag, 1, None);
+        target.update_catch_up(usize::max_value());
+        let from = target.catch_up_frame().unwrap();
+        let err = source
+            .get_catch_up_frames(from, 2, u64::max_value())
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::PrunedFrame(from)));
+        target.record_pruned_reply(&dag.creator(0));
+        target.record_pruned_reply(&dag.creator(2));
+        assert_eq!(target.catch_up_frame(), Some(from));
+        // the last peer has pruned the frame too
+        target.record_pruned_reply(&dag.creator(3));
+        assert_eq!(target.catch_up_frame(), None);
+        // the node does not catch up from the frame again
+        target.update_catch_up(usize::max_value());
+        assert_eq!(target.catch_up_frame(), None);
+    }
+
+    #[test]
+    fn test_catch_up_frame_error_paths() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(12);
+        let mut source = new_core(&dag, 0, None);
+        insert_events(&mut source, &dag.events);
+        let frames = source.get_catch_up_frames(0, 2, u64::max_value()).unwrap();
+        assert_eq!(frames.len(), 2);
+        let mut target = new_core(&dag, 1, None);
+        // frames are ignored during normal gossip
+        assert_eq!(target.insert_catch_up_frame(frames[0].clone()).unwrap(), 0);
+        target.update_catch_up(usize::max_value());
+        assert_eq!(target.catch_up_frame(), Some(0));
+        let err = target.insert_catch_up_frame(frames[1].clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::InvalidCatchUpFrame(
+                1,
+                "frame 0 expected".to_string()
+            ))
+        );
+        let mut forged = frames[0].clone();
+        let last = dag.events.last().unwrap();
+        forged.records.events.push(FrameRecord {
+            hash: last.hash,
+            lamport_time: last.lamport_timestamp,
+            timestamp: last.timestamp,
+            received_time: None,
+            consensus_timestamp: HybridTime::default(),
+        });
+        let err = target.insert_catch_up_frame(forged).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::InvalidCatchUpFrame(
+                0,
+                "events do not match frame records".to_string()
+            ))
+        );
+        assert_eq!(target.catch_up_frame(), Some(0));
+        // frames pruned by the source are refused as such
+        source.next_prune_frame = 1;
+        let err = source
+            .get_catch_up_frames(0, 2, u64::max_value())
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::PrunedFrame(0)));
+    }
+
+    #[test]
+    fn test_oversized_messages_attributed_to_single_peer() {
+        let dag = RecordedDag::new(4);
+        let mut core = new_core(&dag, 0, None);
+        let remote = dag.creator(3);
+        {
+            let mut cfg = core.conf.write().unwrap();
+            let peer = cfg.peers.find_peer_mut(&remote).unwrap();
+            peer.request_addr = "10.0.0.2:20001".to_string();
+            peer.reply_addr = "10.0.0.2:20002".to_string();
+        }
+        core.report_oversized_message(&"10.0.0.2".parse().unwrap())
+            .unwrap();
+        // the host shared by the two other peers
+        core.report_oversized_message(&"127.0.0.1".parse().unwrap())
+            .unwrap();
+        let cfg = core.conf.read().unwrap();
+        for i in 1..4 {
+            let expected = if i == 3 { 20 } else { 0 };
+            let peer = cfg.peers.find_peer(&dag.creator(i)).unwrap();
+            assert_eq!(peer.get_misbehaviour(), expected);
+        }
     }
 }
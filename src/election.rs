@@ -0,0 +1,271 @@
+// Election of Atropos roots
+//
+// Every root of frame f+1 votes for each creator whether it strongly sees the
+// root of that creator in frame f: there are events of a supermajority of
+// creators which are descendants of the root and ancestors of the voter.
+// Roots of frame f+2 and later collect votes of the roots of the previous frame
+// they strongly see: if a supermajority of those votes agree, the candidate is
+// decided; otherwise the root votes with the majority and the election goes on
+// in the next frame. Once candidates of all creators of frame f are decided,
+// the Atropos of frame f is the root of the first creator, in creator order,
+// which is decided positively.
+//
+// A positive vote names the root voted for, so a creator which forks and has
+// several roots in a frame is decided for one of them only: a voter strongly
+// seeing several of them votes for the one with the lowest hash, and ties of
+// votes are broken towards positive votes and then towards the lowest hash.
+//
+// Candidates are identified by creator position in DAGPeerList and frame number.
+
+use crate::peer::FrameNumber;
+use libhash_sha3::Hash as EventHash;
+use std::collections::HashMap;
+
+type RootId = (usize, FrameNumber);
+
+// Vote on a candidate: the root of the candidate voted for, None if negative
+type Vote = Option<EventHash>;
+
+pub(crate) enum AtroposDecision {
+    // some candidates of the frame are not decided yet
+    Undecided,
+    Elected(EventHash),
+    // all candidates of the frame are decided negatively
+    NoAtropos,
+}
+
+#[derive(Default)]
+pub(crate) struct Election {
+    // roots of each creator and frame; a forking creator may have several
+    roots: HashMap<RootId, Vec<EventHash>>,
+    // votes of each root on candidates
+    votes: HashMap<EventHash, HashMap<RootId, Vote>>,
+    decisions: HashMap<RootId, Vote>,
+    // the lowest frame whose Atropos is not determined yet
+    first_undecided: FrameNumber,
+}
+
+// Checks if vote a is preferred to vote b when they are cast equally often
+fn preferred(a: &Vote, b: &Vote) -> bool {
+    match (a, b) {
+        (Some(x), Some(y)) => x < y,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+impl Election {
+    // Registers a new root of the creator and computes its votes on candidates
+    // of undecided frames preceding the root's frame; `strongly_seen` are roots
+    // of the previous frame strongly seen by the new root with their creators,
+    // `creators` is the number of creators.
+    pub(crate) fn add_root(
+        &mut self,
+        root: EventHash,
+        creator: usize,
+        frame: FrameNumber,
+        strongly_seen: &[(usize, EventHash)],
+        creators: usize,
+        supermajority: usize,
+    ) {
+        // late roots of frames already finalised take no part in the election
+        if frame < self.first_undecided {
+            return;
+        }
+        let roots = self.roots.entry((creator, frame)).or_insert_with(Vec::new);
+        if roots.contains(&root) {
+            return;
+        }
+        roots.push(root);
+        if frame == 0 {
+            return;
+        }
+        let mut votes: HashMap<RootId, Vote> = HashMap::new();
+        for f in self.first_undecided..frame {
+            for c in 0..creators {
+                let candidate = (c, f);
+                if self.decisions.contains_key(&candidate) {
+                    continue;
+                }
+                if f + 1 == frame {
+                    let mut vote: Vote = None;
+                    for (_, seen) in strongly_seen.iter().filter(|(sc, _)| *sc == c) {
+                        if vote.map_or(true, |v| *seen < v) {
+                            vote = Some(*seen);
+                        }
+                    }
+                    votes.insert(candidate, vote);
+                    continue;
+                }
+                let mut tally: HashMap<Vote, usize> = HashMap::new();
+                for (_, voter) in strongly_seen.iter() {
+                    if let Some(vote) = self.votes.get(voter).and_then(|v| v.get(&candidate)) {
+                        *tally.entry(*vote).or_insert(0) += 1;
+                    }
+                }
+                let mut majority: Option<(Vote, usize)> = None;
+                for (vote, count) in tally.into_iter() {
+                    majority = match majority {
+                        Some((best, best_count))
+                            if best_count > count
+                                || (best_count == count && !preferred(&vote, &best)) =>
+                        {
+                            Some((best, best_count))
+                        }
+                        _ => Some((vote, count)),
+                    };
+                }
+                match majority {
+                    Some((vote, count)) if count >= supermajority => {
+                        self.decisions.insert(candidate, vote);
+                    }
+                    Some((vote, _)) => {
+                        votes.insert(candidate, vote);
+                    }
+                    None => {
+                        votes.insert(candidate, None);
+                    }
+                }
+            }
+        }
+        if !votes.is_empty() {
+            self.votes.insert(root, votes);
+        }
+    }
+
+    // Returns roots of the frame with their creators
+    pub(crate) fn roots(&self, frame: FrameNumber) -> Vec<(usize, EventHash)> {
+        let mut roots = Vec::new();
+        for ((creator, f), hashes) in self.roots.iter() {
+            if *f == frame {
+                roots.extend(hashes.iter().map(|h| (*creator, *h)));
+            }
+        }
+        roots
+    }
+
+    // Returns Atropos of the frame if it can be determined
+    pub(crate) fn atropos(&self, frame: FrameNumber, creators: usize) -> AtroposDecision {
+        for c in 0..creators {
+            match self.decisions.get(&(c, frame)) {
+                None => return AtroposDecision::Undecided,
+                Some(None) => continue,
+                Some(Some(root)) => return AtroposDecision::Elected(*root),
+            }
+        }
+        AtroposDecision::NoAtropos
+    }
+
+    // Returns roots of the frame decided positively, in creator order
+    pub(crate) fn famous_roots(&self, frame: FrameNumber, creators: usize) -> Vec<EventHash> {
+        (0..creators)
+            .filter_map(|c| match self.decisions.get(&(c, frame)) {
+                Some(Some(root)) => Some(*root),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Forgets roots, votes and decisions not needed after the frame is finalised
+    pub(crate) fn prune(&mut self, frame: FrameNumber) {
+        self.roots.retain(|(_, f), _| *f > frame);
+        self.votes.retain(|_, votes| {
+            votes.retain(|(_, f), _| *f > frame);
+            !votes.is_empty()
+        });
+        self.decisions.retain(|(_, f), _| *f > frame);
+        self.first_undecided = frame + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREATORS: usize = 4;
+    const SUPERMAJORITY: usize = 3;
+
+    fn hash(i: usize) -> EventHash {
+        EventHash::new(&i).unwrap()
+    }
+
+    // Adds roots of frame 0 and roots of frames 1 and 2 of all creators which
+    // strongly see `seen` roots of frame 0 and all roots of frame 1 respectively
+    fn elect(election: &mut Election, frame0: &[(usize, EventHash)], seen: &[(usize, EventHash)]) {
+        for (creator, root) in frame0.iter() {
+            election.add_root(*root, *creator, 0, &[], CREATORS, SUPERMAJORITY);
+        }
+        let mut frame1 = Vec::new();
+        for creator in 0..CREATORS {
+            let root = hash(100 + creator);
+            election.add_root(root, creator, 1, seen, CREATORS, SUPERMAJORITY);
+            frame1.push((creator, root));
+        }
+        for creator in 0..CREATORS {
+            election.add_root(
+                hash(200 + creator),
+                creator,
+                2,
+                &frame1,
+                CREATORS,
+                SUPERMAJORITY,
+            );
+        }
+    }
+
+    #[test]
+    fn test_atropos_is_first_root_strongly_seen() {
+        let mut election = Election::default();
+        let frame0: Vec<(usize, EventHash)> = (0..CREATORS).map(|c| (c, hash(c))).collect();
+        // the root of creator 0 is not strongly seen by any root of frame 1
+        elect(&mut election, &frame0, &frame0[1..]);
+        match election.atropos(0, CREATORS) {
+            AtroposDecision::Elected(root) => assert_eq!(root, hash(1)),
+            _ => panic!("Atropos of frame 0 is not elected"),
+        }
+        assert_eq!(
+            election.famous_roots(0, CREATORS),
+            vec![hash(1), hash(2), hash(3)]
+        );
+    }
+
+    #[test]
+    fn test_fork_decided_for_lowest_hash() {
+        let (a, b) = if hash(10) < hash(11) {
+            (hash(10), hash(11))
+        } else {
+            (hash(11), hash(10))
+        };
+        let mut frame0: Vec<(usize, EventHash)> = (1..CREATORS).map(|c| (c, hash(c))).collect();
+        // creator 0 forks; the fork with the higher hash arrives first
+        frame0.insert(0, (0, a));
+        frame0.insert(0, (0, b));
+        let mut election = Election::default();
+        elect(&mut election, &frame0, &frame0);
+        match election.atropos(0, CREATORS) {
+            AtroposDecision::Elected(root) => assert_eq!(root, a),
+            _ => panic!("Atropos of frame 0 is not elected"),
+        }
+    }
+
+    #[test]
+    fn test_no_atropos_and_undecided_frames() {
+        let mut election = Election::default();
+        let frame0: Vec<(usize, EventHash)> = (0..CREATORS).map(|c| (c, hash(c))).collect();
+        match election.atropos(0, CREATORS) {
+            AtroposDecision::Undecided => {}
+            _ => panic!("frame 0 is decided without votes"),
+        }
+        elect(&mut election, &frame0, &[]);
+        match election.atropos(0, CREATORS) {
+            AtroposDecision::NoAtropos => {}
+            _ => panic!("frame 0 has an Atropos"),
+        }
+        election.prune(0);
+        assert!(election.roots(0).is_empty());
+        // late roots of finalised frames are ignored
+        election.add_root(hash(50), 0, 0, &[], CREATORS, SUPERMAJORITY);
+        assert!(election.roots(0).is_empty());
+        assert_eq!(election.roots(1).len(), CREATORS);
+    }
+}
@@ -0,0 +1,123 @@
+// Finality engine abstraction
+//
+// DAGcore validates and stores events; a finality engine assigns frame numbers
+// to events, keeps its own state about them and decides which events become
+// final. Ordering of events within finalised batches is up to DAGcore.
+
+use crate::errors::Result;
+use crate::event::Event;
+use crate::frame::FrameRecord;
+use crate::hashgraph::Hashgraph;
+use crate::hlc::HybridTime;
+use crate::lachesis::Lachesis;
+use crate::ordering::OrderingMode;
+use crate::peer::FrameNumber;
+use crate::store::DAGstore;
+use libcommon_rs::data::DataType;
+use libcommon_rs::peer::PeerId;
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+
+// Type of finality engine; chosen in DAGconfig
+#[derive(Clone, Debug, PartialEq)]
+pub enum FinalityType {
+    // frames and flag tables with Atropos election
+    Lachesis,
+    // rounds, witnesses and famous witness virtual voting
+    Hashgraph,
+}
+
+impl Default for FinalityType {
+    fn default() -> FinalityType {
+        FinalityType::Lachesis
+    }
+}
+
+// Creators taking part in consensus, in creator order, and the number of
+// creators forming a supermajority
+pub(crate) struct Participants<P> {
+    pub(crate) creators: Vec<P>,
+    pub(crate) supermajority: usize,
+}
+
+// A batch of events finalised together; frame numbers of batches
+// are consecutive starting from 0
+pub(crate) struct FinalisedBatch {
+    pub(crate) frame: FrameNumber,
+    // events of the batch in no particular order; received time is set
+    // if the engine defines it
+    pub(crate) events: Vec<FrameRecord>,
+    // consensus timestamp of the batch derived from timestamps of famous
+    // roots; None if consensus timestamps are received times of the events
+    pub(crate) consensus_time: Option<HybridTime>,
+}
+
+pub(crate) trait FinalityEngine<Data, P, PK, Sig>: Send + Sync
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    // Registers a leaf event, which has no parents; called before it is stored
+    fn add_leaf_event(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        event: &Event<Data, P, PK, Sig>,
+        participants: &Participants<P>,
+    ) -> Result<()>;
+
+    // Processes a new event whose parents are stored already and sets its
+    // frame number; called before the event is stored
+    fn add_event(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        event: &mut Event<Data, P, PK, Sig>,
+        participants: &Participants<P>,
+    ) -> Result<()>;
+
+    // Returns batches finalised after the event added last has been stored,
+    // in frame order; the engine may prune its data of finalised events
+    fn finalised_batches(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        participants: &Participants<P>,
+    ) -> Result<Vec<FinalisedBatch>>;
+
+    // Resumes finality from a checkpoint: frames below `next_frame` are
+    // finalised already; called before events of the checkpoint are added
+    fn restore(&mut self, next_frame: FrameNumber) -> Result<()>;
+
+    // Rebuilds state of the engine from `events` stored earlier, in Lamport
+    // time order, when the store is opened again: frames below `next_frame`
+    // are finalised already and batches of them are not returned again;
+    // called after leaf events are registered
+    fn rebuild(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        events: Vec<Event<Data, P, PK, Sig>>,
+        participants: &Participants<P>,
+        next_frame: FrameNumber,
+    ) -> Result<()>;
+
+    // Returns ordering mode used for batches of the engine given the one configured
+    fn ordering_mode(&self, configured: &OrderingMode) -> OrderingMode {
+        configured.clone()
+    }
+}
+
+// Creates finality engine of specified type
+pub(crate) fn new_finality_engine<Data, P, PK, Sig>(
+    finality: &FinalityType,
+) -> Box<dyn FinalityEngine<Data, P, PK, Sig>>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    match finality {
+        FinalityType::Lachesis => Box::new(Lachesis::default()),
+        FinalityType::Hashgraph => Box::new(Hashgraph::default()),
+    }
+}
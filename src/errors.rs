@@ -13,6 +13,8 @@ pub enum Error {
     Bincode(bincode::Error),
     #[fail(display = "Sled Error: {:?}", 0)]
     Sled(sled::Error),
+    #[fail(display = "Sled Transaction Error: {:?}", 0)]
+    SledTransaction(sled::TransactionError),
     #[fail(display = "Io Error: {:?}", 0)]
     Io(std::io::Error),
     #[fail(display = "SerdeJson Error: {:?}", 0)]
@@ -21,6 +23,36 @@ pub enum Error {
     NoneError,
     #[fail(display = "Libhash Error: {:?}", 0)]
     LibHash(LibhashError),
+    #[fail(display = "Node cannot ban itself")]
+    SelfBan,
+    #[fail(display = "Unknown peer: {}", 0)]
+    UnknownPeer(String),
+    #[fail(display = "Implausible Lamport time {}; bound: {}", 0, 1)]
+    ImplausibleLamportTime(usize, usize),
+    #[fail(display = "Implausible timestamp: {}", 0)]
+    ImplausibleTimestamp(String),
+    #[fail(
+        display = "Store schema version {} cannot be used; supported version: {}",
+        0, 1
+    )]
+    UnsupportedSchema(u32, u32),
+    #[fail(
+        display = "Store {} has the unversioned layout of a database per record kind, which cannot be migrated; remove it and catch up from peers",
+        0
+    )]
+    LegacyStoreLayout(String),
+    #[fail(display = "Checkpoint cannot be used: {}", 0)]
+    UnsupportedCheckpoint(String),
+    #[fail(display = "Message of {} bytes exceeds maximal message size {}", 0, 1)]
+    OversizedMessage(u64, u64),
+    #[fail(display = "Invalid catch-up frame {}: {}", 0, 1)]
+    InvalidCatchUpFrame(usize, String),
+    #[fail(display = "Finalised frame {} is pruned", 0)]
+    PrunedFrame(usize),
+    #[fail(display = "Finality state cannot be rebuilt: {}", 0)]
+    UnrecoverableState(String),
+    #[fail(display = "Parent event {} is neither known nor pruned", 0)]
+    UnknownParent(String),
 }
 
 impl From<LibhashError> for Error {
@@ -44,6 +76,13 @@ impl From<sled::Error> for Error {
     }
 }
 
+impl From<sled::TransactionError> for Error {
+    #[inline]
+    fn from(transaction_error: sled::TransactionError) -> Error {
+        Error::SledTransaction(transaction_error)
+    }
+}
+
 impl From<bincode::Error> for Error {
     #[inline]
     fn from(bincode_error: bincode::Error) -> Error {
@@ -76,6 +115,36 @@ impl PartialEq for Error {
     fn eq(&self, other: &Error) -> bool {
         match *self {
             Error::NoneError => *other == Error::NoneError,
+            Error::SelfBan => matches!(*other, Error::SelfBan),
+            Error::ImplausibleLamportTime(l0, l1) => {
+                matches!(*other, Error::ImplausibleLamportTime(r0, r1) if l0 == r0 && l1 == r1)
+            }
+            Error::ImplausibleTimestamp(ref l) => {
+                matches!(*other, Error::ImplausibleTimestamp(ref r) if l == r)
+            }
+            Error::UnsupportedSchema(l0, l1) => {
+                matches!(*other, Error::UnsupportedSchema(r0, r1) if l0 == r0 && l1 == r1)
+            }
+            Error::LegacyStoreLayout(ref l) => {
+                matches!(*other, Error::LegacyStoreLayout(ref r) if l == r)
+            }
+            Error::UnsupportedCheckpoint(ref l) => {
+                matches!(*other, Error::UnsupportedCheckpoint(ref r) if l == r)
+            }
+            Error::OversizedMessage(l0, l1) => {
+                matches!(*other, Error::OversizedMessage(r0, r1) if l0 == r0 && l1 == r1)
+            }
+            Error::InvalidCatchUpFrame(l0, ref l1) => {
+                matches!(*other, Error::InvalidCatchUpFrame(r0, ref r1) if l0 == r0 && l1 == r1)
+            }
+            Error::PrunedFrame(l) => matches!(*other, Error::PrunedFrame(r) if l == r),
+            Error::UnrecoverableState(ref l) => {
+                matches!(*other, Error::UnrecoverableState(ref r) if l == r)
+            }
+            Error::UnknownParent(ref l) => {
+                matches!(*other, Error::UnknownParent(ref r) if l == r)
+            }
+            Error::UnknownPeer(ref l) => matches!(*other, Error::UnknownPeer(ref r) if l == r),
             Error::SerdeJson(ref _l) => {
                 // FIXME: serde_json::error::Error has no PartialEq trait implemented
                 false
@@ -91,6 +160,9 @@ impl PartialEq for Error {
                     false
                 }
             }
+            Error::SledTransaction(ref l) => {
+                matches!(*other, Error::SledTransaction(ref r) if l == r)
+            }
             Error::Bincode(ref _l) => {
                 // FIXME: add comparison for bincode::Error
                 false
@@ -1,4 +1,5 @@
 use crate::errors::Result;
+use crate::hlc::HybridTime;
 use crate::lamport_time::LamportTime;
 use crate::peer::FrameNumber;
 use crate::peer::Height;
@@ -29,7 +30,11 @@ where
     pub(crate) self_parent: EventHash,
     pub(crate) other_parent: EventHash,
     pub(crate) lamport_timestamp: LamportTime,
+    pub(crate) timestamp: HybridTime,
     pub(crate) transactions: Vec<Data>,
+    // hashes of transactions first seen by the creator since its previous event,
+    // in order of arrival; recorded in FairOrder ordering mode only
+    pub(crate) tx_arrivals: Vec<EventHash>,
     #[serde(bound = "")]
     pub(crate) internal_transactions: Vec<InternalTransaction<P, PK>>,
     pub(crate) hash: EventHash,
@@ -52,7 +57,9 @@ where
     self_parent: EventHash,
     other_parent: EventHash,
     pub(crate) lamport_timestamp: LamportTime,
+    pub(crate) timestamp: HybridTime,
     transactions: Vec<Data>,
+    tx_arrivals: Vec<EventHash>,
     #[serde(bound = "")]
     internal_transactions: Vec<InternalTransaction<P, PK>>,
     #[serde(bound = "")]
@@ -71,7 +78,9 @@ where
     self_parent: EventHash,
     other_parent: EventHash,
     lamport_timestamp: LamportTime,
+    timestamp: HybridTime,
     transactions: Vec<Data>,
+    tx_arrivals: Vec<EventHash>,
     internal_transactions: Vec<InternalTransaction<P, PK>>,
 }
 
@@ -97,7 +106,9 @@ where
             self_parent: ev.self_parent,
             other_parent: ev.other_parent,
             lamport_timestamp: ev.lamport_timestamp,
+            timestamp: ev.timestamp,
             transactions: ev.transactions,
+            tx_arrivals: ev.tx_arrivals,
             internal_transactions: ev.internal_transactions,
         }
     }
@@ -116,7 +127,9 @@ where
             self_parent: ev.self_parent,
             other_parent: ev.other_parent,
             lamport_timestamp: ev.lamport_timestamp,
+            timestamp: ev.timestamp,
             transactions: ev.transactions,
+            tx_arrivals: ev.tx_arrivals,
             internal_transactions: ev.internal_transactions,
             signatures: ev.signatures,
         }
@@ -137,7 +150,9 @@ where
             self_parent: ev.self_parent,
             other_parent: ev.other_parent,
             lamport_timestamp: ev.lamport_timestamp,
+            timestamp: ev.timestamp,
             transactions: ev.transactions,
+            tx_arrivals: ev.tx_arrivals,
             internal_transactions: ev.internal_transactions,
             hash: EventHash::default(),
             signatures: ev.signatures,
@@ -157,9 +172,9 @@ where
 {
     fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
         let mut formatted = String::new();
-        formatted.push_str(&format!("creator:{}; height:{}; self-parent:{}; other-parent:{}; lamport_time:{}; hash:{}; frame:{}; transactions: {:#?}; signatures: ",
+        formatted.push_str(&format!("creator:{}; height:{}; self-parent:{}; other-parent:{}; lamport_time:{}; timestamp:{}; hash:{}; frame:{}; transactions: {:#?}; signatures: ",
         self.creator, self.height, self.self_parent, self.other_parent,
-        self.lamport_timestamp, self.hash, self.frame_number,
+        self.lamport_timestamp, self.timestamp, self.hash, self.frame_number,
         self.transactions));
         for (signatory, signature) in self.signatures.iter() {
             formatted.push_str(&format!("({}*{})", signatory, signature));
@@ -177,9 +192,9 @@ where
 {
     fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
         let mut formatted = String::new();
-        formatted.push_str(&format!("creator:{}; height:{}; self-parent:{}; other-parent:{}; lamport_time:{}; transactions: {:#?}; signatures: ",
+        formatted.push_str(&format!("creator:{}; height:{}; self-parent:{}; other-parent:{}; lamport_time:{}; timestamp:{}; transactions: {:#?}; signatures: ",
         self.creator, self.height, self.self_parent, self.other_parent,
-        self.lamport_timestamp, self.transactions));
+        self.lamport_timestamp, self.timestamp, self.transactions));
         for (signatory, signature) in self.signatures.iter() {
             formatted.push_str(&format!("({}*{})", signatory, signature));
         }
@@ -200,7 +215,9 @@ where
         self_parent: EventHash,
         other_parent: EventHash,
         lamport_timestamp: LamportTime,
+        timestamp: HybridTime,
         transactions: Vec<Data>,
+        tx_arrivals: Vec<EventHash>,
         internal_transactions: Vec<InternalTransaction<P, PK>>,
     ) -> Self {
         Event {
@@ -209,7 +226,9 @@ where
             self_parent,
             other_parent,
             lamport_timestamp,
+            timestamp,
             transactions,
+            tx_arrivals,
             internal_transactions,
             hash: EventHash::default(),
             signatures: HashMap::new(),
@@ -222,6 +241,9 @@ where
     pub(crate) fn get_lamport_time(&self) -> LamportTime {
         self.lamport_timestamp
     }
+    pub(crate) fn get_timestamp(&self) -> HybridTime {
+        self.timestamp
+    }
     pub(crate) fn get_height(&self) -> Height {
         self.height
     }
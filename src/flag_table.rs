@@ -1,102 +1,198 @@
 use crate::peer::FrameNumber;
-use libcommon_rs::peer::PeerId;
-use libhash_sha3::Hash as EventHash;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
 
-// FlagTable is a map from EventHash into Frame number
-pub(crate) type FlagTable = HashMap<EventHash, FrameNumber>;
-// CreatorFlagTable is a map from PeerId into Frame number (Frame)
-pub(crate) type CreatorFlagTable<P> = HashMap<P, FrameNumber>;
+// FlagTable records roots visible from an event as a vector indexed by creator
+// position in DAGPeerList; for each creator it holds frame numbers of its visible
+// roots in ascending order. A creator has at most one root in every frame, so
+// a root is identified by the creator and the frame number.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct FlagTable {
+    frames: Vec<Vec<FrameNumber>>,
+}
 
-pub(crate) fn flag_table_fmt(ft: &FlagTable) -> String {
-    let mut formatted = String::new();
-    formatted.push_str("[");
-    for (k, v) in ft {
-        formatted.push_str(&format!("{}:{},", k, v));
+impl FlagTable {
+    // Creates a flag table with no visible roots for specified number of creators
+    pub(crate) fn new(creators: usize) -> FlagTable {
+        FlagTable {
+            frames: vec![Vec::new(); creators],
+        }
+    }
+    // Marks root of the creator in specified frame as visible
+    pub(crate) fn insert(&mut self, creator: usize, frame: FrameNumber) {
+        if self.frames.len() <= creator {
+            self.frames.resize(creator + 1, Vec::new());
+        }
+        let frames = &mut self.frames[creator];
+        if let Err(pos) = frames.binary_search(&frame) {
+            frames.insert(pos, frame);
+        }
+    }
+    // Checks if root of the creator in specified frame is visible
+    pub(crate) fn sees(&self, creator: usize, frame: FrameNumber) -> bool {
+        match self.frames.get(creator) {
+            Some(frames) => frames.binary_search(&frame).is_ok(),
+            None => false,
+        }
+    }
+    // Returns positions of creators whose roots in specified frame are visible
+    pub(crate) fn creators_seen(&self, frame: FrameNumber) -> Vec<usize> {
+        (0..self.frames.len())
+            .filter(|c| self.sees(*c, frame))
+            .collect()
+    }
+    // Drops all visible roots except those in specified frame
+    pub(crate) fn compact(&mut self, frame: FrameNumber) {
+        for frames in self.frames.iter_mut() {
+            frames.retain(|f| *f == frame);
+        }
     }
-    formatted.push_str("]");
-    formatted
 }
 
-pub(crate) fn creator_flag_table_fmt<P>(ft: &CreatorFlagTable<P>) -> String
-where
-    P: PeerId,
-{
+pub(crate) fn flag_table_fmt(ft: &FlagTable) -> String {
     let mut formatted = String::new();
     formatted.push_str("[");
-    for (k, v) in ft {
-        formatted.push_str(&format!("{}:{},", k, v));
+    for (creator, frames) in ft.frames.iter().enumerate() {
+        if !frames.is_empty() {
+            formatted.push_str(&format!("{}:{:?},", creator, frames));
+        }
     }
     formatted.push_str("]");
     formatted
 }
 
 // Strict flag table merging procedure takes two flag tables and the frame number
-// and forms a new flag table which contains only those entries from any of source
-// flag tables whose corresponding frame number is equal to the frame number specified.
+// and forms a new flag table which contains only those roots visible in any of
+// source flag tables whose frame number is equal to the frame number specified.
 pub(crate) fn strict_merge_flag_table(
     first: &FlagTable,
     second: &FlagTable,
     frame_number: FrameNumber,
 ) -> FlagTable {
-    let mut result = FlagTable::new();
-    for (key, value) in first.iter() {
-        if *value == frame_number {
-            result.insert(*key, *value);
-        }
-    }
-    for (key, value) in second.iter() {
-        if *value == frame_number {
-            result.insert(*key, *value);
+    let creators = first.frames.len().max(second.frames.len());
+    let mut result = FlagTable::new(creators);
+    for creator in 0..creators {
+        if first.sees(creator, frame_number) || second.sees(creator, frame_number) {
+            result.frames[creator].push(frame_number);
         }
     }
     result
 }
 
 // Open flag table merging procedure takes two flag tables and the frame number
-// and forms a new flagtable which contains only those entries from any of source
-// flag tables whose corresponding frame number is equal or greater to the frame
+// and forms a new flag table which contains only those roots visible in any of
+// source flag tables whose frame number is equal or greater to the frame
 // number specified.
 pub(crate) fn open_merge_flag_table(
     first: &FlagTable,
     second: &FlagTable,
     frame_number: FrameNumber,
 ) -> FlagTable {
-    let mut result = FlagTable::new();
-    for (key, value) in first.iter() {
-        if *value >= frame_number {
-            result.insert((*key).clone(), (*value).clone());
-        }
-    }
-    for (key, value) in second.iter() {
-        match first.get(key) {
-            Some(number) => {
-                if *number > *value {
-                    result.insert((*key).clone(), (*value).clone());
+    let creators = first.frames.len().max(second.frames.len());
+    let mut result = FlagTable::new(creators);
+    let empty = Vec::new();
+    for creator in 0..creators {
+        let a = first.frames.get(creator).unwrap_or(&empty);
+        let b = second.frames.get(creator).unwrap_or(&empty);
+        let merged = &mut result.frames[creator];
+        // both lists are sorted; merge them dropping duplicates
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            let next = if j >= b.len() || (i < a.len() && a[i] <= b[j]) {
+                let x = a[i];
+                i += 1;
+                if j < b.len() && b[j] == x {
+                    j += 1;
                 }
-            }
-            _ => {
-                result.insert((*key).clone(), (*value).clone());
+                x
+            } else {
+                let x = b[j];
+                j += 1;
+                x
+            };
+            if next >= frame_number {
+                merged.push(next);
             }
         }
     }
     result
 }
 
-pub(crate) fn min_frame<P>(ft: &CreatorFlagTable<P>) -> FrameNumber {
-    let mut res: Option<FrameNumber> = None;
-    for (_, frame) in ft.iter() {
-        match res {
-            None => res = Some(*frame),
-            Some(x) => {
-                if x > *frame {
-                    res = Some(*frame)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    const CREATORS: usize = 5;
+    const FRAMES: FrameNumber = 8;
+
+    // Builds a flag table with the same roots as the set, inserted in the order given
+    fn from_roots(roots: &[(usize, FrameNumber)]) -> FlagTable {
+        let mut ft = FlagTable::new(CREATORS);
+        for (creator, frame) in roots.iter() {
+            ft.insert(*creator, *frame);
+        }
+        ft
+    }
+
+    // Pseudo-random roots, some of them repeated
+    fn roots(seed: usize) -> Vec<(usize, FrameNumber)> {
+        let mut x = seed;
+        (0..20)
+            .map(|_| {
+                x = (x * 1_103_515_245 + 12345) % (1 << 31);
+                ((x >> 8) % CREATORS, (x >> 16) % FRAMES)
+            })
+            .collect()
+    }
+
+    fn as_set(ft: &FlagTable) -> HashSet<(usize, FrameNumber)> {
+        let mut set = HashSet::new();
+        for creator in 0..CREATORS {
+            for frame in 0..FRAMES {
+                if ft.sees(creator, frame) {
+                    set.insert((creator, frame));
                 }
             }
         }
+        set
     }
-    match res {
-        None => 0,
-        Some(x) => x,
+
+    #[test]
+    fn test_insert_and_sees() {
+        let mut ft = from_roots(&[(2, 3), (2, 1), (2, 3), (0, 3)]);
+        assert_eq!(ft.frames[2], vec![1, 3]);
+        assert!(ft.sees(0, 3));
+        assert!(!ft.sees(0, 1));
+        // creators beyond the table are not seen until inserted
+        assert!(!ft.sees(CREATORS + 1, 3));
+        ft.insert(CREATORS + 1, 3);
+        assert_eq!(ft.creators_seen(3), vec![0, 2, CREATORS + 1]);
+    }
+
+    #[test]
+    fn test_merges_match_set_semantics() {
+        for seed in 1..20 {
+            let (a, b) = (roots(seed), roots(seed + 100));
+            let (first, second) = (from_roots(&a), from_roots(&b));
+            let union: HashSet<(usize, FrameNumber)> = a.iter().chain(b.iter()).cloned().collect();
+            for frame in 0..FRAMES {
+                let strict: HashSet<_> = union.iter().filter(|r| r.1 == frame).cloned().collect();
+                let open: HashSet<_> = union.iter().filter(|r| r.1 >= frame).cloned().collect();
+                assert_eq!(
+                    as_set(&strict_merge_flag_table(&first, &second, frame)),
+                    strict
+                );
+                assert_eq!(as_set(&open_merge_flag_table(&first, &second, frame)), open);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_forgets_finalised_frames() {
+        let mut ft = from_roots(&[(2, 3), (2, 1), (0, 3), (1, 0)]);
+        ft.compact(3);
+        assert!(!ft.sees(2, 1));
+        assert!(!ft.sees(1, 0));
+        assert_eq!(ft.creators_seen(3), vec![0, 2]);
     }
 }
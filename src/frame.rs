@@ -1,4 +1,6 @@
+use crate::hlc::HybridTime;
 use crate::lamport_time::LamportTime;
+use crate::ordering::{lamport_hash_cmp, median_timestamp_cmp, OrderingMode};
 use core::fmt::Display;
 use core::fmt::Formatter;
 use libhash_sha3::Hash as EventHash;
@@ -10,17 +12,25 @@ use serde::{Deserialize, Serialize};
 pub(crate) struct FrameRecord {
     pub(crate) hash: EventHash,
     pub(crate) lamport_time: LamportTime,
+    pub(crate) timestamp: HybridTime,
+    // assigned when the frame is finalised
+    pub(crate) received_time: Option<HybridTime>,
+    pub(crate) consensus_timestamp: HybridTime,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub(crate) struct Frame {
     pub(crate) events: Vec<FrameRecord>,
+    // final order of transactions as (event hash, transaction index);
+    // set at finalisation in FairOrder ordering mode only
+    pub(crate) tx_order: Vec<(EventHash, usize)>,
 }
 
 impl Default for Frame {
     fn default() -> Frame {
         Frame {
             events: Vec::with_capacity(1),
+            tx_order: Vec::new(),
         }
     }
 }
@@ -29,27 +39,92 @@ impl Display for Frame {
     fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
         write!(f, "[")?;
         for e in self.events.iter() {
-            write!(f, "({};{})", e.hash, e.lamport_time)?;
+            write!(
+                f,
+                "({};{};{})",
+                e.hash, e.lamport_time, e.consensus_timestamp
+            )?;
         }
         write!(f, "[")
     }
 }
 
 impl Frame {
-    pub(crate) fn finalise(&mut self) {
-        self.events.sort_by(|a, b| {
-            use std::cmp::Ordering;
-            if a.lamport_time < b.lamport_time {
-                Ordering::Less
-            } else if a.lamport_time > b.lamport_time {
-                Ordering::Greater
-            } else if a.hash < b.hash {
-                Ordering::Less
-            } else if a.hash > b.hash {
-                Ordering::Greater
-            } else {
-                Ordering::Equal
+    // Sorts events of the frame into the final order and assigns consensus
+    // timestamps to them; `last` is the consensus timestamp of the last event
+    // of the previous finalised frame. Consensus timestamp of an event is
+    // the consensus timestamp of the batch if the finality engine sets one and
+    // the received time of the event otherwise, raised where needed to keep them
+    // non-decreasing in the final order; own timestamps of events, which are
+    // claims of their creators, are never used. Returns the consensus timestamp
+    // of the last event.
+    pub(crate) fn finalise(
+        &mut self,
+        mode: &OrderingMode,
+        consensus_time: Option<HybridTime>,
+        last: HybridTime,
+    ) -> HybridTime {
+        match mode {
+            OrderingMode::LamportHash | OrderingMode::FairOrder => {
+                self.events.sort_by(lamport_hash_cmp)
             }
-        });
+            OrderingMode::MedianTimestamp => self.events.sort_by(median_timestamp_cmp),
+        }
+        let mut last = last;
+        for e in self.events.iter_mut() {
+            if let Some(time) = consensus_time.or(e.received_time) {
+                last = last.max(time);
+            }
+            e.consensus_timestamp = last;
+        }
+        last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(byte: u8, lamport_time: LamportTime, timestamp: HybridTime) -> FrameRecord {
+        FrameRecord {
+            hash: EventHash::new(&byte).unwrap(),
+            lamport_time,
+            timestamp,
+            received_time: None,
+            consensus_timestamp: HybridTime::default(),
+        }
+    }
+
+    #[test]
+    fn test_finalise_ignores_creator_timestamps() {
+        let mut frame = Frame::default();
+        // a creator claims a timestamp far in the future
+        frame
+            .events
+            .push(record(1, 2, HybridTime::new(1_000_000, 0)));
+        frame.events.push(record(2, 1, HybridTime::new(10, 0)));
+        let batch_time = HybridTime::new(20, 0);
+        let last = frame.finalise(
+            &OrderingMode::LamportHash,
+            Some(batch_time),
+            HybridTime::default(),
+        );
+        assert_eq!(last, batch_time);
+        assert!(frame
+            .events
+            .iter()
+            .all(|e| e.consensus_timestamp == batch_time));
+    }
+
+    #[test]
+    fn test_finalise_keeps_consensus_timestamps_non_decreasing() {
+        let mut frame = Frame::default();
+        let mut early = record(1, 1, HybridTime::new(10, 0));
+        early.received_time = Some(HybridTime::new(5, 0));
+        frame.events.push(early);
+        let previous = HybridTime::new(8, 0);
+        let last = frame.finalise(&OrderingMode::MedianTimestamp, None, previous);
+        assert_eq!(last, previous);
+        assert_eq!(frame.events[0].consensus_timestamp, previous);
     }
 }
@@ -0,0 +1,703 @@
+// Hashgraph virtual voting over the DAG of events
+//
+// Events are divided into rounds: an event is in the round of its parents, or
+// in the next one if it strongly sees witnesses of that round of a supermajority
+// of creators. The first event of a creator in a round is a witness. Fame of
+// witnesses is decided by virtual voting of witnesses of later rounds; once fame
+// of all witnesses of a round is decided, events which are ancestors of all unique
+// famous witnesses of the round get that round as round received. A famous witness
+// is unique unless its creator forks and has several famous witnesses in the round.
+
+use crate::engine::{FinalisedBatch, FinalityEngine, Participants};
+use crate::errors::{Error, Result};
+use crate::event::Event;
+use crate::frame::FrameRecord;
+use crate::hlc::{median_time, HybridTime};
+use crate::ordering::OrderingMode;
+use crate::peer::{FrameNumber, Height};
+use crate::store::DAGstore;
+use libcommon_rs::data::DataType;
+use libcommon_rs::peer::PeerId;
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+use std::collections::{HashMap, HashSet};
+use to_vec::ToVec;
+
+// Hashgraph state of an event
+struct HashgraphEvent<P> {
+    creator: P,
+    height: Height,
+    timestamp: HybridTime,
+    round: usize,
+    // for each creator the height of its highest event among ancestors
+    last_ancestors: HashMap<P, Height>,
+    // for each creator the height of its lowest event among descendants
+    first_descendants: HashMap<P, Height>,
+    round_received: Option<usize>,
+}
+
+// Witness of a round
+struct Witness {
+    hash: EventHash,
+    famous: Option<bool>,
+    // votes on fame of witnesses of earlier rounds
+    votes: HashMap<EventHash, bool>,
+}
+
+pub(crate) struct Hashgraph<P> {
+    events: HashMap<EventHash, HashgraphEvent<P>>,
+    // events by creator and height
+    chains: HashMap<(P, Height), EventHash>,
+    // witnesses of each round by creator; a forking creator may have several
+    witnesses: HashMap<usize, HashMap<P, Vec<Witness>>>,
+    // height of the latest event of each creator
+    latest: HashMap<P, Height>,
+    // height of the highest pruned event of each creator
+    pruned: HashMap<P, Height>,
+    // the lowest round whose events are not received yet
+    next_round: usize,
+}
+
+impl<P> Default for Hashgraph<P> {
+    fn default() -> Hashgraph<P> {
+        Hashgraph {
+            events: HashMap::new(),
+            chains: HashMap::new(),
+            witnesses: HashMap::new(),
+            latest: HashMap::new(),
+            pruned: HashMap::new(),
+            next_round: 0,
+        }
+    }
+}
+
+impl<P> Hashgraph<P>
+where
+    P: PeerId,
+{
+    // Every COIN_ROUND_PERIOD-th round of voting is a coin round
+    const COIN_ROUND_PERIOD: usize = 10;
+    // Number of rounds below the next round to be received whose events and
+    // witnesses are kept: rounds of late events may still depend on them
+    const PRUNE_MARGIN: usize = 2;
+
+    // Checks if event x has event y as an ancestor
+    fn sees(&self, x: &EventHash, y: &EventHash) -> bool {
+        match (self.events.get(x), self.events.get(y)) {
+            (Some(x), Some(y)) => match x.last_ancestors.get(&y.creator) {
+                Some(height) => *height >= y.height,
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Checks if event x strongly sees event y: there are events of a supermajority
+    // of creators which are descendants of y and ancestors of x
+    fn strongly_sees(&self, x: &EventHash, y: &EventHash, supermajority: usize) -> bool {
+        match (self.events.get(x), self.events.get(y)) {
+            (Some(x), Some(y)) => {
+                let count = y
+                    .first_descendants
+                    .iter()
+                    .filter(|(c, first)| match x.last_ancestors.get(*c) {
+                        Some(last) => *last >= **first,
+                        None => false,
+                    })
+                    .count();
+                count >= supermajority
+            }
+            _ => false,
+        }
+    }
+
+    // Adds an event to the hashgraph and returns its round; parents of the event
+    // must be added before, unless the event is a leaf event whose parents are
+    // default hashes. A parent pruned already is read from the store and stands
+    // for itself only: its ancestors are all received, so they can be neither
+    // descendants of witnesses still voting nor received again.
+    fn insert<Data, PK, Sig>(
+        &mut self,
+        store: &dyn DAGstore<Data, P, PK, Sig>,
+        event: &Event<Data, P, PK, Sig>,
+        supermajority: usize,
+    ) -> Result<usize>
+    where
+        Data: DataType,
+        PK: PublicKey,
+        Sig: Signature<Hash = EventHash, PublicKey = PK>,
+    {
+        if let Some(e) = self.events.get(&event.hash) {
+            return Ok(e.round);
+        }
+        let (hash, creator, height, timestamp) = (
+            event.hash,
+            event.creator.clone(),
+            event.height,
+            event.timestamp,
+        );
+        let mut last_ancestors: HashMap<P, Height> = HashMap::new();
+        let mut parent_round: Option<usize> = None;
+        let mut self_parent_round: Option<usize> = None;
+        for parent in [event.self_parent, event.other_parent].iter() {
+            if *parent == EventHash::default() {
+                continue;
+            }
+            let round = match self.events.get(parent) {
+                Some(p) => {
+                    for (c, h) in p.last_ancestors.iter() {
+                        add_ancestor(&mut last_ancestors, c, *h);
+                    }
+                    p.round
+                }
+                None => {
+                    let p = match store.get_event(parent) {
+                        Ok(p)
+                            if self
+                                .pruned
+                                .get(&p.creator)
+                                .map_or(false, |h| *h >= p.height) =>
+                        {
+                            p
+                        }
+                        _ => return Err(Error::UnknownParent(format!("{:?}", parent)).into()),
+                    };
+                    add_ancestor(&mut last_ancestors, &p.creator, p.height);
+                    p.frame_number
+                }
+            };
+            parent_round = Some(parent_round.map_or(round, |r| r.max(round)));
+            if *parent == event.self_parent {
+                self_parent_round = Some(round);
+            }
+        }
+        last_ancestors.insert(creator.clone(), height);
+        // the new event is the first descendant of its ancestors not having
+        // a descendant by its creator yet
+        for (c, h) in last_ancestors.iter() {
+            let mut k = *h;
+            loop {
+                let ex = match self.chains.get(&(c.clone(), k)) {
+                    Some(ex) => *ex,
+                    None => break,
+                };
+                match self.events.get_mut(&ex) {
+                    Some(e) => {
+                        if e.first_descendants.contains_key(&creator) {
+                            break;
+                        }
+                        e.first_descendants.insert(creator.clone(), height);
+                    }
+                    None => break,
+                }
+                if k == 0 {
+                    break;
+                }
+                k -= 1;
+            }
+        }
+        let mut first_descendants = HashMap::new();
+        first_descendants.insert(creator.clone(), height);
+        self.events.insert(
+            hash,
+            HashgraphEvent {
+                creator: creator.clone(),
+                height,
+                timestamp,
+                round: 0,
+                last_ancestors,
+                first_descendants,
+                round_received: None,
+            },
+        );
+        self.chains.insert((creator.clone(), height), hash);
+        let latest = self.latest.entry(creator.clone()).or_insert(height);
+        if *latest < height {
+            *latest = height;
+        }
+        // round of the event
+        let mut round = parent_round.unwrap_or(0);
+        if parent_round.is_some() {
+            let strongly_seen = match self.witnesses.get(&round) {
+                Some(witnesses) => witnesses
+                    .values()
+                    .filter(|ws| {
+                        ws.iter()
+                            .any(|w| self.strongly_sees(&hash, &w.hash, supermajority))
+                    })
+                    .count(),
+                None => 0,
+            };
+            if strongly_seen >= supermajority {
+                round += 1;
+            }
+        }
+        if let Some(e) = self.events.get_mut(&hash) {
+            e.round = round;
+        }
+        let witness = match self_parent_round {
+            None => true,
+            Some(r) => round > r,
+        };
+        // late witnesses of rounds received already count for rounds of
+        // later events only
+        if !witness || round + Self::PRUNE_MARGIN < self.next_round {
+            return Ok(round);
+        }
+        let votes = if round >= self.next_round {
+            self.vote(&hash, round, supermajority)
+        } else {
+            HashMap::new()
+        };
+        self.witnesses
+            .entry(round)
+            .or_insert_with(HashMap::new)
+            .entry(creator)
+            .or_insert_with(Vec::new)
+            .push(Witness {
+                hash,
+                famous: None,
+                votes,
+            });
+        Ok(round)
+    }
+
+    // Computes votes of a new witness on fame of witnesses of undecided rounds
+    // and decides fame where a supermajority of votes agree
+    fn vote(
+        &mut self,
+        x: &EventHash,
+        round: usize,
+        supermajority: usize,
+    ) -> HashMap<EventHash, bool> {
+        let mut votes: HashMap<EventHash, bool> = HashMap::new();
+        let mut decided: Vec<(usize, EventHash, bool)> = Vec::new();
+        // witnesses of the previous round strongly seen by the new witness
+        let voters: Vec<&Witness> = match round.checked_sub(1).and_then(|r| self.witnesses.get(&r))
+        {
+            Some(witnesses) => witnesses
+                .values()
+                .flatten()
+                .filter(|w| self.strongly_sees(x, &w.hash, supermajority))
+                .collect(),
+            None => Vec::new(),
+        };
+        for r in self.next_round..round {
+            let witnesses = match self.witnesses.get(&r) {
+                Some(witnesses) => witnesses,
+                None => continue,
+            };
+            for y in witnesses.values().flatten() {
+                if y.famous.is_some() {
+                    continue;
+                }
+                let distance = round - r;
+                if distance == 1 {
+                    votes.insert(y.hash, self.sees(x, &y.hash));
+                    continue;
+                }
+                let yes = voters
+                    .iter()
+                    .filter(|v| v.votes.get(&y.hash) == Some(&true))
+                    .count();
+                let no = voters
+                    .iter()
+                    .filter(|v| v.votes.get(&y.hash) == Some(&false))
+                    .count();
+                let majority = yes >= no;
+                let count = if majority { yes } else { no };
+                if distance % Self::COIN_ROUND_PERIOD != 0 {
+                    if count >= supermajority {
+                        decided.push((r, y.hash, majority));
+                    } else {
+                        votes.insert(y.hash, majority);
+                    }
+                } else if count >= supermajority {
+                    votes.insert(y.hash, majority);
+                } else {
+                    // coin round: vote with a bit of the voter's hash
+                    let coin = x.to_vec().first().map_or(false, |b| b & 1 == 1);
+                    votes.insert(y.hash, coin);
+                }
+            }
+        }
+        for (r, y, famous) in decided.into_iter() {
+            if let Some(witnesses) = self.witnesses.get_mut(&r) {
+                for w in witnesses.values_mut().flatten().filter(|w| w.hash == y) {
+                    w.famous = Some(famous);
+                }
+            }
+        }
+        votes
+    }
+
+    // Returns the next round whose fame of witnesses is decided, if any
+    pub(crate) fn next_decided_round(&self) -> Option<usize> {
+        let witnesses = self.witnesses.get(&self.next_round)?;
+        // late witnesses may appear until witnesses of two rounds later exist
+        self.witnesses.get(&(self.next_round + 2))?;
+        if witnesses.values().flatten().all(|w| w.famous.is_some()) {
+            Some(self.next_round)
+        } else {
+            None
+        }
+    }
+
+    // Finds events receiving the round next to be decided, with their consensus
+    // timestamps: the median of timestamps of the earliest self-ancestors of
+    // famous witnesses seeing the event. Events received are in hash order.
+    pub(crate) fn receive_round(&mut self) -> Vec<(EventHash, HybridTime)> {
+        let round = self.next_round;
+        // unique famous witnesses of the round
+        let famous: Vec<EventHash> = match self.witnesses.get(&round) {
+            Some(witnesses) => witnesses
+                .values()
+                .filter_map(|ws| {
+                    let mut famous = ws.iter().filter(|w| w.famous == Some(true));
+                    match (famous.next(), famous.next()) {
+                        (Some(w), None) => Some(w.hash),
+                        _ => None,
+                    }
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        // count famous witnesses seeing each event not received yet
+        let mut seen_by: HashMap<EventHash, usize> = HashMap::new();
+        for w in famous.iter() {
+            let mut stack = vec![*w];
+            let mut visited: HashSet<EventHash> = HashSet::new();
+            while let Some(ex) = stack.pop() {
+                if !visited.insert(ex) {
+                    continue;
+                }
+                let e = match self.events.get(&ex) {
+                    Some(e) => e,
+                    None => continue,
+                };
+                if e.round_received.is_some() {
+                    continue;
+                }
+                *seen_by.entry(ex).or_insert(0) += 1;
+                for (c, h) in e.last_ancestors.iter() {
+                    if *c == e.creator {
+                        if *h > 0 {
+                            if let Some(sp) = self.chains.get(&(c.clone(), *h - 1)) {
+                                stack.push(*sp);
+                            }
+                        }
+                    } else if let Some(ancestor) = self.chains.get(&(c.clone(), *h)) {
+                        stack.push(*ancestor);
+                    }
+                }
+            }
+        }
+        let mut received: Vec<(EventHash, HybridTime)> = Vec::new();
+        for (ex, count) in seen_by.into_iter() {
+            if count < famous.len() {
+                continue;
+            }
+            let times: Vec<HybridTime> = famous
+                .iter()
+                .filter_map(|w| self.earliest_seeing(w, &ex))
+                .collect();
+            received.push((ex, median_time(times).unwrap_or_default()));
+        }
+        for (ex, _) in received.iter() {
+            if let Some(e) = self.events.get_mut(ex) {
+                e.round_received = Some(round);
+            }
+        }
+        received.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        self.next_round += 1;
+        self.prune();
+        received
+    }
+
+    // Returns timestamp of the earliest self-ancestor of w which sees ex
+    fn earliest_seeing(&self, w: &EventHash, ex: &EventHash) -> Option<HybridTime> {
+        let w = self.events.get(w)?;
+        let mut result = None;
+        let mut k = w.height;
+        while let Some(z) = self.chains.get(&(w.creator.clone(), k)) {
+            if !self.sees(z, ex) {
+                break;
+            }
+            result = self.events.get(z).map(|e| e.timestamp);
+            if k == 0 {
+                break;
+            }
+            k -= 1;
+        }
+        result
+    }
+
+    // Forgets witnesses and received events of rounds no longer needed to compute
+    // rounds of late events; the latest event of each creator is kept, as the next
+    // event of a creator lagging behind has it as self-parent. Heights pruned are
+    // remembered, so late events referring to pruned events are still accepted.
+    fn prune(&mut self) {
+        let next_round = self.next_round;
+        self.witnesses
+            .retain(|r, _| *r + Self::PRUNE_MARGIN >= next_round);
+        let latest = &self.latest;
+        let mut pruned: Vec<(P, Height)> = Vec::new();
+        self.events.retain(|_, e| {
+            let old = e.round_received.is_some() && e.round + Self::PRUNE_MARGIN < next_round;
+            if old && latest.get(&e.creator).map_or(false, |h| *h > e.height) {
+                pruned.push((e.creator.clone(), e.height));
+                return false;
+            }
+            true
+        });
+        for (creator, height) in pruned.into_iter() {
+            self.chains.remove(&(creator.clone(), height));
+            add_ancestor(&mut self.pruned, &creator, height);
+        }
+    }
+}
+
+// Raises the height recorded for the creator to the height given
+fn add_ancestor<P: PeerId>(heights: &mut HashMap<P, Height>, creator: &P, height: Height) {
+    let entry = heights.entry(creator.clone()).or_insert(height);
+    if *entry < height {
+        *entry = height;
+    }
+}
+
+impl<Data, P, PK, Sig> FinalityEngine<Data, P, PK, Sig> for Hashgraph<P>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    fn add_leaf_event(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        event: &Event<Data, P, PK, Sig>,
+        participants: &Participants<P>,
+    ) -> Result<()> {
+        // leaf events are witnesses of round 0
+        self.insert(store, event, participants.supermajority)?;
+        Ok(())
+    }
+
+    fn add_event(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        event: &mut Event<Data, P, PK, Sig>,
+        participants: &Participants<P>,
+    ) -> Result<()> {
+        // rounds serve as frame numbers
+        event.frame_number = self.insert(store, event, participants.supermajority)?;
+        Ok(())
+    }
+
+    // rounds of events depend on all their ancestors back to round 0,
+    // which a checkpoint does not carry
+    fn restore(&mut self, _next_frame: FrameNumber) -> Result<()> {
+        Err(Error::UnsupportedCheckpoint(
+            "Hashgraph finality cannot resume from a checkpoint".to_string(),
+        )
+        .into())
+    }
+
+    // rounds are computed again from all events stored, which have to reach
+    // back to round 0; rounds already received are received again silently
+    fn rebuild(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        events: Vec<Event<Data, P, PK, Sig>>,
+        participants: &Participants<P>,
+        next_frame: FrameNumber,
+    ) -> Result<()> {
+        for event in events.iter() {
+            self.insert(store, event, participants.supermajority)?;
+        }
+        while self.next_round < next_frame {
+            if self.next_decided_round().is_none() {
+                return Err(Error::UnrecoverableState(format!(
+                    "Hashgraph round {} is finalised but cannot be decided from events stored",
+                    self.next_round
+                ))
+                .into());
+            }
+            self.receive_round();
+        }
+        Ok(())
+    }
+
+    fn finalised_batches(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        _participants: &Participants<P>,
+    ) -> Result<Vec<FinalisedBatch>> {
+        let mut batches = Vec::new();
+        while let Some(round) = self.next_decided_round() {
+            let mut events = Vec::new();
+            for (ex, consensus_timestamp) in self.receive_round().into_iter() {
+                let event = store.get_event(&ex)?;
+                events.push(FrameRecord {
+                    hash: ex,
+                    lamport_time: event.lamport_timestamp,
+                    timestamp: event.timestamp,
+                    received_time: Some(consensus_timestamp),
+                    consensus_timestamp: HybridTime::default(),
+                });
+            }
+            // events carry their own consensus timestamps as received times
+            batches.push(FinalisedBatch {
+                frame: round,
+                events,
+                consensus_time: None,
+            });
+        }
+        Ok(batches)
+    }
+
+    // Events are ordered by consensus timestamps unless FairOrder is configured
+    fn ordering_mode(&self, configured: &OrderingMode) -> OrderingMode {
+        match configured {
+            OrderingMode::FairOrder => OrderingMode::FairOrder,
+            _ => OrderingMode::MedianTimestamp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{feed, mem_store, Engine, Id, RecordedDag, Store, TestEvent};
+
+    #[test]
+    fn test_rounds_received_in_order_once() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(12);
+        let participants = dag.participants();
+        let mut store = mem_store();
+        let mut engine = Hashgraph::<Id>::default();
+        let batches = feed(
+            &mut engine as &mut Engine,
+            &mut store,
+            &participants,
+            &dag.events,
+        );
+        assert!(batches.len() > 1);
+        let mut received = HashSet::new();
+        for (i, (round, events)) in batches.iter().enumerate() {
+            assert_eq!(*round, i);
+            for ex in events.iter() {
+                assert!(received.insert(*ex));
+            }
+        }
+    }
+
+    #[test]
+    fn test_lagging_creator_survives_pruning() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(2);
+        let lagging = dag.latest(3).hash;
+        let early = dag.events[4].hash;
+        // creator 3 falls silent while the others go on
+        for _ in 0..12 {
+            for i in 0..3 {
+                dag.add(i, (i + 1) % 3, Vec::new());
+            }
+        }
+        let participants = dag.participants();
+        let mut store = mem_store();
+        let mut engine = Hashgraph::<Id>::default();
+        let events = dag.events.len();
+        let mut batches = feed(
+            &mut engine as &mut Engine,
+            &mut store,
+            &participants,
+            &dag.events,
+        );
+        assert!(!engine.events.contains_key(&early));
+        match engine.events.get(&lagging) {
+            Some(e) => assert!(e.round_received.is_some()),
+            None => panic!("latest event of the lagging creator is pruned"),
+        }
+        // creator 3 comes back and refers to its latest event
+        let late = dag.add(3, 0, Vec::new());
+        dag.gossip(8);
+        batches.extend(feed(
+            &mut engine as &mut Engine,
+            &mut store,
+            &participants,
+            &dag.events[events..],
+        ));
+        let late_round = engine.events.get(&late.hash).map(|e| e.round);
+        let other_round = engine.events.get(&late.other_parent).map(|e| e.round);
+        assert!(late_round.is_some() && late_round >= other_round);
+        let mut received = HashSet::new();
+        for (i, (round, events)) in batches.iter().enumerate() {
+            assert_eq!(*round, i);
+            for ex in events.iter() {
+                assert!(received.insert(*ex));
+            }
+        }
+        assert!(received.contains(&lagging));
+        assert!(received.contains(&late.hash));
+    }
+
+    #[test]
+    fn test_pruned_parents_accepted_unknown_refused() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(2);
+        let lagging = dag.latest(3).clone();
+        let early = dag.events[4].clone();
+        for _ in 0..12 {
+            for i in 0..3 {
+                dag.add(i, (i + 1) % 3, Vec::new());
+            }
+        }
+        let participants = dag.participants();
+        let mut store = mem_store();
+        let mut engine = Hashgraph::<Id>::default();
+        feed(
+            &mut engine as &mut Engine,
+            &mut store,
+            &participants,
+            &dag.events,
+        );
+        assert!(!engine.events.contains_key(&early.hash));
+        // the lagging creator refers to an event pruned already
+        let mut late = TestEvent::new(
+            lagging.creator.clone(),
+            lagging.height + 1,
+            lagging.hash,
+            early.hash,
+            lagging.lamport_timestamp.max(early.lamport_timestamp) + 1,
+            lagging.timestamp.max(early.timestamp).successor(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
+        late.event_hash().unwrap();
+        let engine_dyn = &mut engine as &mut Engine;
+        engine_dyn
+            .add_event(&mut store, &mut late, &participants)
+            .unwrap();
+        let stored_early = (&store as &Store).get_event(&early.hash).unwrap();
+        assert!(late.frame_number >= stored_early.frame_number);
+        match engine.events.get(&late.hash) {
+            Some(e) => assert_eq!(e.last_ancestors.get(&early.creator), Some(&early.height)),
+            None => panic!("event with a pruned parent is not added"),
+        }
+        // an event whose other-parent has never been added is refused
+        let unknown = dag.add(1, 2, Vec::new());
+        let mut orphan = dag.add(2, 1, Vec::new());
+        let engine_dyn = &mut engine as &mut Engine;
+        let e = engine_dyn
+            .add_event(&mut store, &mut orphan, &participants)
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<Error>(),
+            Some(&Error::UnknownParent(format!("{:?}", unknown.hash)))
+        );
+        assert!(!engine.events.contains_key(&orphan.hash));
+    }
+}
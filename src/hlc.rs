@@ -0,0 +1,100 @@
+// Hybrid logical clock used to timestamp events
+
+use core::fmt::Display;
+use core::fmt::Formatter;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hybrid logical clock timestamp: wall clock time in milliseconds since
+/// UNIX epoch combined with a logical counter ordering events which
+/// share the same wall clock time.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub struct HybridTime {
+    pub wall: u64,
+    pub logical: u32,
+}
+
+impl Display for HybridTime {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}.{}", self.wall, self.logical)
+    }
+}
+
+impl HybridTime {
+    pub fn new(wall: u64, logical: u32) -> HybridTime {
+        HybridTime { wall, logical }
+    }
+    // Returns the smallest timestamp greater than this one
+    pub(crate) fn successor(&self) -> HybridTime {
+        match self.logical.checked_add(1) {
+            Some(logical) => HybridTime::new(self.wall, logical),
+            None => HybridTime::new(self.wall + 1, 0),
+        }
+    }
+}
+
+// Returns the median of timestamps, the upper one for an even number of them
+pub(crate) fn median_time(mut times: Vec<HybridTime>) -> Option<HybridTime> {
+    times.sort();
+    times.get(times.len() / 2).cloned()
+}
+
+// Returns physical time in milliseconds since UNIX epoch
+pub(crate) fn physical_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Hybrid logical clock of the node; never goes backwards and stays
+// close to the physical clock of the node.
+#[derive(Default)]
+pub(crate) struct HybridClock {
+    last: HybridTime,
+}
+
+impl HybridClock {
+    // Returns timestamp for a new event which must follow `after`,
+    // the highest timestamp of the event parents
+    pub(crate) fn next(&mut self, after: HybridTime) -> HybridTime {
+        let physical = HybridTime::new(physical_now(), 0);
+        let time = physical.max(self.last.successor()).max(after.successor());
+        self.last = time;
+        time
+    }
+    // Advances the clock with a timestamp received from other node
+    pub(crate) fn observe(&mut self, time: HybridTime) {
+        if self.last < time {
+            self.last = time;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median_time() {
+        assert_eq!(median_time(Vec::new()), None);
+        let times = vec![
+            HybridTime::new(30, 0),
+            HybridTime::new(10, 0),
+            HybridTime::new(u64::max_value(), 0),
+        ];
+        // a single far-off claim does not move the median
+        assert_eq!(median_time(times), Some(HybridTime::new(30, 0)));
+    }
+
+    #[test]
+    fn test_clock_follows_parents() {
+        let mut clock = HybridClock::default();
+        let far = HybridTime::new(physical_now() + 60_000, 7);
+        let next = clock.next(far);
+        assert_eq!(next, far.successor());
+        assert!(clock.next(HybridTime::default()) > next);
+    }
+}
@@ -0,0 +1,435 @@
+// Lachesis finality engine: frames and flag tables with Atropos election
+
+use crate::election::{AtroposDecision, Election};
+use crate::engine::{FinalisedBatch, FinalityEngine, Participants};
+use crate::errors::{Error, Result};
+use crate::event::Event;
+use crate::flag_table::flag_table_fmt;
+use crate::flag_table::FlagTable;
+use crate::flag_table::{open_merge_flag_table, strict_merge_flag_table};
+use crate::frame::FrameRecord;
+use crate::hlc::{median_time, HybridTime};
+use crate::peer::{FrameNumber, Height};
+use crate::store::DAGstore;
+use libcommon_rs::data::DataType;
+use libcommon_rs::peer::PeerId;
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+// Ancestry of an event: for each creator the height of its highest event
+// among ancestors of the event, if any
+struct Ancestry {
+    creator: usize,
+    height: Height,
+    last_ancestors: Vec<Option<Height>>,
+}
+
+#[derive(Default)]
+pub(crate) struct Lachesis {
+    election: Election,
+    // the lowest frame not finalised yet
+    next_frame: FrameNumber,
+    // ancestry of events not finalised; finalised events cannot be descendants
+    // of roots of frames not finalised, so they are not needed for voting
+    ancestry: HashMap<EventHash, Ancestry>,
+    // events not finalised by creator position and height; of events of
+    // a forking creator at the same height the first one known is kept
+    chains: HashMap<(usize, Height), EventHash>,
+}
+
+impl Lachesis {
+    // Records ancestry of a new event whose parents are recorded already
+    // unless they are finalised
+    fn add_ancestry(
+        &mut self,
+        hash: EventHash,
+        creator: usize,
+        height: Height,
+        parents: [EventHash; 2],
+        creators: usize,
+    ) {
+        let mut last_ancestors: Vec<Option<Height>> = vec![None; creators];
+        for parent in parents.iter() {
+            if let Some(p) = self.ancestry.get(parent) {
+                for (a, h) in last_ancestors.iter_mut().zip(p.last_ancestors.iter()) {
+                    *a = (*a).max(*h);
+                }
+            }
+        }
+        last_ancestors[creator] = Some(height);
+        self.ancestry.insert(
+            hash,
+            Ancestry {
+                creator,
+                height,
+                last_ancestors,
+            },
+        );
+        self.chains.entry((creator, height)).or_insert(hash);
+    }
+
+    // Checks if event x strongly sees event y: the highest ancestors of x of
+    // a supermajority of creators have y as an ancestor
+    fn strongly_sees(&self, x: &EventHash, y: &EventHash, supermajority: usize) -> bool {
+        let (x, y) = match (self.ancestry.get(x), self.ancestry.get(y)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return false,
+        };
+        let count = x
+            .last_ancestors
+            .iter()
+            .enumerate()
+            .filter(|(creator, height)| {
+                let z = match height {
+                    Some(height) => self
+                        .chains
+                        .get(&(*creator, *height))
+                        .and_then(|z| self.ancestry.get(z)),
+                    None => None,
+                };
+                match z {
+                    Some(z) => z.last_ancestors.get(y.creator) >= Some(&Some(y.height)),
+                    None => false,
+                }
+            })
+            .count();
+        count >= supermajority
+    }
+
+    // Returns roots of the previous frame strongly seen by a new root
+    fn strongly_seen(
+        &self,
+        root: &EventHash,
+        frame: FrameNumber,
+        supermajority: usize,
+    ) -> Vec<(usize, EventHash)> {
+        if frame == 0 {
+            return Vec::new();
+        }
+        self.election
+            .roots(frame - 1)
+            .into_iter()
+            .filter(|(_, seen)| self.strongly_sees(root, seen, supermajority))
+            .collect()
+    }
+
+    // Forgets ancestry of a finalised event
+    fn remove_ancestry(&mut self, hash: &EventHash) {
+        if let Some(a) = self.ancestry.remove(hash) {
+            if self.chains.get(&(a.creator, a.height)) == Some(hash) {
+                self.chains.remove(&(a.creator, a.height));
+            }
+        }
+    }
+}
+
+// Returns position of the creator in creator order
+fn creator_position<P: PeerId>(participants: &Participants<P>, creator: &P) -> Result<usize> {
+    match participants.creators.iter().position(|c| c == creator) {
+        Some(position) => Ok(position),
+        None => Err(Error::UnknownPeer(format!("creator {}", creator)).into()),
+    }
+}
+
+impl<Data, P, PK, Sig> FinalityEngine<Data, P, PK, Sig> for Lachesis
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    fn add_leaf_event(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        event: &Event<Data, P, PK, Sig>,
+        participants: &Participants<P>,
+    ) -> Result<()> {
+        // leaf events are roots of frame 0
+        let creator = creator_position(participants, &event.creator)?;
+        let mut ft = FlagTable::new(participants.creators.len());
+        ft.insert(creator, 0);
+        store.set_flag_table(&event.hash, &ft)?;
+        self.add_ancestry(
+            event.hash,
+            creator,
+            event.height,
+            [event.self_parent, event.other_parent],
+            participants.creators.len(),
+        );
+        self.election.add_root(
+            event.hash,
+            creator,
+            0,
+            &[],
+            participants.creators.len(),
+            participants.supermajority,
+        );
+        Ok(())
+    }
+
+    fn add_event(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        event: &mut Event<Data, P, PK, Sig>,
+        participants: &Participants<P>,
+    ) -> Result<()> {
+        let creator = creator_position(participants, &event.creator)?;
+        let self_parent_event = store.get_event(&event.self_parent)?;
+        let other_parent_event = store.get_event(&event.other_parent)?;
+        let self_parent_ft = store.get_flag_table(&event.self_parent)?;
+        let other_parent_ft = store.get_flag_table(&event.other_parent)?;
+        let root: bool;
+        let frame: FrameNumber = match self_parent_event
+            .frame_number
+            .cmp(&other_parent_event.frame_number)
+        {
+            Ordering::Equal => {
+                let root_flag_table = strict_merge_flag_table(
+                    &self_parent_ft,
+                    &other_parent_ft,
+                    self_parent_event.frame_number,
+                );
+                let creators_seen = root_flag_table.creators_seen(self_parent_event.frame_number);
+                if creators_seen.len() >= participants.supermajority {
+                    root = true;
+                    self_parent_event.frame_number + 1
+                } else {
+                    root = false;
+                    self_parent_event.frame_number
+                }
+            }
+            Ordering::Greater => {
+                root = false;
+                self_parent_event.frame_number
+            }
+            Ordering::Less => {
+                root = true;
+                other_parent_event.frame_number
+            }
+        };
+        event.frame_number = frame;
+        let mut visibilis_flag_table =
+            open_merge_flag_table(&self_parent_ft, &other_parent_ft, self.next_frame);
+        if root {
+            visibilis_flag_table.insert(creator, frame);
+        }
+        debug!(
+            "Lachesis: event {} frame {}; ft:{}",
+            event.hash,
+            frame,
+            flag_table_fmt(&visibilis_flag_table)
+        );
+        store.set_flag_table(&event.hash, &visibilis_flag_table)?;
+        self.add_ancestry(
+            event.hash,
+            creator,
+            event.height,
+            [event.self_parent, event.other_parent],
+            participants.creators.len(),
+        );
+        if root {
+            let strongly_seen = self.strongly_seen(&event.hash, frame, participants.supermajority);
+            self.election.add_root(
+                event.hash,
+                creator,
+                frame,
+                &strongly_seen,
+                participants.creators.len(),
+                participants.supermajority,
+            );
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, next_frame: FrameNumber) -> Result<()> {
+        if next_frame > 0 {
+            self.election.prune(next_frame - 1);
+        }
+        self.next_frame = next_frame;
+        Ok(())
+    }
+
+    // Frames, flag tables and roots of events not finalised are computed again;
+    // finalised events take no part in elections of later frames
+    fn rebuild(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        events: Vec<Event<Data, P, PK, Sig>>,
+        participants: &Participants<P>,
+        next_frame: FrameNumber,
+    ) -> Result<()> {
+        FinalityEngine::<Data, P, PK, Sig>::restore(self, next_frame)?;
+        for mut event in events.into_iter() {
+            if store.is_finalised(&event.hash)? {
+                self.remove_ancestry(&event.hash);
+                continue;
+            }
+            if event.self_parent == EventHash::default() {
+                self.add_leaf_event(store, &event, participants)?;
+            } else {
+                self.add_event(store, &mut event, participants)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Events finalised by an Atropos are its ancestors not finalised
+    // by previous Atropos roots. Flag tables of finalised events are compacted
+    // to roots of their own frame, which is all strict merge of their
+    // descendants needs; open merge drops finalised frames anyway.
+    fn finalised_batches(
+        &mut self,
+        store: &mut dyn DAGstore<Data, P, PK, Sig>,
+        participants: &Participants<P>,
+    ) -> Result<Vec<FinalisedBatch>> {
+        let mut batches = Vec::new();
+        // events finalised in earlier batches of this call are not marked in the store yet
+        let mut finalised: HashSet<EventHash> = HashSet::new();
+        loop {
+            let frame = self.next_frame;
+            let atropos = match self.election.atropos(frame, participants.creators.len()) {
+                AtroposDecision::Undecided => break,
+                AtroposDecision::NoAtropos => None,
+                AtroposDecision::Elected(atropos) => Some(atropos),
+            };
+            let mut events = Vec::new();
+            // consensus timestamp of the batch is the median of timestamps
+            // of the roots of the frame decided positively
+            let mut consensus_time = None;
+            if let Some(atropos) = atropos {
+                let mut times = Vec::new();
+                for root in self
+                    .election
+                    .famous_roots(frame, participants.creators.len())
+                    .iter()
+                {
+                    times.push(store.get_event(root)?.timestamp);
+                }
+                consensus_time = median_time(times);
+                let mut stack = vec![atropos];
+                while let Some(ex) = stack.pop() {
+                    // events pruned by retention policy stay marked finalised
+                    if ex == EventHash::default()
+                        || !finalised.insert(ex)
+                        || store.is_finalised(&ex)?
+                    {
+                        continue;
+                    }
+                    let event = store.get_event(&ex)?;
+                    events.push(FrameRecord {
+                        hash: ex,
+                        lamport_time: event.lamport_timestamp,
+                        timestamp: event.timestamp,
+                        received_time: None,
+                        consensus_timestamp: HybridTime::default(),
+                    });
+                    let mut ft = store.get_flag_table(&ex)?;
+                    ft.compact(event.frame_number);
+                    store.set_flag_table(&ex, &ft)?;
+                    self.remove_ancestry(&ex);
+                    stack.push(event.self_parent);
+                    stack.push(event.other_parent);
+                }
+            }
+            batches.push(FinalisedBatch {
+                frame,
+                events,
+                consensus_time,
+            });
+            self.election.prune(frame);
+            self.next_frame = frame + 1;
+        }
+        Ok(batches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{feed, mem_store, Data, Engine, Id, RecordedDag, Sig};
+
+    #[test]
+    fn test_frames_finalised_in_order_once() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(12);
+        let participants = dag.participants();
+        let mut store = mem_store();
+        let mut engine = Lachesis::default();
+        let batches = feed(
+            &mut engine as &mut Engine,
+            &mut store,
+            &participants,
+            &dag.events,
+        );
+        assert!(batches.len() > 1);
+        let mut finalised = HashSet::new();
+        for (i, (frame, events)) in batches.iter().enumerate() {
+            assert_eq!(*frame, i);
+            for ex in events.iter() {
+                assert!(finalised.insert(*ex));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rebuild_continues_elections() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(16);
+        let participants = dag.participants();
+        let half = dag.events.len() / 2;
+        let mut store = mem_store();
+        let mut engine = Lachesis::default();
+        let first = feed(
+            &mut engine as &mut Engine,
+            &mut store,
+            &participants,
+            &dag.events[..half],
+        );
+        let next_frame = first.len();
+        assert!(next_frame > 0);
+        // a new engine over the same store, as after a restart
+        let mut rebuilt = Lachesis::default();
+        let leaves: Vec<_> = dag.events[..4].to_vec();
+        feed(
+            &mut rebuilt as &mut Engine,
+            &mut store,
+            &participants,
+            &leaves,
+        );
+        let mut events = DAGstore::<Data, Id, Id, Sig>::get_events(&store).unwrap();
+        events.sort_by(|a, b| a.lamport_timestamp.cmp(&b.lamport_timestamp));
+        FinalityEngine::<Data, Id, Id, Sig>::rebuild(
+            &mut rebuilt,
+            &mut store,
+            events,
+            &participants,
+            next_frame,
+        )
+        .unwrap();
+        let mut other_store = mem_store();
+        let mut reference = Lachesis::default();
+        let all = feed(
+            &mut reference as &mut Engine,
+            &mut other_store,
+            &participants,
+            &dag.events,
+        );
+        let rest = feed(
+            &mut rebuilt as &mut Engine,
+            &mut store,
+            &participants,
+            &dag.events[half..],
+        );
+        assert!(!rest.is_empty());
+        let sets =
+            |batches: &[(FrameNumber, Vec<EventHash>)]| -> Vec<(FrameNumber, HashSet<EventHash>)> {
+                batches
+                    .iter()
+                    .map(|(f, events)| (*f, events.iter().cloned().collect()))
+                    .collect()
+            };
+        assert_eq!(sets(&rest), sets(&all[next_frame..]));
+    }
+}
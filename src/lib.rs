@@ -14,15 +14,32 @@ extern crate libconsensus;
 extern crate syslog;
 pub use crate::conf::DAGconfig;
 use crate::core::DAGcore;
+pub use crate::core::QuarantineRecord;
+pub use crate::core::TransactionBatch;
+pub use crate::engine::FinalityType;
 use crate::errors::Error;
-// reserved for DAG1
-//use crate::errors::{Result};
+use crate::errors::Result;
 use crate::event::Event;
+pub use crate::hlc::HybridTime;
+pub use crate::ordering::OrderingMode;
 pub use crate::peer::DAGPeer;
 pub use crate::peer::DAGPeerList;
 use crate::peer::FrameNumber;
 use crate::peer::GossipList;
-use crate::sync::{SyncReply, SyncReq};
+pub use crate::peer::Misbehaviour;
+pub use crate::peer::PeerBan;
+pub use crate::peer::SyncStats;
+use crate::peer_selector::new_peer_selector;
+pub use crate::peer_selector::PeerSelectionType;
+use crate::rate_limit::{ReplySlots, RequestLimiter};
+pub use crate::replay::{replay_store, ReplayReport};
+pub use crate::retention::RetentionPolicy;
+pub use crate::store::DAGstoreType;
+pub use crate::store::Durability;
+pub use crate::store_cached::CacheStats;
+use crate::sync::{gossip_lag, limit_events_size, RequestId, SyncReply, SyncReq, SyncStatus};
+use crate::transport::{send_message, FramedReceiver};
+pub use crate::verify::{verify_store, IntegrityReport};
 // reserved for DAG1
 //use crate::transactions::InternalTransaction;
 use futures::executor::block_on;
@@ -38,10 +55,6 @@ use libconsensus::Consensus;
 use libhash_sha3::Hash as EventHash;
 use libsignature::Signature;
 use libsignature::{PublicKey, SecretKey};
-use libtransport::TransportReceiver;
-use libtransport::TransportSender;
-use libtransport_tcp::receiver::TCPreceiver;
-use libtransport_tcp::sender::TCPsender;
 use log::error;
 use std::pin::Pin;
 use std::sync::mpsc::{self, Sender};
@@ -68,15 +81,29 @@ where
     quit_txs: Vec<Sender<()>>,
 }
 
+// Reports peers which have sent oversized messages to the receiver
+fn report_rejected<P, Data, SK, PK, Sig, M>(
+    core: &Arc<RwLock<DAGcore<P, Data, SK, PK, Sig>>>,
+    receiver: &FramedReceiver<M>,
+    me: &str,
+) where
+    Data: DataType + 'static,
+    P: PeerId + 'static,
+    SK: SecretKey,
+    PK: PublicKey + 'static,
+    Sig: Signature<Hash = EventHash, PublicKey = PK, SecretKey = SK> + 'static,
+{
+    for ip in receiver.take_rejected().into_iter() {
+        if let Err(e) = core.write().unwrap().report_oversized_message(&ip) {
+            error!("{}: misbehaviour report error: {:?}", me, e);
+        }
+    }
+}
+
 fn listener<P, Data, SK, PK, Sig>(
     core: Arc<RwLock<DAGcore<P, Data, SK, PK, Sig>>>,
     quit_rx: Receiver<()>,
-    sync_reply_receiver: &mut dyn TransportReceiver<
-        P,
-        SyncReply<Data, P, PK, Sig>,
-        Error,
-        DAGPeerList<P, PK>,
-    >,
+    sync_reply_receiver: &mut FramedReceiver<SyncReply<Data, P, PK, Sig>>,
 ) where
     Data: DataType + 'static,
     P: PeerId + 'static,
@@ -101,9 +128,11 @@ fn listener<P, Data, SK, PK, Sig>(
             }
             Err(TryRecvError::Empty) => {}
         }
+        report_rejected(&core, sync_reply_receiver, &me);
 
         // Receive Sync Reply and process it.
-        // NB: it may not be from the very same peer we have sent Sync Request above.
+        // NB: it may not be from the very same peer we have sent Sync Request above;
+        // request_id of the reply is used to find the Sync Request it answers.
         block_on(async {
             if let Some(sync_reply) = sync_reply_receiver.next().await {
                 debug!(
@@ -111,28 +140,193 @@ fn listener<P, Data, SK, PK, Sig>(
                     me.clone(),
                     sync_reply.from.clone()
                 );
-                // update Lamport timestamp of the node
-                {
+                if { config.read().unwrap().peers.is_banned(&sync_reply.from) } {
+                    warn!(
+                        "l {}: ignoring Sync Reply from banned peer {}",
+                        me.clone(),
+                        sync_reply.from.clone()
+                    );
+                    return;
+                }
+                let known = {
+                    config
+                        .read()
+                        .unwrap()
+                        .peers
+                        .find_peer(&sync_reply.from)
+                        .is_ok()
+                };
+                if !known {
+                    core.write().unwrap().quarantine(QuarantineRecord {
+                        from: sync_reply.from.clone(),
+                        creator: None,
+                        reason: format!("Sync Reply {} from unknown peer", sync_reply.request_id),
+                    });
+                    return;
+                }
+                // match the reply with its Sync Request and update statistics of the peer
+                let rtt = {
                     core.write()
                         .unwrap()
-                        .update_lamport_time(sync_reply.lamport_time);
+                        .sync_tracker
+                        .complete(sync_reply.request_id, &sync_reply.from)
+                };
+                match rtt {
+                    // events and frames nobody has asked for are dropped
+                    None => {
+                        warn!(
+                            "l {}: Sync Reply {} from {} matches no pending Sync Request",
+                            me.clone(),
+                            sync_reply.request_id,
+                            sync_reply.from.clone()
+                        );
+                        if let Err(e) = core
+                            .write()
+                            .unwrap()
+                            .report_misbehaviour(&sync_reply.from, Misbehaviour::UnsolicitedReply)
+                        {
+                            error!("l {}: misbehaviour report error: {:?}", me.clone(), e);
+                        }
+                        return;
+                    }
+                    Some(rtt) => {
+                        let mut cfg = config.write().unwrap();
+                        let slow = Duration::from_millis(cfg.slow_sync);
+                        if let Ok(peer) = cfg.peers.find_peer_mut(&sync_reply.from) {
+                            match sync_reply.status {
+                                SyncStatus::Ok | SyncStatus::Lagging | SyncStatus::Pruned => {
+                                    peer.record_sync_success(rtt, slow)
+                                }
+                                SyncStatus::Busy => peer.record_sync_busy(),
+                            }
+                        }
+                    }
+                }
+                match sync_reply.status {
+                    SyncStatus::Ok => {}
+                    SyncStatus::Busy => {
+                        debug!("l {}: peer {} is busy", me.clone(), sync_reply.from.clone());
+                        return;
+                    }
+                    SyncStatus::Lagging => {
+                        debug!(
+                            "l {}: peer {} is lagging",
+                            me.clone(),
+                            sync_reply.from.clone()
+                        );
+                        return;
+                    }
+                    // catch-up goes on with the next peer selected, or gives way
+                    // to normal gossip once no peer has the frames requested
+                    SyncStatus::Pruned => {
+                        info!(
+                            "l {}: peer {} has pruned frames requested for catch-up",
+                            me.clone(),
+                            sync_reply.from.clone()
+                        );
+                        core.write().unwrap().record_pruned_reply(&sync_reply.from);
+                        return;
+                    }
+                }
+                // process finalised frames fetched in catch-up mode;
+                // catch-up finishes when the peer has no more frames to send
+                if sync_reply.catch_up.is_some() {
+                    if sync_reply.frames.is_empty() {
+                        core.write().unwrap().finish_catch_up();
+                    }
+                    for frame in sync_reply.frames.into_iter() {
+                        let number = frame.frame;
+                        let inserted = { core.write().unwrap().insert_catch_up_frame(frame) };
+                        match inserted {
+                            Ok(count) => debug!(
+                                "l {}: catch-up frame {}: {} events inserted",
+                                me.clone(),
+                                number,
+                                count
+                            ),
+                            Err(e) => {
+                                let mut local_core = core.write().unwrap();
+                                if let Some(Error::InvalidCatchUpFrame(_, _)) =
+                                    e.downcast_ref::<Error>()
+                                {
+                                    if let Err(e) = local_core.report_misbehaviour(
+                                        &sync_reply.from,
+                                        Misbehaviour::InvalidFrame,
+                                    ) {
+                                        error!(
+                                            "l {}: misbehaviour report error: {:?}",
+                                            me.clone(),
+                                            e
+                                        );
+                                    }
+                                }
+                                local_core.quarantine(QuarantineRecord {
+                                    from: sync_reply.from.clone(),
+                                    creator: None,
+                                    reason: format!("catch-up frame {} rejected: {:?}", number, e),
+                                });
+                                break;
+                            }
+                        }
+                    }
                 }
                 // process unknown events
                 for ev in sync_reply.events.into_iter() {
                     {
                         let event: Event<Data, P, PK, Sig> = ev.into();
-                        // check if event is valid
-                        if !{ core.read().unwrap().check_event(&event).unwrap() } {
-                            error!("l {}: Event {} is not valid", me.clone(), event);
-                            continue;
-                        }
                         let lamport_time = event.get_lamport_time();
                         let height = event.get_height();
                         let creator = event.get_creator();
+                        let hash = event.get_hash();
+                        // check if event is valid
+                        let valid = { core.read().unwrap().check_event(&event) };
+                        match valid {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                error!("l {}: Event {} is not valid", me.clone(), event);
+                                if let Err(e) = core.write().unwrap().report_misbehaviour(
+                                    &sync_reply.from,
+                                    Misbehaviour::InvalidSignature,
+                                ) {
+                                    error!("l {}: misbehaviour report error: {:?}", me.clone(), e);
+                                }
+                                continue;
+                            }
+                            Err(e) => {
+                                let mut local_core = core.write().unwrap();
+                                if let Some(Error::ImplausibleLamportTime(_, _))
+                                | Some(Error::ImplausibleTimestamp(_)) =
+                                    e.downcast_ref::<Error>()
+                                {
+                                    if let Err(e) = local_core.report_misbehaviour(
+                                        &sync_reply.from,
+                                        Misbehaviour::MalformedEvent,
+                                    ) {
+                                        error!(
+                                            "l {}: misbehaviour report error: {:?}",
+                                            me.clone(),
+                                            e
+                                        );
+                                    }
+                                }
+                                local_core.quarantine(QuarantineRecord {
+                                    from: sync_reply.from.clone(),
+                                    creator: Some(creator),
+                                    reason: format!("event {} rejected: {:?}", hash, e),
+                                });
+                                continue;
+                            }
+                        }
                         debug!("l {}: insert event: {}", me.clone(), event.clone());
                         // insert event into node DB
-                        {
-                            core.write().unwrap().insert_event(event).unwrap();
+                        let inserted = { core.write().unwrap().insert_event(event) };
+                        if let Err(e) = inserted {
+                            core.write().unwrap().quarantine(QuarantineRecord {
+                                from: sync_reply.from.clone(),
+                                creator: Some(creator),
+                                reason: format!("event {} insertion failed: {:?}", hash, e),
+                            });
+                            continue;
                         }
                         debug!(
                             "l {}: updating lamport time {} and height {}",
@@ -141,15 +335,39 @@ fn listener<P, Data, SK, PK, Sig>(
                             height.clone()
                         );
                         // update lamport time and height of the event creator's peer
-                        config
-                            .write()
-                            .unwrap()
-                            .peers
-                            .find_peer_mut(&creator)
-                            .unwrap()
-                            .update_lamport_time_and_height(lamport_time, height);
+                        match config.write().unwrap().peers.find_peer_mut(&creator) {
+                            Ok(peer) => peer.update_lamport_time_and_height(lamport_time, height),
+                            Err(e) => {
+                                error!("l {}: peer {} find error: {:?}", me.clone(), creator, e)
+                            }
+                        }
                     }
                 }
+                // update Lamport timestamp of the node; it is checked against
+                // Lamport times of the events known, including those just inserted
+                let time_update = {
+                    core.write()
+                        .unwrap()
+                        .update_lamport_time(sync_reply.lamport_time)
+                };
+                if let Err(e) = time_update {
+                    core.write().unwrap().quarantine(QuarantineRecord {
+                        from: sync_reply.from.clone(),
+                        creator: None,
+                        reason: format!(
+                            "Sync Reply {} Lamport time rejected: {:?}",
+                            sync_reply.request_id, e
+                        ),
+                    });
+                }
+                // switch to catch-up mode if the node is still far behind the peer
+                if sync_reply.catch_up.is_none() {
+                    let lag = {
+                        let local = config.read().unwrap().peers.get_gossip_list();
+                        gossip_lag(&local, &sync_reply.gossip_list)
+                    };
+                    core.write().unwrap().update_catch_up(lag);
+                }
                 debug!(
                     "l {} Sync Reply from {} done",
                     me.clone(),
@@ -165,6 +383,31 @@ fn listener<P, Data, SK, PK, Sig>(
     }
 }
 
+// Forms Sync Request with the current gossip list and Lamport time of the node
+fn new_sync_request<P, D, SK, PK, Sig>(
+    core: &Arc<RwLock<DAGcore<P, D, SK, PK, Sig>>>,
+    request_id: RequestId,
+    to: P,
+) -> SyncReq<P>
+where
+    D: DataType + 'static,
+    P: PeerId + 'static,
+    SK: SecretKey,
+    PK: PublicKey + 'static,
+    Sig: Signature<Hash = EventHash, PublicKey = PK, SecretKey = SK> + 'static,
+{
+    let core = core.read().unwrap();
+    let cfg = core.conf.read().unwrap();
+    SyncReq {
+        request_id,
+        from: cfg.get_creator(),
+        to,
+        gossip_list: cfg.peers.get_gossip_list(),
+        lamport_time: core.get_lamport_time(),
+        catch_up: core.catch_up_frame(),
+    }
+}
+
 // Procedure A of DAG consensus
 fn procedure_a<P, D, SK, PK, Sig>(core: Arc<RwLock<DAGcore<P, D, SK, PK, Sig>>>)
 where
@@ -175,28 +418,19 @@ where
     Sig: Signature<Hash = EventHash, PublicKey = PK, SecretKey = SK> + 'static,
 {
     let config = { core.read().unwrap().conf.clone() };
-    let store = { core.read().unwrap().store.clone() };
     let creator = { config.read().unwrap().get_creator() };
+    let mut selector = {
+        let cfg = config.read().unwrap();
+        new_peer_selector::<P, PK>(&cfg.peer_selection, Duration::from_millis(cfg.sync_backoff))
+    };
     let mut ticker = {
         let cfg = config.read().unwrap();
         thread::sleep(Duration::from_millis(cfg.get_proc_a_delay()));
         async_timer::Interval::platform_new(Duration::from_millis(cfg.heartbeat))
     };
-    let (transport_type, reply_bind_address) = {
-        let cfg = config.read().unwrap();
-        (cfg.transport_type.clone(), cfg.reply_addr.clone())
-    };
+    let reply_bind_address = { config.read().unwrap().reply_addr.clone() };
     let me = { core.read().unwrap().me_a() };
     debug!("procedure_a, reply_bind_addr: {}", reply_bind_address);
-    // setup TransportSender for Sync Request.
-    let mut sync_req_sender = {
-        match transport_type {
-            libtransport::TransportType::TCP => {
-                TCPsender::<P, SyncReq<P>, errors::Error, peer::DAGPeerList<P, PK>>::new().unwrap()
-            }
-            libtransport::TransportType::Unknown => panic!("unknown transport"),
-        }
-    };
     // DAG procedure A loop
     loop {
         debug!("{}: proc_a loop", me.clone());
@@ -209,28 +443,87 @@ where
             // FIXME: need to be implemented
             break;
         }
-        debug!("{} locking cfg", me.clone());
-        let mut cfg = config.write().unwrap();
+        // lift expired bans
+        if let Err(e) = core.write().unwrap().expire_bans() {
+            error!("{}: ban expiry error: {:?}", me.clone(), e);
+        }
+        // expire timed out Sync Requests and resend those due for retry
+        let (timeout, backoff, retries) = {
+            let cfg = config.read().unwrap();
+            (
+                Duration::from_millis(cfg.sync_timeout),
+                Duration::from_millis(cfg.sync_backoff),
+                cfg.sync_retries,
+            )
+        };
+        let timed_out = {
+            core.write()
+                .unwrap()
+                .sync_tracker
+                .expire(timeout, backoff, retries)
+        };
+        for peer_id in timed_out.iter() {
+            warn!("{}: Sync Request to {} timed out", me.clone(), peer_id);
+            if let Ok(peer) = config.write().unwrap().peers.find_peer_mut(peer_id) {
+                peer.record_sync_failure();
+            }
+            if let Err(e) = core
+                .write()
+                .unwrap()
+                .report_misbehaviour(peer_id, Misbehaviour::Timeout)
+            {
+                error!("{}: misbehaviour report error: {:?}", me.clone(), e);
+            }
+        }
+        let due_retries = { core.write().unwrap().sync_tracker.due_retries() };
+        for (request_id, peer_id) in due_retries.into_iter() {
+            let address = match { config.read().unwrap().peers.find_peer(&peer_id) } {
+                Ok(peer) => peer.request_addr,
+                Err(e) => {
+                    error!("peer {} find error: {:?}", peer_id, e);
+                    continue;
+                }
+            };
+            let request = new_sync_request(&core, request_id, peer_id);
+            debug!(
+                "{}: resending SyncReq to {} ==> {}",
+                me.clone(),
+                address.clone(),
+                request.clone()
+            );
+            if let Err(e) = send_message(&address, &request) {
+                error!("error resending sync request to {}: {:?}", address, e);
+            }
+        }
         // choose the next peer and send Sync Request to it.
         debug!("{} getting next peer", me.clone());
-        let peer = cfg.peers.next_peer();
-        debug!("{} got next peer: {}", me.clone(), peer.clone());
-        let gossip_list: GossipList<P> = cfg.peers.get_gossip_list();
-        drop(cfg);
-        debug!("{} got gossip list", me.clone());
-        let request = SyncReq {
-            from: creator.clone(),
-            to: peer.id.clone(),
-            gossip_list,
-            lamport_time: { core.read().unwrap().get_lamport_time() },
+        let next_peer = {
+            let cfg = config.read().unwrap();
+            selector.next_peer(&cfg.peers)
         };
+        let peer = match next_peer {
+            Some(peer) => peer,
+            None => {
+                debug!("{}: no peer eligible for sync", me.clone());
+                block_on(async {
+                    ticker.as_mut().await;
+                });
+                continue;
+            }
+        };
+        debug!("{} got next peer: {}", me.clone(), peer.clone());
+        if let Ok(p) = config.write().unwrap().peers.find_peer_mut(&peer.id) {
+            p.record_sync_attempt();
+        }
+        let request_id = { core.write().unwrap().sync_tracker.start(peer.id.clone()) };
+        let request = new_sync_request(&core, request_id, peer.id.clone());
         debug!(
             "{}: sending SyncReq to {} ==> {}",
             me.clone(),
             peer.request_addr.clone(),
             request.clone()
         );
-        match sync_req_sender.send(peer.request_addr.clone(), request) {
+        match send_message(&peer.request_addr, &request) {
             Ok(()) => {}
             Err(e) => error!(
                 "error sending sync request to {}: {:?}",
@@ -241,70 +534,18 @@ where
 
         // Sync Reply receiver was here
 
+        // no new events in catch-up mode; they would refer to outdated other-parents
+        if { core.read().unwrap().catch_up_frame().is_some() } {
+            debug!("{}: catching up; no new event", me.clone());
+            block_on(async {
+                ticker.as_mut().await;
+            });
+            continue;
+        }
         // create new event if needed referring remote peer as other-parent
         debug!("{}: create new event", me);
-        let height = {
-            config
-                .write()
-                .unwrap()
-                .peers
-                .find_peer_mut(&creator)
-                .unwrap()
-                .get_next_height()
-        };
-        let other_height = {
-            config
-                .read()
-                .unwrap()
-                .peers
-                .find_peer(&peer.id)
-                .unwrap()
-                .get_height()
-        };
-        debug!(
-            "{}: heights; self[{}]: {}; other[{}]: {}",
-            me.clone(),
-            creator.clone(),
-            height,
-            peer.id.clone(),
-            other_height,
-        );
-        let (other_parent_event, self_parent_event) = {
-            let store_local = store.read().unwrap();
-            (
-                store_local
-                    .get_event_of_creator(peer.id.clone(), other_height)
-                    .unwrap(),
-                store_local
-                    .get_event_of_creator(creator.clone(), height - 1)
-                    .unwrap(),
-            )
-        };
-        debug!("{}: parent events read", me.clone());
-        let self_parent = self_parent_event.hash;
-        let other_parent = other_parent_event.hash;
-        let (lamport_timestamp, transactions, internal_transactions) = {
-            let mut local_core = core.write().unwrap();
-            (
-                local_core.get_next_lamport_time(),
-                local_core.next_transactions(),
-                local_core.next_internal_transactions(),
-            )
-        };
-        let mut event: Event<D, P, PK, Sig> = Event::new(
-            creator.clone(),
-            height,
-            self_parent,
-            other_parent,
-            lamport_timestamp,
-            transactions,
-            internal_transactions,
-        );
-        debug!("{}: event formed: {}", me.clone(), event.clone());
-        let ex = event.event_hash().unwrap();
-        let rc = { core.write().unwrap().insert_event(event).unwrap() };
-        if !rc {
-            error!("Error inserting new event {:?}", ex);
+        if let Err(e) = create_event(&core, &creator, &peer.id, &me) {
+            error!("{}: error creating new event: {:?}", me.clone(), e);
         }
 
         // wait until heartbeat interval expires
@@ -316,10 +557,183 @@ where
     }
 }
 
+// Creates new event of the creator with the last known event of the peer as
+// other-parent and inserts it into the DAG
+fn create_event<P, D, SK, PK, Sig>(
+    core: &Arc<RwLock<DAGcore<P, D, SK, PK, Sig>>>,
+    creator: &P,
+    peer_id: &P,
+    me: &str,
+) -> Result<()>
+where
+    D: DataType + 'static,
+    P: PeerId + 'static,
+    SK: SecretKey,
+    PK: PublicKey + 'static,
+    Sig: Signature<Hash = EventHash, PublicKey = PK, SecretKey = SK> + 'static,
+{
+    let config = { core.read().unwrap().conf.clone() };
+    let store = { core.read().unwrap().store.clone() };
+    let (height, other_height) = {
+        let mut cfg = config.write().unwrap();
+        let other_height = cfg.peers.find_peer(peer_id)?.get_height();
+        (
+            cfg.peers.find_peer_mut(creator)?.get_next_height(),
+            other_height,
+        )
+    };
+    debug!(
+        "{}: heights; self[{}]: {}; other[{}]: {}",
+        me,
+        creator.clone(),
+        height,
+        peer_id.clone(),
+        other_height,
+    );
+    let (other_parent_event, self_parent_event) = {
+        let store_local = store.read().unwrap();
+        (
+            store_local.get_event_of_creator(peer_id.clone(), other_height)?,
+            store_local.get_event_of_creator(creator.clone(), height - 1)?,
+        )
+    };
+    debug!("{}: parent events read", me);
+    let self_parent = self_parent_event.hash;
+    let other_parent = other_parent_event.hash;
+    let parent_time = self_parent_event
+        .get_lamport_time()
+        .max(other_parent_event.get_lamport_time());
+    let parent_timestamp = self_parent_event
+        .get_timestamp()
+        .max(other_parent_event.get_timestamp());
+    let (lamport_timestamp, timestamp, transactions, tx_arrivals, internal_transactions) = {
+        let mut local_core = core.write().unwrap();
+        (
+            local_core.get_next_lamport_time(parent_time),
+            local_core.get_next_timestamp(parent_timestamp),
+            local_core.next_transactions(),
+            local_core.next_tx_arrivals(),
+            local_core.next_internal_transactions(),
+        )
+    };
+    let mut event: Event<D, P, PK, Sig> = Event::new(
+        creator.clone(),
+        height,
+        self_parent,
+        other_parent,
+        lamport_timestamp,
+        timestamp,
+        transactions,
+        tx_arrivals,
+        internal_transactions,
+    );
+    debug!("{}: event formed: {}", me, event.clone());
+    let ex = event.event_hash()?;
+    let rc = { core.write().unwrap().insert_event(event)? };
+    if !rc {
+        error!("Error inserting new event {:?}", ex);
+    }
+    Ok(())
+}
+
+// Forms Sync Reply to the Sync Request and sends it; events for gossip or
+// finalised frames are read from the store only when the status is Ok
+fn send_sync_reply<P, D, SK, PK, Sig>(
+    core: &Arc<RwLock<DAGcore<P, D, SK, PK, Sig>>>,
+    sync_req: SyncReq<P>,
+    status: SyncStatus,
+    me: &str,
+) where
+    D: DataType + 'static,
+    P: PeerId + 'static,
+    SK: SecretKey,
+    PK: PublicKey + 'static,
+    Sig: Signature<Hash = EventHash, PublicKey = PK, SecretKey = SK> + 'static,
+{
+    let config = { core.read().unwrap().conf.clone() };
+    let store = { core.read().unwrap().store.clone() };
+    let (max_message_size, catch_up_frames) = {
+        let cfg = config.read().unwrap();
+        (cfg.max_message_size, cfg.catch_up_frames)
+    };
+    // no events for gossip are sent in reply to catch-up requests
+    let events = match status {
+        SyncStatus::Ok if sync_req.catch_up.is_none() => {
+            let events_for_gossip = {
+                store
+                    .read()
+                    .unwrap()
+                    .get_events_for_gossip(&sync_req.gossip_list)
+            };
+            match events_for_gossip {
+                Err(e) => {
+                    error!("Procedure B: get_events_for_gossip() error: {:?}", e);
+                    return;
+                }
+                Ok(events) => events,
+            }
+        }
+        _ => Vec::new(),
+    };
+    debug!("{}: got events for gossip", me);
+    let gossip_list: GossipList<P> = { config.read().unwrap().peers.get_gossip_list() };
+    debug!("{}: got gossip list", me);
+    let mut reply = SyncReply::<D, P, PK, Sig> {
+        request_id: sync_req.request_id,
+        status,
+        from: sync_req.to.clone(),
+        to: sync_req.from.clone(),
+        gossip_list,
+        lamport_time: { core.read().unwrap().get_lamport_time() },
+        events: Vec::new(),
+        catch_up: sync_req.catch_up,
+        frames: Vec::new(),
+    };
+    // keep the reply within maximal message size
+    let overhead = bincode::serialized_size(&reply).unwrap_or(0);
+    let budget = max_message_size.saturating_sub(overhead);
+    reply.events = limit_events_size(events, budget);
+    if let (SyncStatus::Ok, Some(from)) = (status, sync_req.catch_up) {
+        let frames = {
+            core.read()
+                .unwrap()
+                .get_catch_up_frames(from, catch_up_frames, budget)
+        };
+        match frames {
+            Ok(frames) => reply.frames = frames,
+            Err(e) => match e.downcast_ref::<Error>() {
+                Some(Error::PrunedFrame(frame)) => {
+                    debug!("{}: catch-up frame {} is pruned", me, frame);
+                    reply.status = SyncStatus::Pruned;
+                }
+                _ => error!("Procedure B: get_catch_up_frames() error: {:?}", e),
+            },
+        }
+    }
+    debug!("{}: SyncReply formed: {}", me, reply.clone());
+    match {
+        config
+            .write()
+            .unwrap()
+            .peers
+            .find_peer_with_lamport_time_update(&reply.to, sync_req.lamport_time)
+    } {
+        Ok(peer) => {
+            let address = peer.reply_addr.clone();
+            debug!("{}: sending SyncReply to {}", me, address.clone());
+            if let Err(e) = send_message(&address, &reply) {
+                error!("error sending sync reply: {:?}", e);
+            }
+            debug!("{}: SyncReply sent", me);
+        }
+        Err(e) => error!("peer {} find error: {:?}", reply.to, e),
+    }
+}
+
 // Procedure B of DAG consensus
 fn procedure_b<P, D, SK, PK, Sig>(
     core: Arc<RwLock<DAGcore<P, D, SK, PK, Sig>>>,
-    sync_req_receiver: &mut dyn TransportReceiver<P, SyncReq<P>, Error, DAGPeerList<P, PK>>,
+    sync_req_receiver: &mut FramedReceiver<SyncReq<P>>,
 ) where
     D: DataType + 'static,
     P: PeerId + 'static,
@@ -328,87 +742,110 @@ fn procedure_b<P, D, SK, PK, Sig>(
     Sig: Signature<Hash = EventHash, PublicKey = PK, SecretKey = SK> + 'static,
 {
     let config = { core.read().unwrap().conf.clone() };
-    let (transport_type, request_bind_address) = {
-        let cfg = config.read().unwrap();
-        (cfg.transport_type.clone(), cfg.request_addr.clone())
-    };
+    let request_bind_address = { config.read().unwrap().request_addr.clone() };
     let me = { core.read().unwrap().me_b() };
     debug!("procedure_b, request_bind_addr: {}", request_bind_address);
-    let mut sync_reply_sender = {
-        match transport_type {
-            libtransport::TransportType::TCP => {
-                TCPsender::<P, SyncReply<D, P, PK, Sig>, Error, DAGPeerList<P, PK>>::new().unwrap()
-            }
-            libtransport::TransportType::Unknown => panic!("unknown transport"),
-        }
+    let (mut limiter, reply_slots) = {
+        let cfg = config.read().unwrap();
+        (
+            RequestLimiter::<P>::new(cfg.peer_request_rate, cfg.peer_request_burst),
+            ReplySlots::new(cfg.max_concurrent_replies),
+        )
     };
-    let store = { core.read().unwrap().store.clone() };
     block_on(async {
         debug!("{}: waiting for Sync request", me.clone());
         while let Some(sync_req) = sync_req_receiver.next().await {
+            report_rejected(&core, sync_req_receiver, &me);
+            if { config.read().unwrap().peers.is_banned(&sync_req.from) } {
+                warn!(
+                    "{}: ignoring Sync request from banned peer {}",
+                    me.clone(),
+                    sync_req.from.clone()
+                );
+                continue;
+            }
+            let known = {
+                let cfg = config.read().unwrap();
+                cfg.peers.find_peer(&sync_req.from).is_ok() && sync_req.to == cfg.get_creator()
+            };
+            if !known {
+                core.write().unwrap().quarantine(QuarantineRecord {
+                    from: sync_req.from.clone(),
+                    creator: None,
+                    reason: format!(
+                        "Sync request {} from unknown peer or addressed to {}",
+                        sync_req.request_id,
+                        sync_req.to.clone()
+                    ),
+                });
+                continue;
+            }
             debug!(
                 "{} Sync request from {} <== {}",
                 me.clone(),
-                {
-                    config
-                        .read()
-                        .unwrap()
-                        .peers
-                        .find_peer(&sync_req.from)
-                        .unwrap()
-                        .get_base_addr()
-                },
+                sync_req.from.clone(),
                 sync_req.clone()
             );
-            {
+            let time_update = {
                 core.write()
                     .unwrap()
-                    .update_lamport_time(sync_req.lamport_time);
+                    .update_lamport_time(sync_req.lamport_time)
+            };
+            // the request is ahead of all events known: this node is behind,
+            // so it replies lagging and catches up if the gap is large
+            if let Err(e) = time_update {
+                warn!(
+                    "{}: Sync request {} Lamport time rejected: {:?}; replying lagging to {}",
+                    me.clone(),
+                    sync_req.request_id,
+                    e,
+                    sync_req.from.clone()
+                );
+                let lag = {
+                    let local = config.read().unwrap().peers.get_gossip_list();
+                    gossip_lag(&local, &sync_req.gossip_list)
+                };
+                core.write().unwrap().update_catch_up(lag);
+                send_sync_reply(&core, sync_req, SyncStatus::Lagging, &me);
+                continue;
             }
             debug!("{}: lamport time update: {}", me.clone(), {
                 core.read().unwrap().get_lamport_time()
             });
-            let events_for_gossip = {
-                store
-                    .read()
-                    .unwrap()
-                    .get_events_for_gossip(&sync_req.gossip_list)
-            };
-            match events_for_gossip {
-                Err(e) => error!("Procedure B: get_events_for_gossip() error: {:?}", e),
-                Ok(events) => {
-                    debug!("{}: got events for gossip", me.clone());
-                    let gossip_list: GossipList<P> =
-                        { config.read().unwrap().peers.get_gossip_list() };
-                    debug!("{}: got gossip list", me.clone());
-                    let reply = SyncReply::<D, P, PK, Sig> {
-                        from: sync_req.to,
-                        to: sync_req.from,
-                        gossip_list,
-                        lamport_time: { core.read().unwrap().get_lamport_time() },
-                        events,
-                    };
-                    debug!("{}: SyncReply formed: {}", me.clone(), reply.clone());
-                    match {
-                        config
-                            .write()
-                            .unwrap()
-                            .peers
-                            .find_peer_with_lamport_time_update(&reply.to, sync_req.lamport_time)
-                    } {
-                        Ok(peer) => {
-                            let address = peer.reply_addr.clone();
-                            debug!("{}: sending SyncReply to {}", me.clone(), address.clone());
-                            let res = sync_reply_sender.send(address, reply);
-                            match res {
-                                Ok(()) => {}
-                                Err(e) => error!("error sending sync reply: {:?}", e),
-                            }
-                            debug!("{}: SyncReply sent", me.clone());
-                        }
-                        Err(e) => error!("peer {} find error: {:?}", reply.to, e),
-                    }
+            // reply busy without scanning the store when limits are hit
+            if !limiter.admit(&sync_req.from) {
+                warn!(
+                    "{}: rate limit hit; replying busy to {}",
+                    me.clone(),
+                    sync_req.from.clone()
+                );
+                send_sync_reply(&core, sync_req, SyncStatus::Busy, &me);
+                continue;
+            }
+            let slot = match reply_slots.try_acquire() {
+                Some(slot) => slot,
+                None => {
+                    warn!(
+                        "{}: too many replies in progress; replying busy to {}",
+                        me.clone(),
+                        sync_req.from.clone()
+                    );
+                    send_sync_reply(&core, sync_req, SyncStatus::Busy, &me);
+                    continue;
                 }
+            };
+            // the slot is held until the reply is sent
+            let reply_core = core.clone();
+            let reply_me = me.clone();
+            let spawned = thread::Builder::new()
+                .name("sync_reply".to_string())
+                .stack_size(4 * 1024 * 1024)
+                .spawn(move || {
+                    send_sync_reply(&reply_core, sync_req, SyncStatus::Ok, &reply_me);
+                    drop(slot);
+                });
+            if let Err(e) = spawned {
+                error!("{}: sync reply thread error: {:?}", me.clone(), e);
             }
         }
         debug!("{}: exit proc_b loop!", me.clone());
@@ -428,17 +865,21 @@ where
     fn new(cfg: DAGconfig<P, D, SK, PK>) -> BaseResult<DAG<P, D, SK, PK, Sig>> {
         let (tx, rx) = mpsc::channel();
 
-        let (transport_type, reply_bind_address, request_bind_address) = (
+        let (transport_type, reply_bind_address, request_bind_address, max_message_size) = (
             cfg.transport_type.clone(),
             cfg.reply_addr.clone(),
             cfg.request_addr.clone(),
+            cfg.max_message_size,
         );
+        // messages above maximal message size are dropped before they are read
         let mut sync_reply_receiver = {
             match transport_type {
                 libtransport::TransportType::TCP => {
-                    let x: TCPreceiver<P, SyncReply<D, P, PK, Sig>, Error, DAGPeerList<P, PK>> =
-                        TCPreceiver::new(reply_bind_address).unwrap();
-                    x
+                    FramedReceiver::<SyncReply<D, P, PK, Sig>>::new(
+                        reply_bind_address,
+                        max_message_size,
+                    )
+                    .unwrap()
                 }
                 libtransport::TransportType::Unknown => panic!("unknown transport"),
             }
@@ -448,17 +889,15 @@ where
         let mut sync_req_receiver = {
             match transport_type {
                 libtransport::TransportType::TCP => {
-                    TCPreceiver::<P, SyncReq<P>, Error, DAGPeerList<P, PK>>::new(
-                        request_bind_address,
-                    )
-                    .unwrap()
+                    FramedReceiver::<SyncReq<P>>::new(request_bind_address, max_message_size)
+                        .unwrap()
                 }
                 libtransport::TransportType::Unknown => panic!("unknown transport"),
             }
         };
         let syr_tx = sync_req_receiver.get_quit_tx();
 
-        let core = Arc::new(RwLock::new(DAGcore::new(cfg)));
+        let core = Arc::new(RwLock::new(DAGcore::new(cfg)?));
 
         let handle = {
             let listener_core = core.clone();
@@ -487,14 +926,8 @@ where
             quit_txs: Vec::with_capacity(3),
         };
         dag.set_quit_tx(tx);
-        match srr_tx {
-            None => {}
-            Some(x) => dag.set_quit_tx(x),
-        };
-        match syr_tx {
-            None => {}
-            Some(x) => dag.set_quit_tx(x),
-        };
+        dag.set_quit_tx(srr_tx);
+        dag.set_quit_tx(syr_tx);
         Ok(dag)
     }
 
@@ -576,6 +1009,55 @@ where
     pub(crate) fn set_quit_tx(&mut self, tx: Sender<()>) {
         self.quit_txs.push(tx);
     }
+    /// Returns consensus timestamp of the transaction last delivered by the stream;
+    /// transactions share the consensus timestamp of the event carrying them.
+    pub fn last_delivered_timestamp(&self) -> Option<HybridTime> {
+        self.core.read().unwrap().last_delivered_timestamp
+    }
+    /// Returns transactions of the next finalised frame not delivered yet along
+    /// with the consensus timestamp of the frame, or None if there is no such
+    /// frame yet. Shares the position of delivery with the stream: a frame
+    /// partially delivered by the stream is continued where the stream stopped.
+    pub fn next_batch(&mut self) -> Result<Option<TransactionBatch<D, P>>> {
+        self.core.write().unwrap().next_batch()
+    }
+    /// Returns messages rejected because of unknown creators or peers, oldest first
+    pub fn get_quarantine(&self) -> Vec<QuarantineRecord<P>> {
+        self.core.read().unwrap().get_quarantine()
+    }
+    /// Returns list of currently banned peers
+    pub fn get_banned_peers(&self) -> Vec<PeerBan<P>> {
+        self.core.read().unwrap().get_bans()
+    }
+    /// Bans the peer for specified duration or permanently if duration is None;
+    /// the ban is persisted in the store.
+    pub fn ban_peer(&mut self, peer: P, duration: Option<Duration>, reason: String) -> Result<()> {
+        self.core.write().unwrap().ban_peer(peer, duration, reason)
+    }
+    /// Lifts the ban of the peer and resets its misbehaviour score
+    pub fn unban_peer(&mut self, peer: &P) -> Result<()> {
+        self.core.write().unwrap().unban_peer(peer)
+    }
+    /// Writes a checkpoint of the node into the file; a new node given the file
+    /// in DAGconfig starts from the last frame finalised by this node.
+    pub fn export_checkpoint(&self, path: &std::path::Path) -> Result<()> {
+        self.core.read().unwrap().export_checkpoint(path)
+    }
+    /// Checks if the node fetches finalised frames from peers in bulk
+    /// to catch up with them instead of normal gossip.
+    pub fn is_catching_up(&self) -> bool {
+        self.core.read().unwrap().catch_up_frame().is_some()
+    }
+    /// Returns hit and miss counters of the store cache; None if the store has no cache
+    pub fn store_cache_stats(&self) -> Option<CacheStats> {
+        self.core
+            .read()
+            .unwrap()
+            .store
+            .read()
+            .unwrap()
+            .cache_stats()
+    }
 }
 
 impl<P, D, SK, PK, Sig> Unpin for DAG<P, D, SK, PK, Sig>
@@ -643,6 +1125,8 @@ where
                         return Poll::Pending;
                     }
                     current_frame += 1;
+                    core.current_frame = Some(current_frame);
+                    core.current_event = Some(0);
                     0
                 }
                 Some(x) => x,
@@ -652,16 +1136,64 @@ where
                 Some(x) => x,
             };
 
-            let frame = { core.store.read().unwrap().get_frame(current_frame).unwrap() };
-            let n_events = frame.events.len();
-
-            let event_record = frame.events[current_event];
-            let mut event = {
+            let frame = match {
                 core.store
                     .read()
                     .unwrap()
-                    .get_event(&event_record.hash)
-                    .unwrap()
+                    .get_finalised_frame(current_frame)
+            } {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!(
+                        "o {}: finalised frame {} read error: {:?}",
+                        me, current_frame, e
+                    );
+                    return Poll::Ready(None);
+                }
+            };
+            let n_events = frame.events.len();
+            if n_events == 0 {
+                // no Atropos has been elected in the frame
+                core.current_event = None;
+                continue;
+            }
+
+            if !frame.tx_order.is_empty() {
+                // FairOrder mode: deliver transactions in the order set at finalisation
+                let (event_hash, tx_index) = frame.tx_order[current_event];
+                let mut event = match { core.store.read().unwrap().get_event(&event_hash) } {
+                    Ok(event) => event,
+                    Err(e) => {
+                        error!(
+                            "o {}: finalised event {:?} read error: {:?}",
+                            me, event_hash, e
+                        );
+                        return Poll::Ready(None);
+                    }
+                };
+                if let Some(record) = frame.events.iter().find(|r| r.hash == event_hash) {
+                    core.last_delivered_timestamp = Some(record.consensus_timestamp);
+                }
+                data = Some((event.transactions.swap_remove(tx_index), event.creator));
+                current_event += 1;
+                if current_event < frame.tx_order.len() {
+                    core.current_event = Some(current_event);
+                } else {
+                    core.current_event = None;
+                }
+                break;
+            }
+
+            let event_record = frame.events[current_event];
+            let mut event = match { core.store.read().unwrap().get_event(&event_record.hash) } {
+                Ok(event) => event,
+                Err(e) => {
+                    error!(
+                        "o {}: finalised event {:?} read error: {:?}",
+                        me, event_record.hash, e
+                    );
+                    return Poll::Ready(None);
+                }
             };
             debug!("o {}: current event: {}", me.clone(), event.clone());
 
@@ -669,6 +1201,7 @@ where
             debug!("o {}: n_tx:{}", me.clone(), n_tx);
             if n_tx > 0 {
                 data = Some((event.transactions.swap_remove(current_tx), event.creator));
+                core.last_delivered_timestamp = Some(event_record.consensus_timestamp);
             } else {
                 debug!("o {}: event with no txs", me);
             }
@@ -682,7 +1215,6 @@ where
                     core.current_event = Some(current_event);
                 } else {
                     core.current_event = None;
-                    core.current_frame = Some(current_frame + 1);
                 }
             }
             if data != None {
@@ -696,18 +1228,36 @@ where
     }
 }
 
+mod checkpoint;
 mod conf;
 mod core;
+mod election;
+mod engine;
 mod errors;
 mod event;
 mod flag_table;
 mod frame;
+mod hashgraph;
+mod hlc;
+mod lachesis;
 mod lamport_time;
+mod ordering;
 mod peer;
+mod peer_selector;
+mod rate_limit;
+mod replay;
+mod retention;
+mod schema;
 mod store;
+mod store_cached;
+mod store_mem;
 mod store_sled;
 mod sync;
+#[cfg(test)]
+mod test_utils;
 mod transactions;
+mod transport;
+mod verify;
 
 #[cfg(test)]
 mod tests {
@@ -727,6 +1277,7 @@ mod tests {
     use crate::libconsensus::ConsensusConfiguration;
     pub use crate::peer::DAGPeer;
     pub use crate::peer::DAGPeerList;
+    use crate::DAGstoreType;
     use crate::DAG;
 
     type Id = PublicKey;
@@ -752,7 +1303,16 @@ mod tests {
 
     #[test]
     fn test_vectorised_network() {
-        env_logger::init();
+        vectorised_network(10001, libcommon_rs::store::StoreType::Sled.into());
+    }
+
+    #[test]
+    fn test_vectorised_network_in_memory() {
+        vectorised_network(10101, DAGstoreType::Memory);
+    }
+
+    fn vectorised_network(base_port: usize, store_type: DAGstoreType) {
+        let _ = env_logger::try_init();
         //        syslog::init(
         //            syslog::Facility::LOG_USER,
         //            log::LevelFilter::Debug,
@@ -760,7 +1320,6 @@ mod tests {
         //        )
         //        .unwrap();
         const N: usize = 5;
-        const BASE_PORT: usize = 10001;
         let mut kp: Vec<(PublicKey, SecretKey)> = Vec::with_capacity(N);
         let mut peer_list = DAGPeerList::<Id, PublicKey>::default();
         let mut dag: Vec<DAG<Id, Data, SecretKey, PublicKey, Signature<EventHash>>> =
@@ -771,7 +1330,7 @@ mod tests {
             kp.push(Signature::<EventHash>::generate_key_pair().unwrap());
             let mut peer = DAGPeer::<Id, PublicKey>::new(
                 kp[i].0.clone(),
-                format!("127.0.0.1:{}", BASE_PORT + 2 * i).to_string(),
+                format!("127.0.0.1:{}", base_port + 2 * i).to_string(),
             );
             peer.set_public_key(kp[i].0.clone());
             peer_list.add(peer).unwrap();
@@ -779,11 +1338,11 @@ mod tests {
         }
         for i in 0..N {
             let mut consensus_config = DAGconfig::<Id, Data, SecretKey, PublicKey>::new();
-            consensus_config.request_addr = format!("127.0.0.1:{}", BASE_PORT + 2 * i).to_string();
+            consensus_config.request_addr = format!("127.0.0.1:{}", base_port + 2 * i).to_string();
             consensus_config.reply_addr =
-                format!("127.0.0.1:{}", BASE_PORT + 2 * i + 1).to_string();
+                format!("127.0.0.1:{}", base_port + 2 * i + 1).to_string();
             consensus_config.transport_type = libtransport::TransportType::TCP;
-            consensus_config.store_type = libcommon_rs::store::StoreType::Sled;
+            consensus_config.store_type = store_type.clone();
             consensus_config.creator = kp[i].0.clone();
             consensus_config.public_key = kp[i].0.clone();
             consensus_config.secret_key = kp[i].1.clone();
@@ -0,0 +1,536 @@
+// Final ordering of events within finalised frames
+
+use crate::errors::Result;
+use crate::event::Event;
+use crate::frame::{Frame, FrameRecord};
+use crate::hlc::{median_time, HybridTime};
+use crate::peer::Height;
+use crate::store::DAGstore;
+use libcommon_rs::data::DataType;
+use libcommon_rs::peer::PeerId;
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+// Ordering of events within a finalised frame; chosen in DAGconfig
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderingMode {
+    // order by Lamport time, ties broken by event hash
+    LamportHash,
+    // order by received time: the median of the timestamps at which
+    // a supermajority of creators first saw the event; events not seen
+    // by a supermajority within the frame go last in LamportHash order
+    MedianTimestamp,
+    // events are ordered as in LamportHash mode, while transactions are
+    // delivered in receive-order-fair order: if more creators saw transaction A
+    // before transaction B than otherwise, A goes first unless they are
+    // part of a cycle of such preferences; preferences are considered among
+    // chunks of FAIR_ORDER_CHUNK transactions of a frame only
+    FairOrder,
+}
+
+impl Default for OrderingMode {
+    fn default() -> OrderingMode {
+        OrderingMode::LamportHash
+    }
+}
+
+pub(crate) fn lamport_hash_cmp(a: &FrameRecord, b: &FrameRecord) -> Ordering {
+    if a.lamport_time < b.lamport_time {
+        Ordering::Less
+    } else if a.lamport_time > b.lamport_time {
+        Ordering::Greater
+    } else if a.hash < b.hash {
+        Ordering::Less
+    } else if a.hash > b.hash {
+        Ordering::Greater
+    } else {
+        Ordering::Equal
+    }
+}
+
+pub(crate) fn median_timestamp_cmp(a: &FrameRecord, b: &FrameRecord) -> Ordering {
+    match (a.received_time, b.received_time) {
+        (Some(x), Some(y)) => x.cmp(&y).then_with(|| lamport_hash_cmp(a, b)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => lamport_hash_cmp(a, b),
+    }
+}
+
+// Computes received time of every event of the frame. For each creator
+// the earliest of its events in the frame having the event as an ancestor
+// is found; if there are at least `supermajority` such creators, the received
+// time is the median of timestamps of those events. Events of the frame are
+// all ancestors of the Atropos not finalised earlier, so the earliest of them
+// seeing an event are the same on all nodes, while events finalised earlier
+// cannot have events of the frame as ancestors.
+//
+// Ancestry is tracked as the height of the highest ancestor of every creator,
+// so memory is linear in the number of events times the number of creators.
+pub(crate) fn received_times<Data, P, PK, Sig>(
+    store: &dyn DAGstore<Data, P, PK, Sig>,
+    frame: &Frame,
+    supermajority: usize,
+) -> Result<HashMap<EventHash, HybridTime>>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    let mut events: Vec<Event<Data, P, PK, Sig>> = Vec::with_capacity(frame.events.len());
+    for record in frame.events.iter() {
+        events.push(store.get_event(&record.hash)?);
+    }
+    // parents precede their children in Lamport time order
+    events.sort_by(|a, b| a.lamport_timestamp.cmp(&b.lamport_timestamp));
+    let mut creators: HashMap<P, usize> = HashMap::new();
+    for event in events.iter() {
+        let next = creators.len();
+        creators.entry(event.creator.clone()).or_insert(next);
+    }
+    let position: HashMap<EventHash, usize> = events
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (e.hash, i))
+        .collect();
+    // for each event: height of the highest ancestor of every creator in the frame
+    let mut last_ancestors: Vec<Vec<Option<Height>>> = Vec::with_capacity(events.len());
+    // events of every creator in height order
+    let mut chains: Vec<Vec<usize>> = vec![Vec::new(); creators.len()];
+    for (i, event) in events.iter().enumerate() {
+        let mut ancestors: Vec<Option<Height>> = vec![None; creators.len()];
+        for parent in [event.self_parent, event.other_parent].iter() {
+            if let Some(p) = position.get(parent) {
+                for (a, h) in ancestors.iter_mut().zip(last_ancestors[*p].iter()) {
+                    *a = (*a).max(*h);
+                }
+            }
+        }
+        let creator = creators[&event.creator];
+        ancestors[creator] = Some(event.height);
+        last_ancestors.push(ancestors);
+        chains[creator].push(i);
+    }
+    for chain in chains.iter_mut() {
+        chain.sort_by_key(|i| events[*i].height);
+    }
+    let mut result = HashMap::new();
+    for event in events.iter() {
+        let creator = creators[&event.creator];
+        let height = Some(event.height);
+        // highest ancestor heights only grow along a chain, so the earliest
+        // event of each creator seeing the event is found by binary search
+        let mut times: Vec<HybridTime> = Vec::new();
+        for chain in chains.iter() {
+            let first = chain
+                .binary_search_by(|i| {
+                    if last_ancestors[*i][creator] >= height {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Less
+                    }
+                })
+                .unwrap_err();
+            if let Some(i) = chain.get(first) {
+                times.push(events[*i].timestamp);
+            }
+        }
+        if times.len() < supermajority {
+            continue;
+        }
+        if let Some(time) = median_time(times) {
+            result.insert(event.hash, time);
+        }
+    }
+    Ok(result)
+}
+
+// Returns hash identifying a transaction in arrival records
+pub(crate) fn tx_hash<Data: DataType>(tx: &Data) -> Result<EventHash> {
+    Ok(EventHash::new(tx)?)
+}
+
+// Maximal number of distinct transactions ordered together by fair ordering;
+// it bounds the quadratic cost of comparing arrival orders
+pub(crate) const FAIR_ORDER_CHUNK: usize = 512;
+
+// Computes receive-order-fair order of transactions of the frame, whose events
+// must already be sorted in LamportHash order. Arrival orders of creators are
+// taken from arrival records of the frame events. Transaction A precedes B if
+// more creators saw A before B than B before A; cycles of such preferences are
+// collapsed into strongly connected components ordered in LamportHash order.
+// Distinct transactions are split in LamportHash order into chunks of
+// FAIR_ORDER_CHUNK transactions, each ordered fairly on its own, so the cost
+// grows linearly with the size of the frame.
+// Returns (event hash, transaction index) pairs in final order.
+pub(crate) fn fair_order<Data, P, PK, Sig>(
+    store: &dyn DAGstore<Data, P, PK, Sig>,
+    frame: &Frame,
+) -> Result<Vec<(EventHash, usize)>>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    // distinct transactions in LamportHash order with all their occurrences
+    let mut txs: Vec<EventHash> = Vec::new();
+    let mut occurrences: HashMap<EventHash, Vec<(EventHash, usize)>> = HashMap::new();
+    // arrival position of transactions for each creator: (height, index)
+    let mut arrivals: HashMap<P, HashMap<EventHash, (usize, usize)>> = HashMap::new();
+    for record in frame.events.iter() {
+        let event: Event<Data, P, PK, Sig> = store.get_event(&record.hash)?;
+        for (i, tx) in event.transactions.iter().enumerate() {
+            let hash = tx_hash(tx)?;
+            let places = occurrences.entry(hash).or_insert_with(|| {
+                txs.push(hash);
+                Vec::new()
+            });
+            places.push((event.hash, i));
+        }
+        let seen = arrivals
+            .entry(event.creator.clone())
+            .or_insert_with(HashMap::new);
+        for (i, hash) in event.tx_arrivals.iter().enumerate() {
+            let position = (event.height, i);
+            let earlier = match seen.get(hash) {
+                Some(p) => *p <= position,
+                None => false,
+            };
+            if !earlier {
+                seen.insert(*hash, position);
+            }
+        }
+    }
+    let mut result = Vec::with_capacity(txs.len());
+    for chunk in txs.chunks(FAIR_ORDER_CHUNK) {
+        // arrival positions of transactions of the chunk for each creator
+        let positions: Vec<Vec<Option<(usize, usize)>>> = arrivals
+            .values()
+            .map(|seen| chunk.iter().map(|tx| seen.get(tx).cloned()).collect())
+            .collect();
+        for i in fair_order_chunk(&positions, chunk.len()).into_iter() {
+            result.extend(occurrences[&chunk[i]].iter().cloned());
+        }
+    }
+    Ok(result)
+}
+
+// Orders `n` transactions given their arrival positions for each creator;
+// returns indices of transactions in final order
+fn fair_order_chunk(positions: &[Vec<Option<(usize, usize)>>], n: usize) -> Vec<usize> {
+    // preference graph: edge i -> j when more creators saw i before j
+    let mut edges: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for i in 0..n {
+        for j in i + 1..n {
+            let mut before = 0;
+            let mut after = 0;
+            for seen in positions.iter() {
+                if let (Some(a), Some(b)) = (seen[i], seen[j]) {
+                    if a < b {
+                        before += 1;
+                    } else if b < a {
+                        after += 1;
+                    }
+                }
+            }
+            match before.cmp(&after) {
+                Ordering::Greater => edges[i].push(j),
+                Ordering::Less => edges[j].push(i),
+                Ordering::Equal => {}
+            }
+        }
+    }
+    // order components topologically; among available components the one
+    // containing the earliest transaction in LamportHash order goes first
+    let (component, n_components) = strongly_connected_components(&edges);
+    let mut members: Vec<Vec<usize>> = vec![Vec::new(); n_components];
+    for (i, c) in component.iter().enumerate() {
+        members[*c].push(i);
+    }
+    let mut in_degree: Vec<usize> = vec![0; n_components];
+    for (i, targets) in edges.iter().enumerate() {
+        for j in targets.iter() {
+            if component[i] != component[*j] {
+                in_degree[component[*j]] += 1;
+            }
+        }
+    }
+    let mut ready: BinaryHeap<Reverse<(usize, usize)>> = BinaryHeap::new();
+    for (c, d) in in_degree.iter().enumerate() {
+        if *d == 0 {
+            ready.push(Reverse((members[c][0], c)));
+        }
+    }
+    let mut result = Vec::with_capacity(n);
+    while let Some(Reverse((_, c))) = ready.pop() {
+        for i in members[c].iter() {
+            result.push(*i);
+            for j in edges[*i].iter() {
+                let d = component[*j];
+                if d != c {
+                    in_degree[d] -= 1;
+                    if in_degree[d] == 0 {
+                        ready.push(Reverse((members[d][0], d)));
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+// Orders a batch of finalised events and assigns consensus timestamps to them;
+// returns consensus timestamp of the last event. Received times are computed
+// in MedianTimestamp mode unless the finality engine has set them already.
+// `consensus_time` is the consensus timestamp of the batch set by the engine.
+pub(crate) fn order_frame<Data, P, PK, Sig>(
+    store: &dyn DAGstore<Data, P, PK, Sig>,
+    frame: &mut Frame,
+    mode: &OrderingMode,
+    supermajority: usize,
+    consensus_time: Option<HybridTime>,
+    last: HybridTime,
+) -> Result<HybridTime>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    if *mode == OrderingMode::MedianTimestamp
+        && frame.events.iter().all(|r| r.received_time.is_none())
+    {
+        let received = received_times(store, frame, supermajority)?;
+        for record in frame.events.iter_mut() {
+            record.received_time = received.get(&record.hash).cloned();
+        }
+    }
+    let last = frame.finalise(mode, consensus_time, last);
+    if *mode == OrderingMode::FairOrder {
+        frame.tx_order = fair_order(store, frame)?;
+    }
+    Ok(last)
+}
+
+// Tarjan's algorithm; returns component number of every vertex and
+// the number of components. Iterative to avoid deep recursion on large frames.
+fn strongly_connected_components(edges: &[Vec<usize>]) -> (Vec<usize>, usize) {
+    let n = edges.len();
+    let mut index: Vec<Option<usize>> = vec![None; n];
+    let mut low_link: Vec<usize> = vec![0; n];
+    let mut on_stack: Vec<bool> = vec![false; n];
+    let mut stack: Vec<usize> = Vec::new();
+    let mut component: Vec<usize> = vec![0; n];
+    let mut next_index = 0;
+    let mut n_components = 0;
+    for root in 0..n {
+        if index[root].is_some() {
+            continue;
+        }
+        // (vertex, next edge to follow)
+        let mut calls: Vec<(usize, usize)> = vec![(root, 0)];
+        while let Some((v, e)) = calls.pop() {
+            if e == 0 {
+                index[v] = Some(next_index);
+                low_link[v] = next_index;
+                next_index += 1;
+                stack.push(v);
+                on_stack[v] = true;
+            }
+            if e < edges[v].len() {
+                let w = edges[v][e];
+                calls.push((v, e + 1));
+                match index[w] {
+                    None => calls.push((w, 0)),
+                    Some(w_index) => {
+                        if on_stack[w] {
+                            low_link[v] = low_link[v].min(w_index);
+                        }
+                    }
+                }
+                continue;
+            }
+            if Some(low_link[v]) == index[v] {
+                while let Some(w) = stack.pop() {
+                    on_stack[w] = false;
+                    component[w] = n_components;
+                    if w == v {
+                        break;
+                    }
+                }
+                n_components += 1;
+            }
+            if let Some((u, _)) = calls.last() {
+                low_link[*u] = low_link[*u].min(low_link[v]);
+            }
+        }
+    }
+    (component, n_components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+    use crate::store_mem::MemStore;
+    use crate::test_utils::{Data, Id, RecordedDag, Sig, TestEvent};
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    fn store_with(dag: &RecordedDag) -> (MemStore, Frame) {
+        let mut store =
+            <MemStore as DAGstore<Data, Id, Id, Sig>>::new(Path::new(""), &StoreOptions::default())
+                .unwrap();
+        let mut frame = Frame::default();
+        for event in dag.events.iter() {
+            DAGstore::<Data, Id, Id, Sig>::set_event(&mut store, event.clone()).unwrap();
+            frame.events.push(FrameRecord {
+                hash: event.hash,
+                lamport_time: event.lamport_timestamp,
+                timestamp: event.timestamp,
+                received_time: None,
+                consensus_timestamp: HybridTime::default(),
+            });
+        }
+        (store, frame)
+    }
+
+    // received times computed from full ancestor sets
+    fn received_times_by_ancestors(
+        events: &[TestEvent],
+        supermajority: usize,
+    ) -> HashMap<EventHash, HybridTime> {
+        let mut ancestors: HashMap<EventHash, HashSet<EventHash>> = HashMap::new();
+        for event in events.iter() {
+            let mut seen: HashSet<EventHash> = HashSet::new();
+            seen.insert(event.hash);
+            for parent in [event.self_parent, event.other_parent].iter() {
+                if let Some(a) = ancestors.get(parent) {
+                    seen.extend(a.iter().cloned());
+                }
+            }
+            ancestors.insert(event.hash, seen);
+        }
+        let mut result = HashMap::new();
+        for x in events.iter() {
+            let mut first: HashMap<Id, &TestEvent> = HashMap::new();
+            for y in events
+                .iter()
+                .filter(|y| ancestors[&y.hash].contains(&x.hash))
+            {
+                let earlier = first
+                    .get(&y.creator)
+                    .map_or(false, |f| f.height <= y.height);
+                if !earlier {
+                    first.insert(y.creator.clone(), y);
+                }
+            }
+            if first.len() >= supermajority {
+                let times = first.values().map(|e| e.timestamp).collect();
+                result.insert(x.hash, median_time(times).unwrap());
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_received_times_match_ancestor_sets() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(3);
+        // a lagging creator syncs late
+        dag.add(3, 0, Vec::new());
+        dag.gossip(1);
+        let supermajority = dag.participants().supermajority;
+        let (store, frame) = store_with(&dag);
+        let received = received_times(&store, &frame, supermajority).unwrap();
+        assert!(!received.is_empty());
+        assert_eq!(
+            received,
+            received_times_by_ancestors(&dag.events, supermajority)
+        );
+    }
+
+    #[test]
+    fn test_received_times_need_supermajority() {
+        let mut dag = RecordedDag::new(4);
+        let event = dag.add(0, 1, Vec::new());
+        let supermajority = dag.participants().supermajority;
+        let (store, frame) = store_with(&dag);
+        let received = received_times(&store, &frame, supermajority).unwrap();
+        // seen by its creator only
+        assert!(received.get(&event.hash).is_none());
+    }
+
+    fn tx(value: u32) -> Data {
+        Data { value }
+    }
+
+    fn lamport_hash_frame(dag: &RecordedDag) -> (MemStore, Frame) {
+        let (store, mut frame) = store_with(dag);
+        frame.events.sort_by(lamport_hash_cmp);
+        (store, frame)
+    }
+
+    #[test]
+    fn test_fair_order_follows_majority_of_arrivals() {
+        let mut dag = RecordedDag::new(3);
+        let (a, b) = (tx_hash(&tx(1)).unwrap(), tx_hash(&tx(2)).unwrap());
+        let event = dag.add_with_arrivals(0, 1, vec![tx(1), tx(2)], vec![a, b]);
+        dag.add_with_arrivals(1, 0, Vec::new(), vec![b, a]);
+        dag.add_with_arrivals(2, 1, Vec::new(), vec![b, a]);
+        let (store, frame) = lamport_hash_frame(&dag);
+        assert_eq!(
+            fair_order(&store, &frame).unwrap(),
+            vec![(event.hash, 1), (event.hash, 0)]
+        );
+    }
+
+    #[test]
+    fn test_fair_order_cycle_keeps_lamport_hash_order() {
+        let mut dag = RecordedDag::new(3);
+        let hashes: Vec<EventHash> = (1..4).map(|v| tx_hash(&tx(v)).unwrap()).collect();
+        let (a, b, c) = (hashes[0], hashes[1], hashes[2]);
+        // a before b before c before a, each preferred by two of three creators
+        let event = dag.add_with_arrivals(0, 1, vec![tx(1), tx(2), tx(3)], vec![a, b, c]);
+        dag.add_with_arrivals(1, 0, Vec::new(), vec![b, c, a]);
+        dag.add_with_arrivals(2, 1, Vec::new(), vec![c, a, b]);
+        let (store, frame) = lamport_hash_frame(&dag);
+        assert_eq!(
+            fair_order(&store, &frame).unwrap(),
+            vec![(event.hash, 0), (event.hash, 1), (event.hash, 2)]
+        );
+    }
+
+    #[test]
+    fn test_fair_order_large_frame() {
+        const EVENTS: u32 = 8;
+        const TXS: u32 = 500;
+        let mut dag = RecordedDag::new(4);
+        let mut order = Vec::new();
+        for e in 0..EVENTS {
+            let txs: Vec<Data> = (0..TXS).map(|i| tx(e * TXS + i)).collect();
+            let event = dag.add(0, 1, txs);
+            order.extend((0..TXS as usize).map(|i| (event.hash, i)));
+        }
+        // other creators saw all transactions in reverse order
+        let mut arrivals: Vec<EventHash> = (0..EVENTS * TXS)
+            .map(|v| tx_hash(&tx(v)).unwrap())
+            .collect();
+        arrivals.reverse();
+        for creator in 1..4 {
+            dag.add_with_arrivals(creator, 0, Vec::new(), arrivals.clone());
+        }
+        let (store, frame) = lamport_hash_frame(&dag);
+        // preferences are followed within chunks only
+        let mut expected = Vec::with_capacity(order.len());
+        for chunk in order.chunks(FAIR_ORDER_CHUNK) {
+            expected.extend(chunk.iter().rev().cloned());
+        }
+        assert_eq!(fair_order(&store, &frame).unwrap(), expected);
+    }
+}
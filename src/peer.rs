@@ -1,5 +1,6 @@
 use crate::errors::{Error, Result};
 use crate::lamport_time::LamportTime;
+use crate::peer_selector::{PeerSelector, RoundRobinSelector};
 use core::fmt::Display;
 use core::fmt::Formatter;
 use core::slice::{Iter, IterMut};
@@ -11,8 +12,9 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::{Index, IndexMut};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub(crate) type FrameNumber = usize;
 pub(crate) type Height = usize;
@@ -33,6 +35,113 @@ pub(crate) type GossipList<P> = HashMap<P, Gossip>;
 // reserved for DAG1
 //pub(crate) type SuspectList<P> = HashMap<P, LamportTime>;
 
+// Statistics of Sync Requests sent to a peer; used in peer selection
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SyncStats {
+    pub(crate) succeeded: usize,
+    pub(crate) failed: usize,
+    // number of successful syncs which took longer than slow sync threshold
+    pub(crate) slow: usize,
+    // number of Sync Requests answered with busy status
+    pub(crate) busy: usize,
+    // number of failed syncs in a row; reset on successful sync
+    pub(crate) consecutive_failures: usize,
+    pub(crate) last_rtt: Option<Duration>,
+    // smoothed round trip time
+    pub(crate) avg_rtt: Option<Duration>,
+    pub(crate) last_attempt: Option<Instant>,
+    pub(crate) last_success: Option<Instant>,
+    pub(crate) last_failure: Option<Instant>,
+}
+
+impl SyncStats {
+    pub fn succeeded(&self) -> usize {
+        self.succeeded
+    }
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+    pub fn slow(&self) -> usize {
+        self.slow
+    }
+    pub fn busy(&self) -> usize {
+        self.busy
+    }
+    pub fn consecutive_failures(&self) -> usize {
+        self.consecutive_failures
+    }
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        self.avg_rtt
+    }
+}
+
+// Kinds of peer misbehaviour counted in the peer's misbehaviour score
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Misbehaviour {
+    InvalidSignature,
+    MalformedEvent,
+    // finalised frame sent in catch-up mode does not match its events
+    InvalidFrame,
+    // Sync Reply matching no Sync Request sent to the peer
+    UnsolicitedReply,
+    // message above maximal message size
+    OversizedMessage,
+    Timeout,
+}
+
+impl Misbehaviour {
+    // Score added to misbehaviour score of the peer for a single offence
+    pub(crate) fn penalty(self) -> usize {
+        match self {
+            Misbehaviour::InvalidSignature => 50,
+            Misbehaviour::MalformedEvent => 30,
+            Misbehaviour::InvalidFrame => 30,
+            Misbehaviour::UnsolicitedReply => 10,
+            Misbehaviour::OversizedMessage => 20,
+            Misbehaviour::Timeout => 5,
+        }
+    }
+}
+
+impl Display for Misbehaviour {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// Current time in seconds since UNIX epoch
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Ban of a peer; bans are persisted in the store
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PeerBan<P> {
+    pub peer: P,
+    // expiration time of the ban in seconds since UNIX epoch; None for permanent ban
+    pub until: Option<u64>,
+    pub reason: String,
+}
+
+impl<P> PeerBan<P> {
+    pub fn is_active(&self) -> bool {
+        match self.until {
+            None => true,
+            Some(until) => now_secs() < until,
+        }
+    }
+}
+
+fn default_stake() -> u64 {
+    1
+}
+
 // Peer attributes
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct DAGPeer<P, PK> {
@@ -42,12 +151,22 @@ pub struct DAGPeer<P, PK> {
     pub(crate) id: P,
     #[serde(rename = "NetAddr")]
     pub(crate) request_addr: String,
+    #[serde(rename = "Stake", default = "default_stake")]
+    pub(crate) stake: u64,
     #[serde(skip, default)]
     pub(crate) reply_addr: String,
     #[serde(skip, default)]
     height: Height,
     #[serde(skip, default)]
     lamport_time: LamportTime,
+    #[serde(skip, default)]
+    pub(crate) sync_stats: SyncStats,
+    // misbehaviour score; peer is banned when the score crosses the ban threshold
+    #[serde(skip, default)]
+    pub(crate) misbehaviour: usize,
+    // time the misbehaviour score was last updated
+    #[serde(skip, default)]
+    pub(crate) misbehaviour_at: Option<Instant>,
 }
 
 impl<P, PK> From<BaseConsensusPeer<P, PK>> for DAGPeer<P, PK>
@@ -63,9 +182,13 @@ where
             pub_key: bp.pub_key,
             id: bp.id,
             request_addr: bp.net_addr,
+            stake: default_stake(),
             reply_addr: socket.to_string(),
             height: 0,
             lamport_time: 0,
+            sync_stats: SyncStats::default(),
+            misbehaviour: 0,
+            misbehaviour_at: None,
         }
     }
 }
@@ -83,9 +206,13 @@ where
             pub_key: PK::default(),
             id,
             request_addr: net_addr,
+            stake: default_stake(),
             reply_addr: socket.to_string(),
             height: 0,
             lamport_time: 0,
+            sync_stats: SyncStats::default(),
+            misbehaviour: 0,
+            misbehaviour_at: None,
         }
     }
     fn get_id(&self) -> P {
@@ -129,6 +256,12 @@ where
     pub fn set_public_key(&mut self, key: PK) {
         self.pub_key = key;
     }
+    pub fn get_stake(&self) -> u64 {
+        self.stake
+    }
+    pub fn set_stake(&mut self, stake: u64) {
+        self.stake = stake;
+    }
     // reserved for future use
     //    pub(crate) fn update_lamport_time(&mut self, time: LamportTime) {
     //        if self.lamport_time < time {
@@ -145,6 +278,64 @@ where
     pub(crate) fn get_height(&self) -> Height {
         self.height
     }
+    pub fn get_misbehaviour(&self) -> usize {
+        self.misbehaviour
+    }
+    // Forgives part of the misbehaviour score proportional to the time elapsed
+    // since its last update; `decay` is the number of points forgiven per hour
+    fn decay_misbehaviour(&mut self, decay: u64) {
+        if let Some(at) = self.misbehaviour_at {
+            let forgiven = at.elapsed().as_millis() as u64 * decay / 3_600_000;
+            self.misbehaviour = self.misbehaviour.saturating_sub(forgiven as usize);
+        }
+        self.misbehaviour_at = Some(Instant::now());
+    }
+    pub fn get_sync_stats(&self) -> SyncStats {
+        self.sync_stats.clone()
+    }
+    // Records successful sync with the peer; `slow` is the slow sync threshold
+    pub(crate) fn record_sync_success(&mut self, rtt: Duration, slow: Duration) {
+        self.sync_stats.succeeded += 1;
+        if rtt > slow {
+            self.sync_stats.slow += 1;
+        }
+        self.sync_stats.consecutive_failures = 0;
+        self.sync_stats.last_rtt = Some(rtt);
+        self.sync_stats.avg_rtt = Some(match self.sync_stats.avg_rtt {
+            None => rtt,
+            Some(avg) => (avg * 7 + rtt) / 8,
+        });
+        self.sync_stats.last_success = Some(Instant::now());
+    }
+    // Records Sync Request answered with busy status; the peer is reachable,
+    // so failures in a row are reset
+    pub(crate) fn record_sync_busy(&mut self) {
+        self.sync_stats.busy += 1;
+        self.sync_stats.consecutive_failures = 0;
+    }
+    // Records Sync Request sent to the peer
+    pub(crate) fn record_sync_attempt(&mut self) {
+        self.sync_stats.last_attempt = Some(Instant::now());
+    }
+    // Returns true if the peer failed to sync recently and shall not be
+    // contacted until its backoff expires; `backoff` is the base backoff delay
+    // which is doubled on each consecutive failure.
+    pub(crate) fn is_backing_off(&self, backoff: Duration) -> bool {
+        if self.sync_stats.consecutive_failures == 0 {
+            return false;
+        }
+        let exp = (self.sync_stats.consecutive_failures - 1).min(10) as u32;
+        match self.sync_stats.last_failure {
+            None => false,
+            Some(at) => at.elapsed() < backoff * 2u32.pow(exp),
+        }
+    }
+    // Records failed (timed out) sync with the peer
+    pub(crate) fn record_sync_failure(&mut self) {
+        self.sync_stats.failed += 1;
+        self.sync_stats.consecutive_failures += 1;
+        self.sync_stats.last_failure = Some(Instant::now());
+    }
     pub(crate) fn update_lamport_time_and_height(&mut self, time: LamportTime, height: Height) {
         if self.lamport_time < time {
             self.lamport_time = time;
@@ -182,14 +373,12 @@ where
     PK: PublicKey,
 {
     peers: Vec<DAGPeer<P, PK>>,
-    // number of peers; the size of the peer list
-    n: usize,
-    // round robin number
-    r: usize,
     // creator ID for the current node
     creator: P,
-    // index of creator in the peers
-    current: usize,
+    // selector used by deprecated next_peer()
+    selector: RoundRobinSelector,
+    // banned peers
+    bans: HashMap<P, PeerBan<P>>,
 }
 
 impl<P, PK> Default for DAGPeerList<P, PK>
@@ -200,10 +389,9 @@ where
     fn default() -> DAGPeerList<P, PK> {
         DAGPeerList {
             peers: Vec::with_capacity(5),
-            n: 0,
-            r: 0,
             creator: Default::default(),
-            current: 0,
+            selector: RoundRobinSelector::default(),
+            bans: HashMap::new(),
         }
     }
 }
@@ -238,10 +426,9 @@ where
     fn new() -> Self {
         DAGPeerList {
             peers: Vec::with_capacity(5),
-            n: 0,
-            r: 0,
             creator: Default::default(),
-            current: 0,
+            selector: RoundRobinSelector::default(),
+            bans: HashMap::new(),
         }
     }
     fn get_peers_from_file(&mut self, json_peer_path: String) -> std::result::Result<(), Error> {
@@ -256,8 +443,6 @@ where
 
     fn add(&mut self, p: DAGPeer<Pid, PK>) -> std::result::Result<(), Error> {
         self.peers.push(p);
-        self.n = self.peers.len();
-        self.r = self.n >> 1;
         self.sort_peers();
         Ok(())
     }
@@ -284,27 +469,30 @@ where
     }
     fn sort_peers(&mut self) {
         self.peers_mut().sort_by(|a, b| a.id.cmp(&b.id));
-        self.current = match self.peers.iter().position(|x| x.id == self.creator) {
-            Some(p) => p,
-            None => 0, //panic!("creator not found in the peers!"),
-        }
     }
     pub fn set_creator(&mut self, creator: P) {
         self.creator = creator;
         self.sort_peers();
     }
-    pub fn next_peer(&mut self) -> DAGPeer<P, PK> {
-        // we assume the very first peer in the vector is one
-        // corresponding to the current node, so the value of `current`
-        // is always 0 and omitted here.
-        //let next = 1 + self.r % (self.n - 1);
-        let next = (self.current + self.r) % self.n;
-        if self.r > 1 {
-            self.r >>= 1;
-        } else {
-            self.r = self.n >> 1
-        }
-        self.peers[next].clone()
+    // Returns the next peer to sync with in round robin order; None if no peer
+    // other than the node itself is eligible
+    #[deprecated(note = "peers are chosen by the selector set in DAGconfig::peer_selection")]
+    pub fn next_peer(&mut self) -> Option<DAGPeer<P, PK>> {
+        let mut selector = self.selector.clone();
+        let next = PeerSelector::<P, PK>::next_peer(&mut selector, self);
+        self.selector = selector;
+        next
+    }
+    // Returns peers eligible for sync: all peers except the node itself,
+    // banned peers and those backing off after failed syncs
+    pub(crate) fn eligible_peers(&self, backoff: Duration) -> Vec<DAGPeer<P, PK>> {
+        self.peers
+            .iter()
+            .filter(|p| {
+                p.id != self.creator && !self.is_banned(&p.id) && !p.is_backing_off(backoff)
+            })
+            .cloned()
+            .collect()
     }
 
     pub fn get_gossip_list(&self) -> GossipList<P> {
@@ -354,6 +542,80 @@ where
         }
     }
 
+    // Returns peers other than the node itself with an address on the host
+    pub(crate) fn find_peers_by_ip(&self, ip: &IpAddr) -> Vec<P> {
+        let on_host = |address: &str| {
+            address
+                .parse::<SocketAddr>()
+                .map_or(false, |a| a.ip() == *ip)
+        };
+        self.peers
+            .iter()
+            .filter(|p| {
+                p.id != self.creator && (on_host(&p.request_addr) || on_host(&p.reply_addr))
+            })
+            .map(|p| p.id.clone())
+            .collect()
+    }
+
+    // Adds penalty of the misbehaviour to the peer's score, decayed by `decay`
+    // points per hour since the last offence, and returns the new score
+    pub(crate) fn report_misbehaviour(
+        &mut self,
+        id: &P,
+        m: Misbehaviour,
+        decay: u64,
+    ) -> Result<usize> {
+        let peer = self.find_peer_mut(id)?;
+        peer.decay_misbehaviour(decay);
+        peer.misbehaviour += m.penalty();
+        Ok(peer.misbehaviour)
+    }
+
+    pub fn is_banned(&self, id: &P) -> bool {
+        match self.bans.get(id) {
+            None => false,
+            Some(ban) => ban.is_active(),
+        }
+    }
+
+    pub(crate) fn ban(&mut self, ban: PeerBan<P>) {
+        self.bans.insert(ban.peer.clone(), ban);
+    }
+
+    // Lifts the ban of the peer and resets its misbehaviour score
+    pub(crate) fn unban(&mut self, id: &P) -> Option<PeerBan<P>> {
+        if let Ok(peer) = self.find_peer_mut(id) {
+            peer.misbehaviour = 0;
+            peer.misbehaviour_at = None;
+        }
+        self.bans.remove(id)
+    }
+
+    // Lifts bans which have expired, resetting misbehaviour scores of
+    // the peers; returns the peers whose bans have been lifted
+    pub(crate) fn expire_bans(&mut self) -> Vec<P> {
+        let expired: Vec<P> = self
+            .bans
+            .values()
+            .filter(|ban| !ban.is_active())
+            .map(|ban| ban.peer.clone())
+            .collect();
+        for id in expired.iter() {
+            self.unban(id);
+        }
+        expired
+    }
+
+    // Returns list of active bans
+    pub fn get_bans(&self) -> Vec<PeerBan<P>> {
+        self.bans
+            .values()
+            .filter(|ban| ban.is_active())
+            .cloned()
+            .collect()
+    }
+
     /// Return RootMajority value
     pub(crate) fn root_majority(&self) -> usize {
         2 * self.peers.len() / 3 + 1
@@ -364,6 +626,82 @@ where
     }
 
     pub(crate) fn get_creator_id(&self) -> P {
-        self.peers[self.current].id.clone()
+        self.creator.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::new_peers;
+
+    #[test]
+    fn test_expired_ban_resets_misbehaviour() {
+        let (mut peers, keys) = new_peers(3);
+        let id = keys[1].0.clone();
+        for _ in 0..20 {
+            peers
+                .report_misbehaviour(&id, Misbehaviour::Timeout, 0)
+                .unwrap();
+        }
+        assert_eq!(peers.find_peer(&id).unwrap().get_misbehaviour(), 100);
+        peers.ban(PeerBan {
+            peer: id.clone(),
+            until: Some(now_secs() - 1),
+            reason: "test".to_string(),
+        });
+        assert!(!peers.is_banned(&id));
+        assert!(peers.expire_bans() == vec![id.clone()]);
+        assert!(peers.get_bans().is_empty());
+        // a single offence after the ban does not reach the threshold again
+        assert_eq!(
+            peers
+                .report_misbehaviour(&id, Misbehaviour::Timeout, 0)
+                .unwrap(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_misbehaviour_decays() {
+        let (mut peers, keys) = new_peers(2);
+        let id = keys[0].0.clone();
+        peers
+            .report_misbehaviour(&id, Misbehaviour::InvalidSignature, 60)
+            .unwrap();
+        peers.find_peer_mut(&id).unwrap().misbehaviour_at =
+            Some(Instant::now() - Duration::from_secs(1800));
+        // half an hour forgives 30 of 50 points
+        assert_eq!(
+            peers
+                .report_misbehaviour(&id, Misbehaviour::Timeout, 60)
+                .unwrap(),
+            25
+        );
+    }
+
+    #[test]
+    fn test_sync_statistics() {
+        let (mut peers, keys) = new_peers(2);
+        let peer = peers.find_peer_mut(&keys[0].0).unwrap();
+        let slow = Duration::from_millis(100);
+        let backoff = Duration::from_secs(3600);
+        peer.record_sync_success(Duration::from_millis(10), slow);
+        peer.record_sync_success(Duration::from_millis(200), slow);
+        peer.record_sync_failure();
+        peer.record_sync_failure();
+        let stats = peer.get_sync_stats();
+        assert_eq!((stats.succeeded, stats.slow, stats.failed), (2, 1, 2));
+        assert_eq!(stats.consecutive_failures, 2);
+        assert_eq!(stats.last_rtt, Some(Duration::from_millis(200)));
+        assert!(peer.is_backing_off(backoff));
+        // a busy peer is reachable, so it is not backed off
+        peer.record_sync_busy();
+        assert!(!peer.is_backing_off(backoff));
+        peer.record_sync_failure();
+        assert!(!peer.is_backing_off(Duration::from_secs(0)));
+        peer.record_sync_success(Duration::from_millis(10), slow);
+        assert_eq!(peer.get_sync_stats().consecutive_failures, 0);
+        assert!(!peer.is_backing_off(backoff));
     }
 }
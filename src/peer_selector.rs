@@ -0,0 +1,332 @@
+// Peer selection strategies used by procedure A to choose the next peer to sync with
+
+use crate::peer::{DAGPeer, DAGPeerList};
+use libcommon_rs::peer::PeerId;
+use libsignature::PublicKey;
+use rand::Rng;
+use std::time::Duration;
+
+// Type of peer selection strategy; chosen in DAGconfig
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerSelectionType {
+    // cycle through peers in turn
+    RoundRobin,
+    // choose a peer uniformly at random
+    Random,
+    // choose a peer at random with probability proportional to its stake
+    StakeWeighted,
+    // choose the peer we have not sent Sync Request to for the longest time
+    LeastRecentlySynced,
+    // choose a peer at random preferring those with lower measured round trip time
+    LatencyAware,
+}
+
+impl Default for PeerSelectionType {
+    fn default() -> PeerSelectionType {
+        PeerSelectionType::RoundRobin
+    }
+}
+
+pub(crate) trait PeerSelector<P, PK>: Send
+where
+    P: PeerId,
+    PK: PublicKey,
+{
+    // Chooses the next peer to sync with; returns None if no peer is eligible.
+    // The node itself and peers backing off after failed syncs are never chosen.
+    fn next_peer(&mut self, peers: &DAGPeerList<P, PK>) -> Option<DAGPeer<P, PK>>;
+}
+
+// Creates peer selector of specified type;
+// `backoff` is the base backoff delay for unreachable peers
+pub(crate) fn new_peer_selector<P, PK>(
+    selection: &PeerSelectionType,
+    backoff: Duration,
+) -> Box<dyn PeerSelector<P, PK>>
+where
+    P: PeerId,
+    PK: PublicKey,
+{
+    match selection {
+        PeerSelectionType::RoundRobin => Box::new(RoundRobinSelector { backoff, next: 0 }),
+        PeerSelectionType::Random => Box::new(RandomSelector { backoff }),
+        PeerSelectionType::StakeWeighted => Box::new(StakeWeightedSelector { backoff }),
+        PeerSelectionType::LeastRecentlySynced => Box::new(LeastRecentlySyncedSelector { backoff }),
+        PeerSelectionType::LatencyAware => Box::new(LatencyAwareSelector { backoff }),
+    }
+}
+
+// Chooses an index at random with probability proportional to its weight
+fn weighted_choice(weights: &[u64]) -> Option<usize> {
+    let total: u64 = weights.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let mut point = rand::thread_rng().gen_range(0, total);
+    for (i, w) in weights.iter().enumerate() {
+        if point < *w {
+            return Some(i);
+        }
+        point -= *w;
+    }
+    None
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct RoundRobinSelector {
+    backoff: Duration,
+    next: usize,
+}
+
+impl<P, PK> PeerSelector<P, PK> for RoundRobinSelector
+where
+    P: PeerId,
+    PK: PublicKey,
+{
+    fn next_peer(&mut self, peers: &DAGPeerList<P, PK>) -> Option<DAGPeer<P, PK>> {
+        let eligible = peers.eligible_peers(self.backoff);
+        if eligible.is_empty() {
+            return None;
+        }
+        let peer = eligible[self.next % eligible.len()].clone();
+        self.next = self.next.wrapping_add(1);
+        Some(peer)
+    }
+}
+
+struct RandomSelector {
+    backoff: Duration,
+}
+
+impl<P, PK> PeerSelector<P, PK> for RandomSelector
+where
+    P: PeerId,
+    PK: PublicKey,
+{
+    fn next_peer(&mut self, peers: &DAGPeerList<P, PK>) -> Option<DAGPeer<P, PK>> {
+        let eligible = peers.eligible_peers(self.backoff);
+        if eligible.is_empty() {
+            return None;
+        }
+        let next = rand::thread_rng().gen_range(0, eligible.len());
+        Some(eligible[next].clone())
+    }
+}
+
+struct StakeWeightedSelector {
+    backoff: Duration,
+}
+
+impl<P, PK> PeerSelector<P, PK> for StakeWeightedSelector
+where
+    P: PeerId,
+    PK: PublicKey,
+{
+    fn next_peer(&mut self, peers: &DAGPeerList<P, PK>) -> Option<DAGPeer<P, PK>> {
+        let eligible = peers.eligible_peers(self.backoff);
+        let weights: Vec<u64> = eligible.iter().map(|p| p.get_stake()).collect();
+        match weighted_choice(&weights) {
+            Some(next) => Some(eligible[next].clone()),
+            // all stakes are zero; fall back to the first eligible peer
+            None => eligible.first().cloned(),
+        }
+    }
+}
+
+struct LeastRecentlySyncedSelector {
+    backoff: Duration,
+}
+
+impl<P, PK> PeerSelector<P, PK> for LeastRecentlySyncedSelector
+where
+    P: PeerId,
+    PK: PublicKey,
+{
+    fn next_peer(&mut self, peers: &DAGPeerList<P, PK>) -> Option<DAGPeer<P, PK>> {
+        // peers never contacted have no last attempt and go first
+        peers
+            .eligible_peers(self.backoff)
+            .into_iter()
+            .min_by_key(|p| p.sync_stats.last_attempt)
+    }
+}
+
+struct LatencyAwareSelector {
+    backoff: Duration,
+}
+
+impl LatencyAwareSelector {
+    // Weight given to peers with no round trip time measured yet,
+    // so they are probed with the highest priority
+    const UNMEASURED_WEIGHT: u64 = 1_000_000;
+}
+
+impl<P, PK> PeerSelector<P, PK> for LatencyAwareSelector
+where
+    P: PeerId,
+    PK: PublicKey,
+{
+    fn next_peer(&mut self, peers: &DAGPeerList<P, PK>) -> Option<DAGPeer<P, PK>> {
+        let eligible = peers.eligible_peers(self.backoff);
+        // weight is inversely proportional to the smoothed round trip time
+        let weights: Vec<u64> = eligible
+            .iter()
+            .map(|p| match p.sync_stats.avg_rtt {
+                None => Self::UNMEASURED_WEIGHT,
+                Some(rtt) => Self::UNMEASURED_WEIGHT / (rtt.as_micros() as u64).max(1),
+            })
+            .map(|w| w.max(1))
+            .collect();
+        weighted_choice(&weights).map(|next| eligible[next].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerBan;
+    use crate::test_utils::{new_peers, Id};
+    use libsignature_ed25519_dalek::PublicKey as TestPublicKey;
+    use std::collections::HashSet;
+
+    const BACKOFF: Duration = Duration::from_secs(3600);
+    const SELECTIONS: usize = 50;
+
+    // Peer list of `n` peers where the node is the first peer; returns
+    // the list with IDs of the other peers
+    fn peer_list(n: usize) -> (DAGPeerList<Id, TestPublicKey>, Vec<Id>) {
+        let (mut peers, keys) = new_peers(n);
+        peers.set_creator(keys[0].0.clone());
+        (peers, keys[1..].iter().map(|k| k.0.clone()).collect())
+    }
+
+    fn selected(
+        selection: &PeerSelectionType,
+        peers: &DAGPeerList<Id, TestPublicKey>,
+    ) -> Vec<Option<Id>> {
+        let mut selector = new_peer_selector::<Id, TestPublicKey>(selection, BACKOFF);
+        (0..SELECTIONS)
+            .map(|_| selector.next_peer(peers).map(|p| p.id))
+            .collect()
+    }
+
+    const ALL: [PeerSelectionType; 5] = [
+        PeerSelectionType::RoundRobin,
+        PeerSelectionType::Random,
+        PeerSelectionType::StakeWeighted,
+        PeerSelectionType::LeastRecentlySynced,
+        PeerSelectionType::LatencyAware,
+    ];
+
+    #[test]
+    fn test_node_itself_never_selected() {
+        let (peers, others) = peer_list(4);
+        for selection in ALL.iter() {
+            for id in selected(selection, &peers).into_iter() {
+                assert!(others.contains(&id.unwrap()), "{:?}", selection);
+            }
+        }
+        // a node without other peers has no one to sync with
+        let (alone, _) = peer_list(1);
+        for selection in ALL.iter() {
+            assert!(selected(selection, &alone).iter().all(|id| id.is_none()));
+        }
+    }
+
+    #[test]
+    fn test_backing_off_and_banned_peers_skipped() {
+        let (mut peers, others) = peer_list(4);
+        peers
+            .find_peer_mut(&others[0])
+            .unwrap()
+            .record_sync_failure();
+        peers.ban(PeerBan {
+            peer: others[1].clone(),
+            until: None,
+            reason: "test".to_string(),
+        });
+        for selection in ALL.iter() {
+            for id in selected(selection, &peers).into_iter() {
+                assert_eq!(id.as_ref(), Some(&others[2]), "{:?}", selection);
+            }
+        }
+        peers
+            .find_peer_mut(&others[2])
+            .unwrap()
+            .record_sync_failure();
+        for selection in ALL.iter() {
+            assert!(selected(selection, &peers).iter().all(|id| id.is_none()));
+        }
+    }
+
+    #[test]
+    fn test_round_robin_cycles_through_peers() {
+        let (peers, others) = peer_list(4);
+        let ids = selected(&PeerSelectionType::RoundRobin, &peers);
+        for (i, id) in ids.into_iter().enumerate() {
+            assert_eq!(id.as_ref(), Some(&others[i % others.len()]));
+        }
+    }
+
+    #[test]
+    fn test_random_reaches_every_peer() {
+        let (peers, others) = peer_list(3);
+        let ids: HashSet<Id> = selected(&PeerSelectionType::Random, &peers)
+            .into_iter()
+            .map(|id| id.unwrap())
+            .collect();
+        // the chance to miss a peer in 50 selections is 2^-50
+        assert_eq!(ids, others.into_iter().collect());
+    }
+
+    #[test]
+    fn test_stake_weighted_follows_stake() {
+        let (mut peers, others) = peer_list(4);
+        peers.find_peer_mut(&others[0]).unwrap().set_stake(0);
+        peers.find_peer_mut(&others[1]).unwrap().set_stake(0);
+        peers.find_peer_mut(&others[2]).unwrap().set_stake(10);
+        for id in selected(&PeerSelectionType::StakeWeighted, &peers).into_iter() {
+            assert_eq!(id.as_ref(), Some(&others[2]));
+        }
+        // without any stake the first eligible peer is chosen
+        peers.find_peer_mut(&others[2]).unwrap().set_stake(0);
+        for id in selected(&PeerSelectionType::StakeWeighted, &peers).into_iter() {
+            assert_eq!(id.as_ref(), Some(&others[0]));
+        }
+    }
+
+    #[test]
+    fn test_least_recently_synced_first() {
+        let (mut peers, others) = peer_list(3);
+        peers
+            .find_peer_mut(&others[0])
+            .unwrap()
+            .record_sync_attempt();
+        let ids = selected(&PeerSelectionType::LeastRecentlySynced, &peers);
+        assert_eq!(ids[0].as_ref(), Some(&others[1]));
+        peers
+            .find_peer_mut(&others[1])
+            .unwrap()
+            .record_sync_attempt();
+        let ids = selected(&PeerSelectionType::LeastRecentlySynced, &peers);
+        assert_eq!(ids[0].as_ref(), Some(&others[0]));
+    }
+
+    #[test]
+    fn test_latency_aware_prefers_unmeasured_and_fast_peers() {
+        let (mut peers, others) = peer_list(3);
+        let slow = Duration::from_secs(1);
+        peers
+            .find_peer_mut(&others[0])
+            .unwrap()
+            .record_sync_success(Duration::from_secs(1000), slow);
+        // the unmeasured peer weighs a million times more than the slow one
+        let ids = selected(&PeerSelectionType::LatencyAware, &peers);
+        assert!(
+            ids.iter()
+                .filter(|id| id.as_ref() == Some(&others[1]))
+                .count()
+                > SELECTIONS - 2
+        );
+    }
+}
@@ -0,0 +1,138 @@
+// Rate limiting of Sync Requests and cap on concurrent Sync Replies served by procedure B
+
+use libcommon_rs::peer::PeerId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Token bucket: holds up to `capacity` tokens refilled at `rate` tokens per second;
+// every admitted request takes one token.
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    rate: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u32, burst: u32) -> TokenBucket {
+        let capacity = f64::from(burst.max(1));
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            rate: f64::from(rate),
+            last: Instant::now(),
+        }
+    }
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+    // Returns true if a token is available, without taking it
+    fn check(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+    // Takes a token if available; returns false otherwise
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        if self.check() {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// RequestLimiter enforces per peer Sync Request rate limits
+pub(crate) struct RequestLimiter<P> {
+    per_peer: HashMap<P, TokenBucket>,
+    peer_rate: u32,
+    peer_burst: u32,
+}
+
+impl<P> RequestLimiter<P>
+where
+    P: PeerId,
+{
+    pub(crate) fn new(peer_rate: u32, peer_burst: u32) -> RequestLimiter<P> {
+        RequestLimiter {
+            per_peer: HashMap::new(),
+            peer_rate,
+            peer_burst,
+        }
+    }
+    // Returns true if the Sync Request from the peer may be served now
+    pub(crate) fn admit(&mut self, peer: &P) -> bool {
+        let (peer_rate, peer_burst) = (self.peer_rate, self.peer_burst);
+        self.per_peer
+            .entry(peer.clone())
+            .or_insert_with(|| TokenBucket::new(peer_rate, peer_burst))
+            .try_acquire()
+    }
+}
+
+// ReplySlots caps the number of Sync Replies formed and sent at the same time
+#[derive(Clone)]
+pub(crate) struct ReplySlots {
+    in_flight: Arc<AtomicUsize>,
+    max: usize,
+}
+
+// A reply in progress; the slot is released when dropped
+pub(crate) struct ReplySlot {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ReplySlots {
+    pub(crate) fn new(max: usize) -> ReplySlots {
+        ReplySlots {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+    // Takes a slot if fewer than maximal number of replies are in progress
+    pub(crate) fn try_acquire(&self) -> Option<ReplySlot> {
+        if self.in_flight.fetch_add(1, Ordering::SeqCst) >= self.max {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(ReplySlot {
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+impl Drop for ReplySlot {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_slots_cap_concurrent_replies() {
+        let slots = ReplySlots::new(2);
+        let first = slots.try_acquire();
+        let second = slots.try_acquire();
+        assert!(first.is_some() && second.is_some());
+        assert!(slots.try_acquire().is_none());
+        drop(first);
+        assert!(slots.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_token_bucket_limits_burst() {
+        let mut bucket = TokenBucket::new(0, 3);
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+    }
+}
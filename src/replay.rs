@@ -0,0 +1,226 @@
+// Offline replay of a stored DAG
+//
+// Events of a sled store are fed in Lamport time order, which is a topological
+// order, into a fresh finality engine over an in-memory store. Frame numbers
+// and finalised frames produced, including the final order and consensus
+// timestamps, are compared with those stored by the node.
+
+use crate::engine::{new_finality_engine, FinalityType, Participants};
+use crate::errors::{Error, Result};
+use crate::event::Event;
+use crate::frame::Frame;
+use crate::hlc::HybridTime;
+use crate::ordering::{order_frame, OrderingMode};
+use crate::peer::DAGPeerList;
+use crate::store::{DAGstore, Durability, StoreOptions};
+use crate::store_mem::MemStore;
+use crate::store_sled::SledStore;
+use libcommon_rs::data::DataType;
+use libcommon_rs::peer::{Peer, PeerId, PeerList};
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+use std::cmp::Ordering;
+use std::path::Path;
+use to_vec::ToVec;
+
+// Result of the replay of a stored DAG
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    // number of events replayed
+    pub events: usize,
+    // number of frames finalised by the replay
+    pub finalised_frames: usize,
+    // differences from the stored data, one per line
+    pub mismatches: Vec<String>,
+}
+
+impl ReplayReport {
+    // Checks if the replay reproduced the stored data
+    pub fn is_consistent(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Re-runs frame assignment and finalisation over events in the store directory
+/// of a node which is not running and compares the result with the stored frames.
+pub fn replay_store<Data, P, PK, Sig>(
+    store_dir: &Path,
+    peers: &DAGPeerList<P, PK>,
+    finality: &FinalityType,
+    ordering_mode: &OrderingMode,
+) -> Result<ReplayReport>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    let options = StoreOptions {
+        durability: Durability::EveryWrite,
+        cache_capacity: 64 * 1024 * 1024,
+        profile: false,
+        cache_entries: 0,
+    };
+    // the store replayed is only read
+    let stored = SledStore::open_read_only(store_dir, &options)?;
+    let mut report = ReplayReport::default();
+    if stored.is_pruned() {
+        report
+            .mismatches
+            .push("events pruned from the store cannot be replayed".to_string());
+        return Ok(report);
+    }
+    let mut events: Vec<Event<Data, P, PK, Sig>> = stored
+        .event_entries::<Data, P, PK, Sig>()?
+        .into_iter()
+        .filter(|(key, event)| *key == event.hash.to_vec())
+        .map(|(_, event)| event)
+        .collect();
+    events.sort_by(|a, b| {
+        a.lamport_timestamp
+            .cmp(&b.lamport_timestamp)
+            .then_with(|| a.hash.partial_cmp(&b.hash).unwrap_or(Ordering::Equal))
+    });
+    report.events = events.len();
+
+    let participants = Participants {
+        creators: peers.iter().map(|p| p.get_id()).collect(),
+        supermajority: peers.root_majority(),
+    };
+    let mut engine = new_finality_engine::<Data, P, PK, Sig>(finality);
+    let mode = engine.ordering_mode(ordering_mode);
+    let mut store = <MemStore as DAGstore<Data, P, PK, Sig>>::new(store_dir, &options)?;
+    let mut last_consensus_timestamp = HybridTime::default();
+    for mut event in events.into_iter() {
+        if event.self_parent == EventHash::default() {
+            engine.add_leaf_event(&mut store, &event, &participants)?;
+            store.set_event(event)?;
+            continue;
+        }
+        let frame = event.frame_number;
+        engine.add_event(&mut store, &mut event, &participants)?;
+        if event.frame_number != frame {
+            report.mismatches.push(format!(
+                "event {}: stored frame {}, replayed frame {}",
+                event.hash, frame, event.frame_number
+            ));
+        }
+        store.set_event(event)?;
+        for batch in engine
+            .finalised_batches(&mut store, &participants)?
+            .into_iter()
+        {
+            let mut frame = Frame {
+                events: batch.events,
+                tx_order: Vec::new(),
+            };
+            last_consensus_timestamp = order_frame::<Data, P, PK, Sig>(
+                &store,
+                &mut frame,
+                &mode,
+                participants.supermajority,
+                batch.consensus_time,
+                last_consensus_timestamp,
+            )?;
+            match <SledStore as DAGstore<Data, P, PK, Sig>>::get_finalised_frame(
+                &stored,
+                batch.frame,
+            ) {
+                Ok(stored_frame) => {
+                    if stored_frame != frame {
+                        report.mismatches.push(format!(
+                            "finalised frame {}: stored {}, replayed {}",
+                            batch.frame, stored_frame, frame
+                        ));
+                    }
+                }
+                Err(e) => match e.downcast_ref::<Error>() {
+                    Some(Error::NoneError) => report.mismatches.push(format!(
+                        "finalised frame {} is finalised by the replay but not stored",
+                        batch.frame
+                    )),
+                    _ => return Err(e),
+                },
+            }
+            // the engine skips events marked finalised
+            store.set_finalised_frame(batch.frame, frame)?;
+            report.finalised_frames += 1;
+        }
+    }
+    let extra = <SledStore as DAGstore<Data, P, PK, Sig>>::get_finalised_frame(
+        &stored,
+        report.finalised_frames,
+    );
+    match extra {
+        Ok(extra) => {
+            if !extra.events.is_empty() {
+                report.mismatches.push(format!(
+                    "finalised frame {} is stored but not finalised by the replay",
+                    report.finalised_frames
+                ));
+            }
+        }
+        Err(e) => match e.downcast_ref::<Error>() {
+            Some(Error::NoneError) => {}
+            _ => return Err(e),
+        },
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameRecord;
+    use crate::test_utils::{insert_events, new_core, temp_store_dir, Data, Id, RecordedDag, Sig};
+
+    #[test]
+    fn test_replay_reproduces_stored_frames() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(12);
+        let dir = temp_store_dir("replay");
+        {
+            let mut core = new_core(&dag, 0, Some(&dir));
+            insert_events(&mut core, &dag.events);
+        }
+        let path = dir.join(dag.creator(0).to_string());
+        let replay = || {
+            replay_store::<Data, Id, Id, Sig>(
+                &path,
+                &dag.peers,
+                &FinalityType::Lachesis,
+                &OrderingMode::default(),
+            )
+            .unwrap()
+        };
+        let report = replay();
+        assert!(report.is_consistent(), "{:?}", report.mismatches);
+        assert_eq!(report.events, dag.events.len());
+        assert!(report.finalised_frames > 0);
+        // a finalised frame the replay does not reach
+        {
+            let mut store =
+                <SledStore as DAGstore<Data, Id, Id, Sig>>::new(&path, &StoreOptions::default())
+                    .unwrap();
+            let last = dag.events.last().unwrap();
+            let frame = Frame {
+                events: vec![FrameRecord {
+                    hash: last.hash,
+                    lamport_time: last.lamport_timestamp,
+                    timestamp: last.timestamp,
+                    received_time: None,
+                    consensus_timestamp: HybridTime::default(),
+                }],
+                tx_order: Vec::new(),
+            };
+            DAGstore::<Data, Id, Id, Sig>::set_finalised_frame(
+                &mut store,
+                report.finalised_frames,
+                frame,
+            )
+            .unwrap();
+        }
+        let report = replay();
+        assert_eq!(report.mismatches.len(), 1);
+    }
+}
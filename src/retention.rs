@@ -0,0 +1,66 @@
+// Retention of finalised history in the store
+
+use crate::peer::FrameNumber;
+
+// Policy of removing events, frames and flag tables of old finalised frames;
+// chosen in DAGconfig. Only frames already delivered to the consumer are
+// pruned, and the latest event of every creator is always kept, as new
+// events refer to it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RetentionPolicy {
+    // keep everything
+    Archive,
+    // keep the specified number of the latest finalised frames
+    KeepFrames(usize),
+    // keep finalised frames whose consensus timestamp is not older than
+    // the specified number of seconds
+    KeepAge(u64),
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> RetentionPolicy {
+        RetentionPolicy::Archive
+    }
+}
+
+impl RetentionPolicy {
+    // Checks if the frame may be pruned given the last finalised frame,
+    // consensus timestamp of the frame and current time, in milliseconds
+    pub(crate) fn expired(
+        &self,
+        frame: FrameNumber,
+        last_finalised_frame: FrameNumber,
+        frame_time: u64,
+        now: u64,
+    ) -> bool {
+        match self {
+            RetentionPolicy::Archive => false,
+            // huge limits configured keep everything rather than overflow
+            RetentionPolicy::KeepFrames(n) => frame.saturating_add(*n) <= last_finalised_frame,
+            RetentionPolicy::KeepAge(secs) => {
+                frame_time.saturating_add(secs.saturating_mul(1000)) < now
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired() {
+        assert!(!RetentionPolicy::Archive.expired(0, 100, 0, u64::max_value()));
+        let keep_frames = RetentionPolicy::KeepFrames(2);
+        assert!(keep_frames.expired(3, 5, 0, 0));
+        assert!(!keep_frames.expired(4, 5, 0, 0));
+        let keep_age = RetentionPolicy::KeepAge(10);
+        assert!(keep_age.expired(0, 0, 1000, 11_001));
+        assert!(!keep_age.expired(0, 0, 1000, 11_000));
+        // limits too large to add up never expire anything
+        let keep_all_frames = RetentionPolicy::KeepFrames(usize::max_value());
+        assert!(!keep_all_frames.expired(1, usize::max_value() - 1, 0, 0));
+        let keep_forever = RetentionPolicy::KeepAge(u64::max_value());
+        assert!(!keep_forever.expired(0, 0, 1000, u64::max_value()));
+    }
+}
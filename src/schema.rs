@@ -0,0 +1,190 @@
+// Schema versioning of the sled store
+//
+// The schema version is kept in the "meta" tree. A store without the version
+// record is either new, and gets the current version, or was created before
+// versioning was introduced, which is schema version 1. Older stores are
+// upgraded in place by running migrations one version at a time; stores of
+// a newer version are refused.
+//
+// Stores written before the single database layout kept events, flag tables
+// and frames in separate sled databases in subdirectories of the store. Their
+// records were encoded differently and cannot be migrated, so such stores
+// are refused before the database is opened.
+
+use crate::errors::{Error, Result};
+use bincode::{deserialize, serialize};
+use std::path::Path;
+
+// Version of the layout of data written by this code
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+const VERSION_KEY: &[u8] = b"schema_version";
+
+// A migration upgrades a store from one version to the next
+type Migration = fn(&sled::Db) -> Result<()>;
+
+// MIGRATIONS[i] upgrades a store of version i+1 to version i+2
+const MIGRATIONS: &[Migration] = &[];
+
+// Subdirectories holding the databases of the unversioned layout
+const LEGACY_DATABASES: &[&str] = &["events", "flag_tables", "frames"];
+
+// Refuses a store directory with the unversioned layout
+pub(crate) fn check_layout(base_path: &Path) -> Result<()> {
+    if LEGACY_DATABASES
+        .iter()
+        .any(|name| base_path.join(name).is_dir())
+    {
+        return Err(Error::LegacyStoreLayout(base_path.display().to_string()).into());
+    }
+    Ok(())
+}
+
+// Checks the schema version of the store, upgrading the store if needed
+pub(crate) fn check_schema(db: &sled::Db) -> Result<()> {
+    upgrade(db, SCHEMA_VERSION, MIGRATIONS)
+}
+
+// Checks the schema version of a store opened read-only; the store is
+// neither upgraded nor written, so only the current version is accepted
+pub(crate) fn verify_schema(db: &sled::Db) -> Result<()> {
+    let version = stored_version(db, SCHEMA_VERSION)?;
+    if version != SCHEMA_VERSION {
+        return Err(Error::UnsupportedSchema(version, SCHEMA_VERSION).into());
+    }
+    Ok(())
+}
+
+// Returns the schema version of the store; a new store gets the version
+// given. Trees missing are not opened, which would create them.
+fn stored_version(db: &sled::Db, new_version: u32) -> Result<u32> {
+    let names = db.tree_names();
+    let exists = |name: &str| names.iter().any(|n| n.as_ref() == name.as_bytes());
+    if exists("meta") {
+        if let Some(x) = db.open_tree("meta")?.get(VERSION_KEY)? {
+            return Ok(deserialize::<u32>(&x)?);
+        }
+    }
+    if exists("events") && !db.open_tree("events")?.is_empty() {
+        Ok(1)
+    } else {
+        Ok(new_version)
+    }
+}
+
+// Upgrades the store to the target version; the migrations upgrade stores
+// of the versions just below the target, the last one to the target
+fn upgrade(db: &sled::Db, target: u32, migrations: &[Migration]) -> Result<()> {
+    let mut version = stored_version(db, target)?;
+    let meta = db.open_tree("meta")?;
+    if version > target || (target - version) as usize > migrations.len() {
+        return Err(Error::UnsupportedSchema(version, target).into());
+    }
+    while version < target {
+        debug!("migrating store from schema version {}", version);
+        migrations[migrations.len() - (target - version) as usize](db)?;
+        version += 1;
+        // record every step, so an interrupted upgrade resumes where it stopped
+        meta.insert(VERSION_KEY, serialize(&version)?)?;
+        db.flush()?;
+    }
+    meta.insert(VERSION_KEY, serialize(&version)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_store_dir;
+
+    #[test]
+    fn test_new_store_gets_current_version() {
+        let db = sled::open(temp_store_dir("schema-new")).unwrap();
+        check_schema(&db).unwrap();
+        let version = db.open_tree("meta").unwrap().get(VERSION_KEY).unwrap();
+        assert_eq!(
+            deserialize::<u32>(&version.unwrap()).unwrap(),
+            SCHEMA_VERSION
+        );
+        // checking again leaves the store as it is
+        check_schema(&db).unwrap();
+    }
+
+    // Rewrites every event record with a prefix
+    fn tag_events(db: &sled::Db) -> Result<()> {
+        let events = db.open_tree("events")?;
+        let records: Vec<(sled::IVec, sled::IVec)> = events.iter().collect::<sled::Result<_>>()?;
+        for (key, value) in records.into_iter() {
+            let mut tagged = b"v1:".to_vec();
+            tagged.extend_from_slice(&value);
+            events.insert(key, tagged)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_migration_rewrites_records() {
+        let db = sled::open(temp_store_dir("schema-migration")).unwrap();
+        let events = db.open_tree("events").unwrap();
+        events.insert(b"event", b"record".to_vec()).unwrap();
+        let meta = db.open_tree("meta").unwrap();
+        meta.insert(VERSION_KEY, serialize(&0u32).unwrap()).unwrap();
+        let migrations: &[Migration] = &[tag_events];
+        upgrade(&db, 1, migrations).unwrap();
+        let record = |db: &sled::Db| db.open_tree("events").unwrap().get(b"event").unwrap();
+        assert_eq!(record(&db).unwrap().to_vec(), b"v1:record".to_vec());
+        let version = meta.get(VERSION_KEY).unwrap().unwrap();
+        assert_eq!(deserialize::<u32>(&version).unwrap(), 1);
+        // a store upgraded already is not migrated again
+        upgrade(&db, 1, migrations).unwrap();
+        assert_eq!(record(&db).unwrap().to_vec(), b"v1:record".to_vec());
+        // a store older than the migrations reach is refused
+        meta.insert(VERSION_KEY, serialize(&0u32).unwrap()).unwrap();
+        let err = upgrade(&db, 2, migrations).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::UnsupportedSchema(0, 2))
+        );
+    }
+
+    #[test]
+    fn test_verified_without_writing() {
+        let db = sled::open(temp_store_dir("schema-verify")).unwrap();
+        // a store written before versioning is of version 1
+        db.open_tree("events")
+            .unwrap()
+            .insert(b"event", b"record".to_vec())
+            .unwrap();
+        verify_schema(&db).unwrap();
+        assert!(!db.tree_names().iter().any(|n| n.as_ref() == b"meta"));
+        db.open_tree("meta")
+            .unwrap()
+            .insert(VERSION_KEY, serialize(&(SCHEMA_VERSION + 1)).unwrap())
+            .unwrap();
+        let err = verify_schema(&db).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::UnsupportedSchema(
+                SCHEMA_VERSION + 1,
+                SCHEMA_VERSION
+            ))
+        );
+    }
+
+    #[test]
+    fn test_newer_store_refused() {
+        let db = sled::open(temp_store_dir("schema-newer")).unwrap();
+        db.open_tree("meta")
+            .unwrap()
+            .insert(VERSION_KEY, serialize(&(SCHEMA_VERSION + 1)).unwrap())
+            .unwrap();
+        let err = check_schema(&db).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::UnsupportedSchema(
+                SCHEMA_VERSION + 1,
+                SCHEMA_VERSION
+            ))
+        );
+    }
+}
@@ -1,19 +1,81 @@
 // DAG Consensus Store trait
 
-use crate::errors::{Error, Result};
+use crate::errors::Result;
 use crate::event::Event;
 use crate::event::NetEvent;
-use crate::flag_table::{CreatorFlagTable, FlagTable};
+use crate::flag_table::FlagTable;
 use crate::frame::Frame;
+use crate::lamport_time::LamportTime;
 use crate::peer::FrameNumber;
 use crate::peer::GossipList;
 use crate::peer::Height;
+use crate::peer::PeerBan;
+use crate::store_cached::CacheStats;
+use bincode::{deserialize, serialize};
+use libcommon_rs::data::DataType;
 use libcommon_rs::peer::PeerId;
+use libcommon_rs::store::StoreType;
 use libhash_sha3::Hash as EventHash;
 use libsignature::PublicKey;
 use libsignature::Signature;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::collections::HashMap;
+use to_vec::ToVec;
+
+// When data written into the store is flushed to disk; chosen in DAGconfig
+#[derive(Clone, Debug, PartialEq)]
+pub enum Durability {
+    // flush after every write
+    EveryWrite,
+    // flush in background every specified number of milliseconds
+    Interval(u64),
+    // flush when a finalised frame is stored only
+    Finalisation,
+}
+
+impl Default for Durability {
+    fn default() -> Durability {
+        Durability::EveryWrite
+    }
+}
+
+// Backend of the DAG store; chosen in DAGconfig. Store types of libcommon_rs
+// convert into it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DAGstoreType {
+    Unknown,
+    Sled,
+    // the DAG is kept in memory only and is lost when the node stops
+    Memory,
+}
+
+impl Default for DAGstoreType {
+    fn default() -> DAGstoreType {
+        DAGstoreType::Unknown
+    }
+}
+
+impl From<StoreType> for DAGstoreType {
+    fn from(store_type: StoreType) -> DAGstoreType {
+        match store_type {
+            StoreType::Unknown => DAGstoreType::Unknown,
+            StoreType::Sled => DAGstoreType::Sled,
+        }
+    }
+}
+
+// Options of store backends; in-memory store ignores them
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StoreOptions {
+    pub(crate) durability: Durability,
+    // cache size in bytes
+    pub(crate) cache_capacity: u64,
+    // print summary of latency histograms on drop
+    pub(crate) profile: bool,
+    // number of events and of flag tables kept in the cache; 0 disables the cache
+    pub(crate) cache_entries: usize,
+}
 
 pub(crate) trait DAGstore<Data, P, PK, Sig>: Send + Sync
 where
@@ -23,7 +85,7 @@ where
     Sig: Signature<Hash = EventHash, PublicKey = PK>,
 {
     // Create new storage for DAG Consensus
-    fn new(path: &std::path::Path) -> Result<Self>
+    fn new(path: &std::path::Path, options: &StoreOptions) -> Result<Self>
     where
         Self: std::marker::Sized;
 
@@ -48,52 +110,225 @@ where
     // Read Frame with specified frame number
     fn get_frame(&self, frame: FrameNumber) -> Result<Frame>;
 
+    // Writes finalised Frame, the ordered batch of events finalised by
+    // the Atropos of the frame, into storage and marks its events finalised
+    // in the frame in a single transaction
+    fn set_finalised_frame(&mut self, number: FrameNumber, frame: Frame) -> Result<()>;
+
+    // Read finalised Frame with specified frame number; fails with NoneError
+    // if the frame is not finalised yet or is pruned
+    fn get_finalised_frame(&self, frame: FrameNumber) -> Result<Frame>;
+
+    // Marks Event with EventHash as finalised in specified frame
+    fn set_finalised(&mut self, ex: &EventHash, frame: FrameNumber) -> Result<()>;
+
+    // Checks if Event with EventHash is finalised
+    fn is_finalised(&self, ex: &EventHash) -> Result<bool>;
+
+    // Drops data written for an event not stored yet, such as its flag table,
+    // after storing the event has failed
+    fn discard_pending(&mut self, ex: &EventHash);
+
+    // Writes ban of a peer into storage
+    fn set_ban(&mut self, ban: &PeerBan<P>) -> Result<()>;
+
+    // Removes ban of a peer from storage
+    fn remove_ban(&mut self, peer: &P) -> Result<()>;
+
+    // Removes events of the finalised frame, along with their flag tables and
+    // frame records, and the frame itself in a single transaction. The latest
+    // event of a creator is kept and removed when a later frame is pruned after
+    // the next event of the creator is stored. Events removed stay marked finalised.
+    fn prune_finalised_frame(&mut self, frame: FrameNumber) -> Result<()>;
+
+    // Read all bans stored
+    fn get_bans(&self) -> Result<Vec<PeerBan<P>>>;
+
+    // Returns the highest Lamport time of events stored
+    fn get_max_lamport_time(&self) -> Result<LamportTime>;
+
+    // Returns all events stored, each once, in no particular order
+    fn get_events(&self) -> Result<Vec<Event<Data, P, PK, Sig>>>;
+
+    // Returns number of the first finalised frame stored; frames below it are pruned
+    fn get_first_finalised_frame(&self) -> Result<Option<FrameNumber>>;
+
+    // Returns number of the last finalised frame stored
+    fn get_last_finalised_frame(&self) -> Result<Option<FrameNumber>>;
+
     fn get_events_for_gossip(
         &self,
         gossip: &GossipList<P>,
     ) -> Result<Vec<NetEvent<Data, P, PK, Sig>>>;
 
-    // This procedure takes a flag table as input
-    // and produces a map which stores creator's hashes of visible roots;
-    // for each root it stores minimal frame number.
-    fn derive_creator_flag_table(
-        &self,
-        ft: &FlagTable,
-        min_frame: FrameNumber,
-    ) -> CreatorFlagTable<P> {
-        let mut result = CreatorFlagTable::<P>::new();
-        for (key, value) in ft.iter() {
-            if *value >= min_frame {
-                match self.get_event(key) {
-                    Err(e) => match e.downcast::<Error>() {
-                        Ok(err) => {
-                            if err == Error::NoneError {
-                                warn!("Event {:?} not found", key)
-                            } else {
-                                error!(
-                                    "Error {:?} encountered while retrieving event {:?}",
-                                    err, key
-                                )
-                            }
-                        }
-                        Err(erx) => error!(
-                            "Error {:?} encountered while retrieving event {:?}",
-                            erx, key
-                        ),
-                    },
-                    Ok(e) => match result.get(&e.creator) {
-                        Some(frame) => {
-                            if *frame < *value {
-                                result.insert(e.creator, *value);
-                            }
-                        }
-                        _ => {
-                            result.insert(e.creator, *value);
-                        }
-                    },
-                };
+    // Returns statistics of the cache if the store has one
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+// Key of frame data: frame number in big-endian, so keys sort in frame order
+pub(crate) fn frame_key(frame_number: FrameNumber) -> Vec<u8> {
+    (frame_number as u64).to_be_bytes().to_vec()
+}
+
+// Frame number of a frame key or of a frame record key
+pub(crate) fn key_frame(key: &[u8]) -> FrameNumber {
+    let mut number = [0u8; 8];
+    number.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(number) as FrameNumber
+}
+
+// Key of a frame record: frame key followed by hash of the event
+pub(crate) fn frame_record_key(frame_number: FrameNumber, ex: &EventHash) -> Vec<u8> {
+    let mut key = frame_key(frame_number);
+    key.extend_from_slice(&ex.to_vec());
+    key
+}
+
+// Trees of records kept by store backends which pruning and gossip work on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum StoreTree {
+    // events keyed by hash and by creator and height
+    Event,
+    FlagTable,
+    // frame records keyed by frame record key
+    Frame,
+    FinalisedFrame,
+    // the lowest height of events of each creator kept after pruning
+    FirstHeight,
+    // latest events of creators kept when their frames were pruned
+    PruneDeferred,
+}
+
+// Changes of trees applied by a store backend at once
+#[derive(Default)]
+pub(crate) struct TreeBatch {
+    pub(crate) changes: Vec<(StoreTree, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl TreeBatch {
+    pub(crate) fn insert(&mut self, tree: StoreTree, key: Vec<u8>, value: Vec<u8>) {
+        self.changes.push((tree, key, Some(value)));
+    }
+    pub(crate) fn remove(&mut self, tree: StoreTree, key: Vec<u8>) {
+        self.changes.push((tree, key, None));
+    }
+}
+
+// Storage primitives a store backend provides for the logic it shares
+// with other backends
+pub(crate) trait KeyValueStore {
+    // Reads the value of the key in the tree
+    fn tree_get(&self, tree: StoreTree, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    // Reads all values of the tree in key order
+    fn tree_values(&self, tree: StoreTree) -> Result<Vec<Vec<u8>>>;
+
+    // Applies the changes in a single transaction where the backend has them
+    fn tree_apply(&mut self, batch: TreeBatch) -> Result<()>;
+}
+
+// Key of the event of the creator at the height
+fn creator_event_key<P: PeerId>(creator: &P, height: Height) -> Vec<u8> {
+    format!("{}-{}", creator, height).into_bytes()
+}
+
+// Key of the first height of the creator kept
+fn first_height_key<P: PeerId>(creator: &P) -> Vec<u8> {
+    format!("{}", creator).into_bytes()
+}
+
+// Prunes the finalised frame as DAGstore::prune_finalised_frame() describes;
+// everything is read before the first change, so a failure changes nothing
+pub(crate) fn prune_frame<D, P, PK, Sig>(
+    store: &mut dyn KeyValueStore,
+    frame_number: FrameNumber,
+) -> Result<()>
+where
+    D: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    let frame_key = frame_key(frame_number);
+    let frame: Frame = match store.tree_get(StoreTree::FinalisedFrame, &frame_key)? {
+        Some(x) => deserialize::<Frame>(&x)?,
+        None => return Ok(()),
+    };
+    // events of the frame and events deferred when earlier frames were pruned
+    let mut candidates: Vec<EventHash> = frame.events.iter().map(|r| r.hash).collect();
+    for value in store.tree_values(StoreTree::PruneDeferred)?.iter() {
+        candidates.push(deserialize::<EventHash>(value)?);
+    }
+    let mut batch = TreeBatch::default();
+    let mut first_heights: HashMap<Vec<u8>, Height> = HashMap::new();
+    for ex in candidates.iter() {
+        let key = ex.to_vec();
+        let event: Event<D, P, PK, Sig> = match store.tree_get(StoreTree::Event, &key)? {
+            Some(x) => deserialize::<Event<D, P, PK, Sig>>(&x)?,
+            None => continue,
+        };
+        // the latest event of the creator is referred to by its next event
+        let next_key = creator_event_key(&event.creator, event.height + 1);
+        if store.tree_get(StoreTree::Event, &next_key)?.is_none() {
+            batch.insert(StoreTree::PruneDeferred, key, serialize(ex)?);
+            continue;
+        }
+        let creator_key = first_height_key(&event.creator);
+        let first_height = match first_heights.get(&creator_key) {
+            Some(h) => *h,
+            None => match store.tree_get(StoreTree::FirstHeight, &creator_key)? {
+                Some(x) => deserialize::<Height>(&x)?,
+                None => 0,
+            },
+        };
+        first_heights.insert(creator_key, first_height.max(event.height + 1));
+        // events pruned stay marked finalised
+        batch.remove(StoreTree::Event, key.clone());
+        batch.remove(
+            StoreTree::Event,
+            creator_event_key(&event.creator, event.height),
+        );
+        batch.remove(StoreTree::FlagTable, key.clone());
+        batch.remove(StoreTree::Frame, frame_record_key(event.frame_number, ex));
+        batch.remove(StoreTree::PruneDeferred, key);
+    }
+    for (key, height) in first_heights.into_iter() {
+        batch.insert(StoreTree::FirstHeight, key, serialize(&height)?);
+    }
+    batch.remove(StoreTree::FinalisedFrame, frame_key);
+    store.tree_apply(batch)
+}
+
+// Returns events of creators above heights in the gossip list, as
+// DAGstore::get_events_for_gossip() does, in Lamport time order
+pub(crate) fn events_for_gossip<D, P, PK, Sig>(
+    store: &dyn KeyValueStore,
+    gossip: &GossipList<P>,
+) -> Result<Vec<NetEvent<D, P, PK, Sig>>>
+where
+    D: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    let mut events: Vec<NetEvent<D, P, PK, Sig>> = Vec::with_capacity(1);
+    for (peer, gossip) in gossip.iter() {
+        let mut height = gossip.height + 1;
+        // events below the first height kept have been pruned
+        if let Some(x) = store.tree_get(StoreTree::FirstHeight, &first_height_key(peer))? {
+            let first_height = deserialize::<Height>(&x)?;
+            if height < first_height {
+                height = first_height;
             }
         }
-        result
+        while let Some(x) = store.tree_get(StoreTree::Event, &creator_event_key(peer, height))? {
+            let event = deserialize::<Event<D, P, PK, Sig>>(&x)?;
+            events.push(event.into());
+            height += 1;
+        }
     }
+    events.sort_by(|a, b| a.lamport_timestamp.cmp(&b.lamport_timestamp));
+    Ok(events)
 }
@@ -0,0 +1,358 @@
+// Read-through LRU cache of events and flag tables in front of a DAG store
+
+use crate::errors::{Error, Result};
+use crate::event::Event;
+use crate::event::NetEvent;
+use crate::flag_table::FlagTable;
+use crate::frame::Frame;
+use crate::lamport_time::LamportTime;
+use crate::peer::FrameNumber;
+use crate::peer::GossipList;
+use crate::peer::Height;
+use crate::peer::PeerBan;
+use crate::store::*;
+use crate::store_sled::SledStore;
+use libcommon_rs::data::DataType;
+use libcommon_rs::peer::PeerId;
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Statistics of the store cache
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+// Least recently used entries are evicted once capacity is reached
+struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    // keys by time of last use
+    order: BTreeMap<u64, K>,
+}
+
+impl<K, V> Lru<K, V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+{
+    fn new(capacity: usize) -> Lru<K, V> {
+        Lru {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some((value, used)) => {
+                self.order.remove(&*used);
+                *used = tick;
+                self.order.insert(tick, key.clone());
+                Some(value.clone())
+            }
+            None => None,
+        }
+    }
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(evicted) = self.order.remove(&oldest) {
+                self.entries.remove(&evicted);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+    fn remove(&mut self, key: &K) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+}
+
+struct Cache<Data, P, PK, Sig> {
+    events: Lru<EventHash, Event<Data, P, PK, Sig>>,
+    flag_tables: Lru<EventHash, FlagTable>,
+    // events of pruned frames the store has kept as latest events of creators
+    deferred: HashSet<EventHash>,
+    stats: CacheStats,
+}
+
+pub(crate) struct CachedStore<Data, P, PK, Sig> {
+    store: Box<dyn DAGstore<Data, P, PK, Sig>>,
+    cache: Mutex<Cache<Data, P, PK, Sig>>,
+}
+
+impl<Data, P, PK, Sig> CachedStore<Data, P, PK, Sig>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    // Puts a cache of specified number of events and flag tables in front of the store
+    pub(crate) fn with_store(
+        store: Box<dyn DAGstore<Data, P, PK, Sig>>,
+        capacity: usize,
+    ) -> CachedStore<Data, P, PK, Sig> {
+        CachedStore {
+            store,
+            cache: Mutex::new(Cache {
+                events: Lru::new(capacity),
+                flag_tables: Lru::new(capacity),
+                deferred: HashSet::new(),
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+}
+
+impl<Data, P, PK, Sig> DAGstore<Data, P, PK, Sig> for CachedStore<Data, P, PK, Sig>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    // function new() creates a cached Sled based Storage
+    fn new(path: &Path, options: &StoreOptions) -> Result<CachedStore<Data, P, PK, Sig>> {
+        let store = <SledStore as DAGstore<Data, P, PK, Sig>>::new(path, options)?;
+        Ok(CachedStore::with_store(
+            Box::new(store),
+            options.cache_entries,
+        ))
+    }
+
+    fn set_event(&mut self, e: Event<Data, P, PK, Sig>) -> Result<()> {
+        let hash = e.hash;
+        self.store.set_event(e.clone())?;
+        self.cache.lock().unwrap().events.insert(hash, e);
+        Ok(())
+    }
+
+    fn get_event(&self, ex: &EventHash) -> Result<Event<Data, P, PK, Sig>> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(event) = cache.events.get(ex) {
+                cache.stats.hits += 1;
+                return Ok(event);
+            }
+            cache.stats.misses += 1;
+        }
+        let event = self.store.get_event(ex)?;
+        self.cache.lock().unwrap().events.insert(*ex, event.clone());
+        Ok(event)
+    }
+
+    fn get_event_of_creator(&self, creator: P, height: Height) -> Result<Event<Data, P, PK, Sig>> {
+        self.store.get_event_of_creator(creator, height)
+    }
+
+    fn set_flag_table(&mut self, ex: &EventHash, ft: &FlagTable) -> Result<()> {
+        self.store.set_flag_table(ex, ft)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .flag_tables
+            .insert(*ex, ft.clone());
+        Ok(())
+    }
+
+    fn get_flag_table(&self, ex: &EventHash) -> Result<FlagTable> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(ft) = cache.flag_tables.get(ex) {
+                cache.stats.hits += 1;
+                return Ok(ft);
+            }
+            cache.stats.misses += 1;
+        }
+        let ft = self.store.get_flag_table(ex)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .flag_tables
+            .insert(*ex, ft.clone());
+        Ok(ft)
+    }
+
+    fn set_frame(&mut self, number: FrameNumber, frame: Frame) -> Result<()> {
+        self.store.set_frame(number, frame)
+    }
+
+    fn get_frame(&self, frame: FrameNumber) -> Result<Frame> {
+        self.store.get_frame(frame)
+    }
+
+    fn set_finalised_frame(&mut self, number: FrameNumber, frame: Frame) -> Result<()> {
+        self.store.set_finalised_frame(number, frame)
+    }
+
+    fn get_finalised_frame(&self, frame: FrameNumber) -> Result<Frame> {
+        self.store.get_finalised_frame(frame)
+    }
+
+    fn set_finalised(&mut self, ex: &EventHash, frame: FrameNumber) -> Result<()> {
+        self.store.set_finalised(ex, frame)
+    }
+
+    fn is_finalised(&self, ex: &EventHash) -> Result<bool> {
+        self.store.is_finalised(ex)
+    }
+
+    fn discard_pending(&mut self, ex: &EventHash) {
+        self.store.discard_pending(ex);
+        self.cache.lock().unwrap().flag_tables.remove(ex);
+    }
+
+    fn prune_finalised_frame(&mut self, frame: FrameNumber) -> Result<()> {
+        // a frame pruned already leaves nothing to prune
+        let records = match self.store.get_finalised_frame(frame) {
+            Ok(frame) => frame.events,
+            Err(e) => match e.downcast_ref::<Error>() {
+                Some(Error::NoneError) => return Ok(()),
+                _ => return Err(e),
+            },
+        };
+        self.store.prune_finalised_frame(frame)?;
+        // pruned events must not be served from the cache; events the store
+        // keeps may be pruned along with a later frame
+        let mut cache = self.cache.lock().unwrap();
+        let mut candidates: Vec<EventHash> = records.iter().map(|r| r.hash).collect();
+        candidates.extend(cache.deferred.drain());
+        for ex in candidates.into_iter() {
+            if self.store.get_event(&ex).is_ok() {
+                cache.deferred.insert(ex);
+            } else {
+                cache.events.remove(&ex);
+                cache.flag_tables.remove(&ex);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_ban(&mut self, ban: &PeerBan<P>) -> Result<()> {
+        self.store.set_ban(ban)
+    }
+
+    fn remove_ban(&mut self, peer: &P) -> Result<()> {
+        self.store.remove_ban(peer)
+    }
+
+    fn get_bans(&self) -> Result<Vec<PeerBan<P>>> {
+        self.store.get_bans()
+    }
+
+    fn get_max_lamport_time(&self) -> Result<LamportTime> {
+        self.store.get_max_lamport_time()
+    }
+
+    fn get_events(&self) -> Result<Vec<Event<Data, P, PK, Sig>>> {
+        self.store.get_events()
+    }
+
+    fn get_first_finalised_frame(&self) -> Result<Option<FrameNumber>> {
+        self.store.get_first_finalised_frame()
+    }
+
+    fn get_last_finalised_frame(&self) -> Result<Option<FrameNumber>> {
+        self.store.get_last_finalised_frame()
+    }
+
+    fn get_events_for_gossip(
+        &self,
+        gossip: &GossipList<P>,
+    ) -> Result<Vec<NetEvent<Data, P, PK, Sig>>> {
+        self.store.get_events_for_gossip(gossip)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.cache.lock().unwrap().stats.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameRecord;
+    use crate::hlc::HybridTime;
+    use crate::test_utils::{mem_store, Data, Id, RecordedDag, Sig};
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let mut lru: Lru<usize, &str> = Lru::new(2);
+        lru.insert(1, "a");
+        lru.insert(2, "b");
+        assert_eq!(lru.get(&1), Some("a"));
+        lru.insert(3, "c");
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some("a"));
+        assert_eq!(lru.get(&3), Some("c"));
+        lru.remove(&1);
+        assert_eq!(lru.get(&1), None);
+        // a cache of no capacity keeps nothing
+        let mut disabled: Lru<usize, &str> = Lru::new(0);
+        disabled.insert(1, "a");
+        assert_eq!(disabled.get(&1), None);
+    }
+
+    #[test]
+    fn test_hits_misses_and_pruned_events() {
+        let mut dag = RecordedDag::new(2);
+        dag.gossip(2);
+        let mut store = CachedStore::<Data, Id, Id, Sig>::with_store(Box::new(mem_store()), 1);
+        for event in dag.events.iter() {
+            store.set_event(event.clone()).unwrap();
+        }
+        // the first event of creator 0 is not its latest one
+        let first = dag.events[2].clone();
+        assert_eq!(store.get_event(&first.hash).unwrap(), first);
+        assert_eq!(store.get_event(&first.hash).unwrap(), first);
+        assert_eq!(store.cache_stats(), Some(CacheStats { hits: 1, misses: 1 }));
+        let record = FrameRecord {
+            hash: first.hash,
+            lamport_time: first.lamport_timestamp,
+            timestamp: first.timestamp,
+            received_time: None,
+            consensus_timestamp: HybridTime::default(),
+        };
+        store
+            .set_finalised_frame(
+                0,
+                Frame {
+                    events: vec![record],
+                    tx_order: Vec::new(),
+                },
+            )
+            .unwrap();
+        store.prune_finalised_frame(0).unwrap();
+        assert!(store.get_event(&first.hash).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+// In-memory DAG store; keeps the same records as SledStore, serialized
+// in the same way, but in memory only
+
+use crate::errors::{Error, Result};
+use crate::event::Event;
+use crate::event::NetEvent;
+use crate::flag_table::FlagTable;
+use crate::frame::Frame;
+use crate::frame::FrameRecord;
+use crate::hlc::HybridTime;
+use crate::lamport_time::LamportTime;
+use crate::peer::FrameNumber;
+use crate::peer::GossipList;
+use crate::peer::Height;
+use crate::peer::PeerBan;
+use crate::store::*;
+use bincode::{deserialize, serialize};
+use libcommon_rs::data::DataType;
+use libcommon_rs::peer::PeerId;
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+use std::collections::BTreeMap;
+use std::path::Path;
+use to_vec::ToVec;
+
+// ordered by key like sled trees
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// Removes all keys starting with the prefix
+fn remove_prefix(tree: &mut Tree, prefix: &[u8]) {
+    let keys: Vec<Vec<u8>> = tree
+        .range(prefix.to_vec()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in keys.iter() {
+        tree.remove(key);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct MemStore {
+    event: Tree,
+    flag_table: Tree,
+    frame: Tree,
+    finalised_frame: Tree,
+    finalised: Tree,
+    // the lowest height of events of each creator kept after pruning
+    first_height: Tree,
+    // latest events of creators kept when their frames were pruned
+    prune_deferred: Tree,
+    ban: Tree,
+}
+
+impl MemStore {
+    fn tree(&self, tree: StoreTree) -> &Tree {
+        match tree {
+            StoreTree::Event => &self.event,
+            StoreTree::FlagTable => &self.flag_table,
+            StoreTree::Frame => &self.frame,
+            StoreTree::FinalisedFrame => &self.finalised_frame,
+            StoreTree::FirstHeight => &self.first_height,
+            StoreTree::PruneDeferred => &self.prune_deferred,
+        }
+    }
+
+    fn tree_mut(&mut self, tree: StoreTree) -> &mut Tree {
+        match tree {
+            StoreTree::Event => &mut self.event,
+            StoreTree::FlagTable => &mut self.flag_table,
+            StoreTree::Frame => &mut self.frame,
+            StoreTree::FinalisedFrame => &mut self.finalised_frame,
+            StoreTree::FirstHeight => &mut self.first_height,
+            StoreTree::PruneDeferred => &mut self.prune_deferred,
+        }
+    }
+}
+
+// changes cannot fail once they are applied, so no transaction is needed
+impl KeyValueStore for MemStore {
+    fn tree_get(&self, tree: StoreTree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree(tree).get(key).cloned())
+    }
+
+    fn tree_values(&self, tree: StoreTree) -> Result<Vec<Vec<u8>>> {
+        Ok(self.tree(tree).values().cloned().collect())
+    }
+
+    fn tree_apply(&mut self, batch: TreeBatch) -> Result<()> {
+        for (tree, key, value) in batch.changes.into_iter() {
+            match value {
+                Some(value) => self.tree_mut(tree).insert(key, value),
+                None => self.tree_mut(tree).remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<P, D, PK, Sig> DAGstore<D, P, PK, Sig> for MemStore
+where
+    D: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    // function new() creates a new in-memory Storage; the path and options are ignored
+    fn new(_base_path: &Path, _options: &StoreOptions) -> Result<MemStore> {
+        Ok(MemStore::default())
+    }
+
+    fn set_event(&mut self, e: Event<D, P, PK, Sig>) -> Result<()> {
+        let e_bytes = serialize(&e)?;
+        self.event.insert(e.hash.clone().to_vec(), e_bytes.clone());
+        let key2 = format!("{}-{}", e.creator, e.height).into_bytes();
+        self.event.insert(key2, e_bytes);
+        let record = FrameRecord {
+            hash: e.get_hash(),
+            lamport_time: e.lamport_timestamp,
+            timestamp: e.timestamp,
+            received_time: None,
+            consensus_timestamp: HybridTime::default(),
+        };
+        self.frame.insert(
+            frame_record_key(e.frame_number, &e.hash),
+            serialize(&record)?,
+        );
+        Ok(())
+    }
+
+    fn get_event(&self, ex: &EventHash) -> Result<Event<D, P, PK, Sig>> {
+        match self.event.get(&ex.to_vec()) {
+            Some(x) => Ok(deserialize::<Event<D, P, PK, Sig>>(x)?),
+            None => Err(Error::NoneError.into()),
+        }
+    }
+
+    fn set_frame(&mut self, frame_number: FrameNumber, frame: Frame) -> Result<()> {
+        remove_prefix(&mut self.frame, &frame_key(frame_number));
+        for record in frame.events.iter() {
+            self.frame.insert(
+                frame_record_key(frame_number, &record.hash),
+                serialize(record)?,
+            );
+        }
+        Ok(())
+    }
+
+    fn get_frame(&self, frame_number: FrameNumber) -> Result<Frame> {
+        let prefix = frame_key(frame_number);
+        let mut frame = Frame::default();
+        for (_, value) in self
+            .frame
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+        {
+            frame.events.push(deserialize::<FrameRecord>(value)?);
+        }
+        Ok(frame)
+    }
+
+    fn set_finalised_frame(&mut self, frame_number: FrameNumber, frame: Frame) -> Result<()> {
+        let f_bytes = serialize(&frame)?;
+        let n_bytes = serialize(&frame_number)?;
+        for record in frame.events.iter() {
+            self.finalised.insert(record.hash.to_vec(), n_bytes.clone());
+        }
+        self.finalised_frame
+            .insert(frame_key(frame_number), f_bytes);
+        Ok(())
+    }
+
+    fn get_finalised_frame(&self, frame_number: FrameNumber) -> Result<Frame> {
+        match self.finalised_frame.get(&frame_key(frame_number)) {
+            Some(x) => Ok(deserialize::<Frame>(x)?),
+            None => Err(Error::NoneError.into()),
+        }
+    }
+
+    fn set_finalised(&mut self, ex: &EventHash, frame_number: FrameNumber) -> Result<()> {
+        self.finalised
+            .insert(ex.clone().to_vec(), serialize(&frame_number)?);
+        Ok(())
+    }
+
+    fn is_finalised(&self, ex: &EventHash) -> Result<bool> {
+        Ok(self.finalised.contains_key(&ex.to_vec()))
+    }
+
+    // flag tables of events not stored yet are written directly
+    fn discard_pending(&mut self, ex: &EventHash) {
+        let key = ex.to_vec();
+        if !self.event.contains_key(&key) {
+            self.flag_table.remove(&key);
+        }
+    }
+
+    fn set_flag_table(&mut self, ex: &EventHash, ft: &FlagTable) -> Result<()> {
+        self.flag_table.insert(ex.clone().to_vec(), serialize(&ft)?);
+        Ok(())
+    }
+
+    fn get_flag_table(&self, ex: &EventHash) -> Result<FlagTable> {
+        match self.flag_table.get(&ex.to_vec()) {
+            Some(x) => Ok(deserialize::<FlagTable>(x)?),
+            None => Err(Error::NoneError.into()),
+        }
+    }
+
+    fn get_event_of_creator(&self, creator: P, height: Height) -> Result<Event<D, P, PK, Sig>> {
+        let key = format!("{}-{}", creator, height).into_bytes();
+        match self.event.get(&key) {
+            Some(x) => Ok(deserialize::<Event<D, P, PK, Sig>>(x)?),
+            None => Err(Error::NoneError.into()),
+        }
+    }
+
+    fn prune_finalised_frame(&mut self, frame_number: FrameNumber) -> Result<()> {
+        prune_frame::<D, P, PK, Sig>(self, frame_number)
+    }
+
+    fn set_ban(&mut self, ban: &PeerBan<P>) -> Result<()> {
+        self.ban.insert(serialize(&ban.peer)?, serialize(ban)?);
+        Ok(())
+    }
+
+    fn remove_ban(&mut self, peer: &P) -> Result<()> {
+        self.ban.remove(&serialize(peer)?);
+        Ok(())
+    }
+
+    fn get_bans(&self) -> Result<Vec<PeerBan<P>>> {
+        let mut bans = Vec::new();
+        for value in self.ban.values() {
+            bans.push(deserialize::<PeerBan<P>>(value)?);
+        }
+        Ok(bans)
+    }
+
+    fn get_max_lamport_time(&self) -> Result<LamportTime> {
+        let mut max = LamportTime::default();
+        for value in self.event.values() {
+            let e = deserialize::<Event<D, P, PK, Sig>>(value)?;
+            max = max.max(e.lamport_timestamp);
+        }
+        Ok(max)
+    }
+
+    fn get_events(&self) -> Result<Vec<Event<D, P, PK, Sig>>> {
+        let mut events = Vec::new();
+        for (key, value) in self.event.iter() {
+            let e = deserialize::<Event<D, P, PK, Sig>>(value)?;
+            // events are stored by hash and by creator and height
+            if *key == e.hash.to_vec() {
+                events.push(e);
+            }
+        }
+        Ok(events)
+    }
+
+    fn get_first_finalised_frame(&self) -> Result<Option<FrameNumber>> {
+        Ok(self.finalised_frame.keys().next().map(|key| key_frame(key)))
+    }
+
+    fn get_last_finalised_frame(&self) -> Result<Option<FrameNumber>> {
+        Ok(self
+            .finalised_frame
+            .keys()
+            .next_back()
+            .map(|key| key_frame(key)))
+    }
+
+    fn get_events_for_gossip(
+        &self,
+        gossip: &GossipList<P>,
+    ) -> Result<Vec<NetEvent<D, P, PK, Sig>>> {
+        events_for_gossip(self, gossip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{check_pruning, mem_store};
+
+    #[test]
+    fn test_pruning_defers_latest_events() {
+        check_pruning(&mut mem_store());
+    }
+}
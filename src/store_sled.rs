@@ -4,9 +4,13 @@ use crate::event::Event;
 use crate::event::NetEvent;
 use crate::frame::Frame;
 use crate::frame::FrameRecord;
+use crate::hlc::HybridTime;
+use crate::lamport_time::LamportTime;
 use crate::peer::FrameNumber;
 use crate::peer::GossipList;
 use crate::peer::Height;
+use crate::peer::PeerBan;
+use crate::schema::{check_layout, check_schema, verify_schema};
 use crate::store::*;
 use bincode::{deserialize, serialize};
 use libcommon_rs::data::DataType;
@@ -17,14 +21,197 @@ use crate::errors::{Error, Result};
 use crate::flag_table::FlagTable;
 use libcommon_rs::peer::PeerId;
 
+use sled::Transactional;
+use std::collections::HashMap;
 use std::path::Path;
 use to_vec::ToVec;
 
+// All data is kept in named trees of a single database, so an event, its flag
+// table and its frame record can be written in one transaction.
 pub(crate) struct SledStore {
-    event: sled::Db,
-    flag_table: sled::Db,
-    frame: sled::Db,
-    sync: bool,
+    db: sled::Db,
+    event: sled::Tree,
+    flag_table: sled::Tree,
+    frame: sled::Tree,
+    finalised_frame: sled::Tree,
+    finalised: sled::Tree,
+    // the lowest height of events of each creator kept after pruning
+    first_height: sled::Tree,
+    // latest events of creators kept when their frames were pruned
+    prune_deferred: sled::Tree,
+    ban: sled::Tree,
+    // flag tables of events not stored yet; written along with the event
+    pending_flag_table: HashMap<EventHash, Vec<u8>>,
+    durability: Durability,
+}
+
+impl SledStore {
+    // Flushes the database if every write has to be durable
+    fn flush_if_sync(&self) -> Result<()> {
+        if self.durability == Durability::EveryWrite {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    // Returns all entries of the events tree: events keyed by hash and
+    // by creator and height
+    pub(crate) fn event_entries<D, P, PK, Sig>(
+        &self,
+    ) -> Result<Vec<(Vec<u8>, Event<D, P, PK, Sig>)>>
+    where
+        D: DataType,
+        P: PeerId,
+        PK: PublicKey,
+        Sig: Signature<Hash = EventHash, PublicKey = PK>,
+    {
+        let mut entries = Vec::new();
+        for item in self.event.iter() {
+            let (key, value) = item?;
+            entries.push((key.to_vec(), deserialize::<Event<D, P, PK, Sig>>(&value)?));
+        }
+        Ok(entries)
+    }
+
+    // Returns all frame records with frame numbers
+    pub(crate) fn frame_records(&self) -> Result<Vec<(FrameNumber, FrameRecord)>> {
+        let mut records = Vec::new();
+        for item in self.frame.iter() {
+            let (key, value) = item?;
+            let mut number = [0u8; 8];
+            number.copy_from_slice(&key[..8]);
+            records.push((
+                u64::from_be_bytes(number) as FrameNumber,
+                deserialize::<FrameRecord>(&value)?,
+            ));
+        }
+        Ok(records)
+    }
+
+    pub(crate) fn set_frame_record(
+        &mut self,
+        frame_number: FrameNumber,
+        record: &FrameRecord,
+    ) -> Result<()> {
+        self.frame.insert(
+            frame_record_key(frame_number, &record.hash),
+            serialize(record)?,
+        )?;
+        self.flush_if_sync()
+    }
+
+    pub(crate) fn remove_frame_record(
+        &mut self,
+        frame_number: FrameNumber,
+        ex: &EventHash,
+    ) -> Result<()> {
+        self.frame.remove(frame_record_key(frame_number, ex))?;
+        self.flush_if_sync()
+    }
+
+    // Writes an entry of the events tree as is
+    pub(crate) fn set_event_entry(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.event.insert(key, value)?;
+        self.flush_if_sync()
+    }
+
+    pub(crate) fn has_flag_table(&self, ex: &EventHash) -> Result<bool> {
+        Ok(self.flag_table.contains_key(ex.to_vec())?)
+    }
+
+    // Checks if events have been pruned from the store
+    pub(crate) fn is_pruned(&self) -> bool {
+        !self.first_height.is_empty()
+    }
+
+    // Opens the store of a node which is not running for reading only;
+    // the store is not upgraded, so it has to be of the current schema version
+    pub(crate) fn open_read_only(base_path: &Path, options: &StoreOptions) -> Result<SledStore> {
+        check_layout(base_path)?;
+        let db = sled::Config::new()
+            .path(base_path)
+            .cache_capacity(options.cache_capacity)
+            .read_only(true)
+            .open()?;
+        verify_schema(&db)?;
+        SledStore::with_trees(db, Durability::Finalisation)
+    }
+
+    // Opens the trees of the database
+    fn with_trees(db: sled::Db, durability: Durability) -> Result<SledStore> {
+        Ok(SledStore {
+            event: db.open_tree("events")?,
+            flag_table: db.open_tree("flag_tables")?,
+            frame: db.open_tree("frames")?,
+            finalised_frame: db.open_tree("finalised_frames")?,
+            finalised: db.open_tree("finalised")?,
+            first_height: db.open_tree("first_heights")?,
+            prune_deferred: db.open_tree("prune_deferred")?,
+            ban: db.open_tree("bans")?,
+            db,
+            pending_flag_table: HashMap::new(),
+            durability,
+        })
+    }
+}
+
+impl SledStore {
+    fn tree(&self, tree: StoreTree) -> &sled::Tree {
+        match tree {
+            StoreTree::Event => &self.event,
+            StoreTree::FlagTable => &self.flag_table,
+            StoreTree::Frame => &self.frame,
+            StoreTree::FinalisedFrame => &self.finalised_frame,
+            StoreTree::FirstHeight => &self.first_height,
+            StoreTree::PruneDeferred => &self.prune_deferred,
+        }
+    }
+}
+
+impl KeyValueStore for SledStore {
+    fn tree_get(&self, tree: StoreTree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree(tree).get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn tree_values(&self, tree: StoreTree) -> Result<Vec<Vec<u8>>> {
+        let mut values = Vec::new();
+        for item in self.tree(tree).iter() {
+            let (_, value) = item?;
+            values.push(value.to_vec());
+        }
+        Ok(values)
+    }
+
+    fn tree_apply(&mut self, batch: TreeBatch) -> Result<()> {
+        (
+            &self.event,
+            &self.flag_table,
+            &self.frame,
+            &self.finalised_frame,
+            &self.first_height,
+            &self.prune_deferred,
+        )
+            .transaction(
+                |(events, flag_tables, frames, finalised_frames, first_height, prune_deferred)| {
+                    for (tree, key, value) in batch.changes.iter() {
+                        let tree = match tree {
+                            StoreTree::Event => events,
+                            StoreTree::FlagTable => flag_tables,
+                            StoreTree::Frame => frames,
+                            StoreTree::FinalisedFrame => finalised_frames,
+                            StoreTree::FirstHeight => first_height,
+                            StoreTree::PruneDeferred => prune_deferred,
+                        };
+                        match value {
+                            Some(value) => tree.insert(key.clone(), value.clone())?,
+                            None => tree.remove(key.clone())?,
+                        };
+                    }
+                    Ok(())
+                },
+            )?;
+        self.flush_if_sync()
+    }
 }
 
 impl<P, D, PK, Sig> DAGstore<D, P, PK, Sig> for SledStore
@@ -35,53 +222,54 @@ where
     Sig: Signature<Hash = EventHash, PublicKey = PK>,
 {
     // function new() creates a new Sled based Storage
-    fn new(base_path: &Path) -> Result<SledStore> {
-        let event_config = sled::Config::new()
-            .path(base_path.join("events"))
-            .print_profile_on_drop(true);
-        let ft_config = sled::Config::new()
-            .path(base_path.join("flag_tables"))
-            .print_profile_on_drop(true); // if true, gives summary of latency histograms
-        let frame_config = sled::Config::new()
-            .path(base_path.join("frames"))
-            .print_profile_on_drop(true); // if true, gives summary of latency histograms
-
-        Ok(SledStore {
-            event: event_config.open()?,
-            flag_table: ft_config.open()?,
-            frame: frame_config.open()?,
-            sync: true, // let be synchronous in writing, though it's slow
-        })
+    fn new(base_path: &Path, options: &StoreOptions) -> Result<SledStore> {
+        // background flushes are needed for group commit only
+        let flush_every_ms = match options.durability {
+            Durability::Interval(ms) => Some(ms),
+            _ => None,
+        };
+        check_layout(base_path)?;
+        let db = sled::Config::new()
+            .path(base_path)
+            .cache_capacity(options.cache_capacity)
+            .flush_every_ms(flush_every_ms)
+            .print_profile_on_drop(options.profile) // if true, gives summary of latency histograms
+            .open()?;
+        check_schema(&db)?;
+        SledStore::with_trees(db, options.durability.clone())
     }
 
-    // function set_event() writes Event into storage
+    // function set_event() writes Event into storage along with its pending
+    // flag table and its frame record in a single transaction
     fn set_event(&mut self, e: Event<D, P, PK, Sig>) -> Result<()> {
         let e_bytes = serialize(&e)?;
         // Store serialized event with hash as a key.
         let key = e.hash.clone().to_vec();
-        self.event.insert(key, e_bytes.clone())?;
         // Store serialized event with creator and creator's height as a key.
         let key2 = format!("{}-{}", e.creator, e.height).into_bytes();
-        self.event.insert(key2, e_bytes)?;
-        if self.sync {
-            self.event.flush()?;
-        }
-        let frame_key = format!("{}", e.frame_number).into_bytes();
-        let mut frame: Frame = match self.frame.get(&*frame_key)? {
-            Some(x) => deserialize::<Frame>(&x)?,
-            None => Frame::default(),
-        };
+        // frame membership is kept as a record per event
+        let record_key = frame_record_key(e.frame_number, &e.hash);
         let record = FrameRecord {
             hash: e.get_hash(),
             lamport_time: e.lamport_timestamp,
+            timestamp: e.timestamp,
+            received_time: None,
+            consensus_timestamp: HybridTime::default(),
         };
-        frame.events.push(record);
-        let f_bytes = serialize(&frame)?;
-        self.frame.insert(frame_key, f_bytes)?;
-        if self.sync {
-            self.frame.flush()?;
-        }
-        Ok(())
+        let r_bytes = serialize(&record)?;
+        let ft_bytes = self.pending_flag_table.remove(&e.hash);
+        (&self.event, &self.flag_table, &self.frame).transaction(
+            |(events, flag_tables, frames)| {
+                events.insert(key.clone(), e_bytes.clone())?;
+                events.insert(key2.clone(), e_bytes.clone())?;
+                if let Some(ref ft_bytes) = ft_bytes {
+                    flag_tables.insert(key.clone(), ft_bytes.clone())?;
+                }
+                frames.insert(record_key.clone(), r_bytes.clone())?;
+                Ok(())
+            },
+        )?;
+        self.flush_if_sync()
     }
 
     fn get_event(&self, ex: &EventHash) -> Result<Event<D, P, PK, Sig>> {
@@ -93,34 +281,88 @@ where
     }
 
     fn set_frame(&mut self, frame_number: FrameNumber, frame: Frame) -> Result<()> {
-        let f_bytes = serialize(&frame)?;
-        let frame_key = format!("{}", frame_number).into_bytes();
-        self.frame.insert(frame_key, f_bytes)?;
-        if self.sync {
-            self.frame.flush()?;
+        let mut batch = sled::Batch::default();
+        for item in self.frame.scan_prefix(frame_key(frame_number)) {
+            let (key, _) = item?;
+            batch.remove(key);
         }
-        Ok(())
+        for record in frame.events.iter() {
+            batch.insert(
+                frame_record_key(frame_number, &record.hash),
+                serialize(record)?,
+            );
+        }
+        self.frame.apply_batch(batch)?;
+        self.flush_if_sync()
     }
 
     fn get_frame(&self, frame_number: FrameNumber) -> Result<Frame> {
-        let frame_key = format!("{}", frame_number).into_bytes();
-        match self.frame.get(&*frame_key)? {
+        let mut frame = Frame::default();
+        for item in self.frame.scan_prefix(frame_key(frame_number)) {
+            let (_, value) = item?;
+            frame.events.push(deserialize::<FrameRecord>(&value)?);
+        }
+        Ok(frame)
+    }
+
+    // finalised frames are stored whole as they carry the final order
+    fn set_finalised_frame(&mut self, frame_number: FrameNumber, frame: Frame) -> Result<()> {
+        let key = frame_key(frame_number);
+        let f_bytes = serialize(&frame)?;
+        let n_bytes = serialize(&frame_number)?;
+        let marks: Vec<Vec<u8>> = frame.events.iter().map(|r| r.hash.to_vec()).collect();
+        (&self.finalised, &self.finalised_frame).transaction(|(finalised, finalised_frames)| {
+            for mark in marks.iter() {
+                finalised.insert(mark.clone(), n_bytes.clone())?;
+            }
+            finalised_frames.insert(key.clone(), f_bytes.clone())?;
+            Ok(())
+        })?;
+        if self.durability == Durability::Finalisation {
+            self.db.flush()?;
+        }
+        self.flush_if_sync()
+    }
+
+    fn get_finalised_frame(&self, frame_number: FrameNumber) -> Result<Frame> {
+        let frame_key = frame_key(frame_number);
+        match self.finalised_frame.get(&*frame_key)? {
             Some(x) => Ok(deserialize::<Frame>(&x)?),
-            None => Ok(Frame::default()),
+            None => Err(Error::NoneError.into()),
         }
     }
 
+    fn set_finalised(&mut self, ex: &EventHash, frame_number: FrameNumber) -> Result<()> {
+        let key = ex.clone().to_vec();
+        self.finalised.insert(key, serialize(&frame_number)?)?;
+        self.flush_if_sync()
+    }
+
+    fn is_finalised(&self, ex: &EventHash) -> Result<bool> {
+        let key = ex.to_vec();
+        Ok(self.finalised.contains_key(&*key)?)
+    }
+
+    fn discard_pending(&mut self, ex: &EventHash) {
+        self.pending_flag_table.remove(ex);
+    }
+
     fn set_flag_table(&mut self, ex: &EventHash, ft: &FlagTable) -> Result<()> {
         let key = ex.clone().to_vec();
         let e_bytes = serialize(&ft)?;
-        self.flag_table.insert(key, e_bytes)?;
-        if self.sync {
-            self.flag_table.flush()?;
+        // flag table of an event not stored yet is written by set_event()
+        if !self.event.contains_key(&*key)? {
+            self.pending_flag_table.insert(ex.clone(), e_bytes);
+            return Ok(());
         }
-        Ok(())
+        self.flag_table.insert(key, e_bytes)?;
+        self.flush_if_sync()
     }
 
     fn get_flag_table(&self, ex: &EventHash) -> Result<FlagTable> {
+        if let Some(x) = self.pending_flag_table.get(ex) {
+            return Ok(deserialize::<FlagTable>(x)?);
+        }
         let key = ex.to_vec();
         match self.flag_table.get(&*key)? {
             Some(x) => Ok(deserialize::<FlagTable>(&x)?),
@@ -137,39 +379,160 @@ where
         }
     }
 
+    fn prune_finalised_frame(&mut self, frame_number: FrameNumber) -> Result<()> {
+        prune_frame::<D, P, PK, Sig>(self, frame_number)
+    }
+
+    fn set_ban(&mut self, ban: &PeerBan<P>) -> Result<()> {
+        let key = serialize(&ban.peer)?;
+        let b_bytes = serialize(ban)?;
+        self.ban.insert(key, b_bytes)?;
+        self.flush_if_sync()
+    }
+
+    fn remove_ban(&mut self, peer: &P) -> Result<()> {
+        let key = serialize(peer)?;
+        self.ban.remove(key)?;
+        self.flush_if_sync()
+    }
+
+    fn get_bans(&self) -> Result<Vec<PeerBan<P>>> {
+        let mut bans = Vec::new();
+        for item in self.ban.iter() {
+            let (_, value) = item?;
+            bans.push(deserialize::<PeerBan<P>>(&value)?);
+        }
+        Ok(bans)
+    }
+
+    fn get_max_lamport_time(&self) -> Result<LamportTime> {
+        let mut max = LamportTime::default();
+        for item in self.event.iter() {
+            let (_, value) = item?;
+            let e = deserialize::<Event<D, P, PK, Sig>>(&value)?;
+            max = max.max(e.lamport_timestamp);
+        }
+        Ok(max)
+    }
+
+    fn get_events(&self) -> Result<Vec<Event<D, P, PK, Sig>>> {
+        let mut events = Vec::new();
+        for item in self.event.iter() {
+            let (key, value) = item?;
+            let e = deserialize::<Event<D, P, PK, Sig>>(&value)?;
+            // events are stored by hash and by creator and height
+            if *key == *e.hash.to_vec() {
+                events.push(e);
+            }
+        }
+        Ok(events)
+    }
+
+    fn get_first_finalised_frame(&self) -> Result<Option<FrameNumber>> {
+        match self.finalised_frame.iter().next() {
+            Some(item) => Ok(Some(key_frame(&item?.0))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_last_finalised_frame(&self) -> Result<Option<FrameNumber>> {
+        match self.finalised_frame.iter().next_back() {
+            Some(item) => Ok(Some(key_frame(&item?.0))),
+            None => Ok(None),
+        }
+    }
+
     fn get_events_for_gossip(
         &self,
         gossip: &GossipList<P>,
     ) -> Result<Vec<NetEvent<D, P, PK, Sig>>> {
-        let mut events: Vec<NetEvent<D, P, PK, Sig>> = Vec::with_capacity(1);
-        for (peer, gossip) in gossip.iter() {
-            let mut height = gossip.height + 1;
-            loop {
-                debug!("get_events_for_gossip: {} height {}", peer, height);
-                let event = match self.get_event_of_creator(peer.clone(), height.clone()) {
-                    Err(e) => match e.downcast::<Error>() {
-                        Ok(err) => {
-                            debug!("err: {}", err);
-                            break;
-                            // FIXME: following code crashes with stack overflow panic
-                            //                            if err == Error::NoneError {
-                            //                                debug!("NONE ERROR");
-                            //                                break;
-                            //                            } else {
-                            //                                return Err(err.into());
-                            //                            }
-                        }
-                        Err(erx) => return Err(erx),
-                    },
-                    Ok(event) => event,
-                };
-
-                events.push(event.into());
-                height += 1;
-            }
-        }
-        events.sort_by(|a, b| a.lamport_timestamp.cmp(&b.lamport_timestamp));
+        let events = events_for_gossip(self, gossip)?;
         debug!("got events for gossip: {}", events.len());
         Ok(events)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{check_pruning, temp_store_dir, Data, Id, RecordedDag, Sig};
+
+    #[test]
+    fn test_max_lamport_time_restored_on_open() {
+        let path = temp_store_dir("sled-max-lamport-time");
+        let mut dag = RecordedDag::new(3);
+        dag.gossip(4);
+        let max = dag
+            .events
+            .iter()
+            .map(|e| e.lamport_timestamp)
+            .max()
+            .unwrap();
+        {
+            let mut store =
+                <SledStore as DAGstore<Data, Id, Id, Sig>>::new(&path, &StoreOptions::default())
+                    .unwrap();
+            for event in dag.events.iter() {
+                DAGstore::<Data, Id, Id, Sig>::set_event(&mut store, event.clone()).unwrap();
+            }
+        }
+        let store =
+            <SledStore as DAGstore<Data, Id, Id, Sig>>::new(&path, &StoreOptions::default())
+                .unwrap();
+        assert_eq!(
+            DAGstore::<Data, Id, Id, Sig>::get_max_lamport_time(&store).unwrap(),
+            max
+        );
+    }
+
+    #[test]
+    fn test_pruning_defers_latest_events() {
+        let path = temp_store_dir("sled-pruning");
+        let mut store =
+            <SledStore as DAGstore<Data, Id, Id, Sig>>::new(&path, &StoreOptions::default())
+                .unwrap();
+        check_pruning(&mut store);
+        assert!(store.is_pruned());
+    }
+
+    #[test]
+    fn test_baseline_store_refused() {
+        let path = temp_store_dir("sled-baseline");
+        // the baseline layout: a database per record kind
+        for name in ["events", "flag_tables", "frames"].iter() {
+            let db = sled::Config::new().path(path.join(name)).open().unwrap();
+            db.insert(b"key", b"value".to_vec()).unwrap();
+            db.flush().unwrap();
+        }
+        let err = match <SledStore as DAGstore<Data, Id, Id, Sig>>::new(
+            &path,
+            &StoreOptions::default(),
+        ) {
+            Ok(_) => panic!("store of the baseline layout is opened"),
+            Err(e) => e,
+        };
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::LegacyStoreLayout(path.display().to_string()))
+        );
+        // the store is left as it was
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_pending_flag_table_discarded() {
+        let path = temp_store_dir("sled-pending-flag-table");
+        let mut store =
+            <SledStore as DAGstore<Data, Id, Id, Sig>>::new(&path, &StoreOptions::default())
+                .unwrap();
+        let mut dag = RecordedDag::new(2);
+        let event = dag.add(0, 1, Vec::new());
+        DAGstore::<Data, Id, Id, Sig>::set_flag_table(&mut store, &event.hash, &FlagTable::new(2))
+            .unwrap();
+        DAGstore::<Data, Id, Id, Sig>::discard_pending(&mut store, &event.hash);
+        assert!(DAGstore::<Data, Id, Id, Sig>::get_flag_table(&store, &event.hash).is_err());
+        // the event stored later carries no stale flag table
+        DAGstore::<Data, Id, Id, Sig>::set_event(&mut store, event.clone()).unwrap();
+        assert!(DAGstore::<Data, Id, Id, Sig>::get_flag_table(&store, &event.hash).is_err());
+    }
+}
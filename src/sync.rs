@@ -1,6 +1,7 @@
 use crate::event::NetEvent;
+use crate::frame::Frame;
 use crate::lamport_time::LamportTime;
-use crate::peer::GossipList;
+use crate::peer::{FrameNumber, GossipList};
 use core::fmt::Display;
 use core::fmt::Formatter;
 use core::hash::Hash;
@@ -11,18 +12,63 @@ use libhash_sha3::Hash as EventHash;
 use libsignature::PublicKey;
 use libsignature::Signature;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Sync request identifier; used to match Sync Reply with Sync Request
+pub(crate) type RequestId = u64;
+
+// Status of Sync Reply
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SyncStatus {
+    // reply carries events requested
+    Ok,
+    // replying node has hit its rate limits; reply carries no events
+    Busy,
+    // Lamport time of the request is beyond the bound of the replying node,
+    // which is behind and has to catch up; reply carries no events
+    Lagging,
+    // replying node has pruned the finalised frames requested in catch-up
+    // mode; reply carries no frames and the frames are requested from
+    // another peer
+    Pruned,
+}
+
+impl Display for SyncStatus {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
 
 // Sync request
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SyncReq<P: Hash + Eq> {
+    pub(crate) request_id: RequestId,
     pub(crate) from: P,
     pub(crate) to: P,
     pub(crate) gossip_list: GossipList<P>,
     pub(crate) lamport_time: LamportTime,
+    // Some() in catch-up mode: finalised frames are requested from the frame on
+    // instead of events for gossip
+    pub(crate) catch_up: Option<FrameNumber>,
 }
 
 impl<P> Stub for SyncReq<P> where P: PeerId {}
 
+// Finalised frame sent in catch-up mode along with its events
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CatchUpFrame<Data, P: Hash + Eq, PK, Sig>
+where
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    pub(crate) frame: FrameNumber,
+    pub(crate) records: Frame,
+    // events of the frame records
+    #[serde(bound(deserialize = "Data: Deserialize<'de>"))]
+    pub(crate) events: Vec<NetEvent<Data, P, PK, Sig>>,
+}
+
 // Sync Reply
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SyncReply<Data, P: Hash + Eq, PK, Sig>
@@ -30,12 +76,21 @@ where
     PK: PublicKey,
     Sig: Signature<Hash = EventHash, PublicKey = PK>,
 {
+    // ID of the Sync Request this is the reply to
+    pub(crate) request_id: RequestId,
+    pub(crate) status: SyncStatus,
     pub(crate) from: P,
     pub(crate) to: P,
     pub(crate) gossip_list: GossipList<P>,
     pub(crate) lamport_time: LamportTime,
     #[serde(bound(deserialize = "Data: Deserialize<'de>"))]
     pub(crate) events: Vec<NetEvent<Data, P, PK, Sig>>,
+    // catch-up frame of the Sync Request; None for replies to gossip
+    pub(crate) catch_up: Option<FrameNumber>,
+    // finalised frames starting from the catch-up frame; no more frames
+    // available when empty
+    #[serde(bound(deserialize = "Data: Deserialize<'de>"))]
+    pub(crate) frames: Vec<CatchUpFrame<Data, P, PK, Sig>>,
 }
 
 impl<'a, Data, P, PK, Sig> Stub for SyncReply<Data, P, PK, Sig>
@@ -54,7 +109,8 @@ where
     fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
         let mut formatted = String::new();
         formatted.push_str(&format!(
-            "from:{}; to:{}; lamport_time:{}; gossip_list:[",
+            "request_id:{}; from:{}; to:{}; lamport_time:{}; gossip_list:[",
+            self.request_id,
             self.from.clone(),
             self.to.clone(),
             self.lamport_time
@@ -63,6 +119,9 @@ where
             formatted.push_str(&format!("({}:{})", k, v));
         }
         formatted.push_str("]");
+        if let Some(frame) = self.catch_up {
+            formatted.push_str(&format!("; catch_up:{}", frame));
+        }
         write!(f, "{}", formatted)
     }
 }
@@ -77,7 +136,8 @@ where
     fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
         let mut formatted = String::new();
         formatted.push_str(&format!(
-            "from:{}; to:{}; lamport_time:{}; gossip_list:[",
+            "request_id:{}; from:{}; to:{}; lamport_time:{}; gossip_list:[",
+            self.request_id,
             self.from.clone(),
             self.to.clone(),
             self.lamport_time
@@ -90,6 +150,241 @@ where
             formatted.push_str(&format!("({})", e));
         }
         formatted.push_str("]");
+        if let Some(frame) = self.catch_up {
+            formatted.push_str(&format!("; catch_up:{}; frames:[", frame));
+            for c in self.frames.iter() {
+                formatted.push_str(&format!("({}:{} events)", c.frame, c.events.len()));
+            }
+            formatted.push_str("]");
+        }
         write!(f, "{}", formatted)
     }
 }
+
+// Truncates the list of events so that their total serialised size does not
+// exceed the size budget; remaining events are sent in the following syncs.
+pub(crate) fn limit_events_size<Data, P, PK, Sig>(
+    mut events: Vec<NetEvent<Data, P, PK, Sig>>,
+    budget: u64,
+) -> Vec<NetEvent<Data, P, PK, Sig>>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    let mut budget = budget;
+    let mut count = 0;
+    for e in events.iter() {
+        let size = match bincode::serialized_size(e) {
+            Ok(size) => size,
+            Err(_) => break,
+        };
+        if size > budget {
+            break;
+        }
+        budget -= size;
+        count += 1;
+    }
+    if count < events.len() {
+        debug!(
+            "limit_events_size: {} of {} events fit into the budget",
+            count,
+            events.len()
+        );
+    }
+    events.truncate(count);
+    events
+}
+
+// Returns the number of events known to the peer and not known to the node
+// according to their gossip lists
+pub(crate) fn gossip_lag<P>(local: &GossipList<P>, remote: &GossipList<P>) -> usize
+where
+    P: PeerId,
+{
+    remote
+        .iter()
+        .map(|(creator, gossip)| match local.get(creator) {
+            Some(known) => gossip.height.saturating_sub(known.height),
+            None => 0,
+        })
+        .sum()
+}
+
+// A Sync Request sent to a peer and not answered yet
+#[derive(Clone, Debug)]
+pub(crate) struct PendingSync<P> {
+    pub(crate) peer: P,
+    // number of retries made so far
+    pub(crate) attempt: usize,
+    pub(crate) sent_at: Instant,
+    // Some() when the request has timed out and waits to be sent again
+    pub(crate) retry_at: Option<Instant>,
+}
+
+// SyncTracker keeps track of Sync Requests sent by procedure A;
+// it assigns request IDs, matches Sync Replies with their requests
+// and schedules retries of timed out requests with exponential backoff.
+pub(crate) struct SyncTracker<P> {
+    next_id: RequestId,
+    pending: HashMap<RequestId, PendingSync<P>>,
+}
+
+impl<P> Default for SyncTracker<P>
+where
+    P: PeerId,
+{
+    fn default() -> SyncTracker<P> {
+        SyncTracker {
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<P> SyncTracker<P>
+where
+    P: PeerId,
+{
+    // Maximal power of two used for backoff calculation
+    const MAX_BACKOFF_EXP: u32 = 10;
+
+    // Registers a new Sync Request to the peer and returns its ID
+    pub(crate) fn start(&mut self, peer: P) -> RequestId {
+        self.next_id += 1;
+        self.pending.insert(
+            self.next_id,
+            PendingSync {
+                peer,
+                attempt: 0,
+                sent_at: Instant::now(),
+                retry_at: None,
+            },
+        );
+        self.next_id
+    }
+
+    // Completes the pending request answered by a Sync Reply.
+    // Returns round trip time of the request if the reply matches
+    // a pending request sent to the very same peer; None otherwise.
+    pub(crate) fn complete(&mut self, id: RequestId, from: &P) -> Option<Duration> {
+        match self.pending.get(&id) {
+            Some(p) if p.peer == *from => {}
+            _ => return None,
+        }
+        self.pending.remove(&id).map(|p| p.sent_at.elapsed())
+    }
+
+    // Finds requests not answered within timeout. Those which still have
+    // retries left are scheduled for resending with exponential backoff,
+    // the others are dropped. Returns peers of all timed out requests.
+    pub(crate) fn expire(
+        &mut self,
+        timeout: Duration,
+        backoff: Duration,
+        retries: usize,
+    ) -> Vec<P> {
+        let now = Instant::now();
+        let mut timed_out = Vec::new();
+        let mut dropped = Vec::new();
+        for (id, p) in self.pending.iter_mut() {
+            if p.retry_at.is_some() || now.duration_since(p.sent_at) < timeout {
+                continue;
+            }
+            timed_out.push(p.peer.clone());
+            if p.attempt < retries {
+                let exp = (p.attempt as u32).min(Self::MAX_BACKOFF_EXP);
+                p.retry_at = Some(now + backoff * 2u32.pow(exp));
+            } else {
+                dropped.push(*id);
+            }
+        }
+        for id in dropped.iter() {
+            self.pending.remove(id);
+        }
+        timed_out
+    }
+
+    // Returns requests whose backoff has elapsed and marks them as sent again
+    pub(crate) fn due_retries(&mut self) -> Vec<(RequestId, P)> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (id, p) in self.pending.iter_mut() {
+            if let Some(at) = p.retry_at {
+                if at <= now {
+                    p.retry_at = None;
+                    p.attempt += 1;
+                    p.sent_at = now;
+                    due.push((*id, p.peer.clone()));
+                }
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_peers, Id};
+
+    fn peers() -> (Id, Id) {
+        let (_, keys) = new_peers(2);
+        (keys[0].0.clone(), keys[1].0.clone())
+    }
+
+    #[test]
+    fn test_replies_matched_by_request_id_and_peer() {
+        let (a, b) = peers();
+        let mut tracker = SyncTracker::<Id>::default();
+        let first = tracker.start(a.clone());
+        let second = tracker.start(b.clone());
+        assert_ne!(first, second);
+        // a reply from another peer or with an unknown ID matches nothing
+        assert_eq!(tracker.complete(first, &b), None);
+        assert_eq!(tracker.complete(second + 1, &b), None);
+        assert!(tracker.complete(first, &a).is_some());
+        // a request is completed once
+        assert_eq!(tracker.complete(first, &a), None);
+        assert!(tracker.complete(second, &b).is_some());
+    }
+
+    #[test]
+    fn test_timeouts_retried_with_backoff() {
+        let (a, _) = peers();
+        let mut tracker = SyncTracker::<Id>::default();
+        let id = tracker.start(a.clone());
+        let hour = Duration::from_secs(3600);
+        // requests within the timeout are kept as they are
+        assert!(tracker.expire(hour, hour, 2).is_empty());
+        assert_eq!(
+            tracker.expire(Duration::from_secs(0), hour, 2),
+            vec![a.clone()]
+        );
+        // a request waiting for its retry does not time out again
+        assert!(tracker.expire(Duration::from_secs(0), hour, 2).is_empty());
+        assert!(tracker.due_retries().is_empty());
+        let retry_at = tracker.pending[&id].retry_at.unwrap();
+        assert!(retry_at > Instant::now() + hour / 2);
+        // the backoff doubles with every attempt
+        tracker.pending.get_mut(&id).unwrap().retry_at = Some(Instant::now());
+        assert_eq!(tracker.due_retries(), vec![(id, a.clone())]);
+        assert_eq!(tracker.pending[&id].attempt, 1);
+        assert_eq!(
+            tracker.expire(Duration::from_secs(0), hour, 2),
+            vec![a.clone()]
+        );
+        let retry_at = tracker.pending[&id].retry_at.unwrap();
+        assert!(retry_at > Instant::now() + hour * 3 / 2);
+        // requests out of retries are dropped
+        tracker.pending.get_mut(&id).unwrap().retry_at = Some(Instant::now());
+        assert_eq!(tracker.due_retries(), vec![(id, a.clone())]);
+        assert_eq!(
+            tracker.expire(Duration::from_secs(0), hour, 2),
+            vec![a.clone()]
+        );
+        assert!(tracker.pending.is_empty());
+        assert_eq!(tracker.complete(id, &a), None);
+    }
+}
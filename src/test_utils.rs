@@ -0,0 +1,330 @@
+// Helpers shared by unit tests: peer lists with fresh keys, recorded DAGs
+// and cores fed with them
+
+use crate::conf::DAGconfig;
+use crate::core::DAGcore;
+use crate::engine::{FinalityEngine, Participants};
+use crate::event::Event;
+use crate::frame::{Frame, FrameRecord};
+use crate::hlc::HybridTime;
+use crate::peer::{DAGPeer, DAGPeerList, FrameNumber};
+use crate::store::{DAGstore, DAGstoreType, StoreOptions};
+use crate::store_mem::MemStore;
+use core::fmt::{Display, Formatter};
+use libcommon_rs::peer::{Peer, PeerList};
+use libconsensus::ConsensusConfiguration;
+use libhash_sha3::Hash as EventHash;
+use libsignature::Signature as LibSignature;
+use libsignature_ed25519_dalek::{PublicKey, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub(crate) type Id = PublicKey;
+pub(crate) type Sig = Signature<EventHash>;
+pub(crate) type TestEvent = Event<Data, Id, PublicKey, Sig>;
+pub(crate) type TestCore = DAGcore<Id, Data, SecretKey, PublicKey, Sig>;
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize, Hash, Copy)]
+pub(crate) struct Data {
+    pub(crate) value: u32,
+}
+
+impl Display for Data {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+// Returns an empty directory for a store of the test
+pub(crate) fn temp_store_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("libconsensus-dag-{}", name));
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
+
+// Creates a list of `n` peers with fresh key pairs; keys are returned
+// in the order of peers in the list
+pub(crate) fn new_peers(n: usize) -> (DAGPeerList<Id, PublicKey>, Vec<(PublicKey, SecretKey)>) {
+    let mut keys: Vec<(PublicKey, SecretKey)> =
+        (0..n).map(|_| Sig::generate_key_pair().unwrap()).collect();
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut peers = DAGPeerList::<Id, PublicKey>::default();
+    for (i, (pk, _)) in keys.iter().enumerate() {
+        let mut peer =
+            DAGPeer::<Id, PublicKey>::new(pk.clone(), format!("127.0.0.1:{}", 20001 + 2 * i));
+        peer.set_public_key(pk.clone());
+        peers.add(peer).unwrap();
+    }
+    (peers, keys)
+}
+
+// A DAG recorded as a list of events in the order of their creation;
+// every creator starts with an unsigned leaf event as DAGcore does
+pub(crate) struct RecordedDag {
+    pub(crate) peers: DAGPeerList<Id, PublicKey>,
+    pub(crate) keys: Vec<(PublicKey, SecretKey)>,
+    pub(crate) events: Vec<TestEvent>,
+    // index of the latest event of every creator in `events`
+    latest: Vec<usize>,
+}
+
+impl RecordedDag {
+    pub(crate) fn new(n: usize) -> RecordedDag {
+        let (peers, keys) = new_peers(n);
+        let mut dag = RecordedDag {
+            peers,
+            keys,
+            events: Vec::new(),
+            latest: Vec::with_capacity(n),
+        };
+        for i in 0..n {
+            let mut event = TestEvent::new(
+                dag.keys[i].0.clone(),
+                0,
+                EventHash::default(),
+                EventHash::default(),
+                0,
+                HybridTime::default(),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+            );
+            event.event_hash().unwrap();
+            dag.latest.push(dag.events.len());
+            dag.events.push(event);
+        }
+        dag
+    }
+
+    pub(crate) fn participants(&self) -> Participants<Id> {
+        Participants {
+            creators: self.keys.iter().map(|k| k.0.clone()).collect(),
+            supermajority: self.peers.root_majority(),
+        }
+    }
+
+    pub(crate) fn creator(&self, i: usize) -> Id {
+        self.keys[i].0.clone()
+    }
+
+    pub(crate) fn latest(&self, creator: usize) -> &TestEvent {
+        &self.events[self.latest[creator]]
+    }
+
+    // Adds a signed event of creator `creator` whose other-parent is the latest
+    // event of creator `other`; returns the event created
+    pub(crate) fn add(
+        &mut self,
+        creator: usize,
+        other: usize,
+        transactions: Vec<Data>,
+    ) -> TestEvent {
+        self.add_with_arrivals(creator, other, transactions, Vec::new())
+    }
+
+    // Adds an event as add() does, recording arrivals of transactions
+    pub(crate) fn add_with_arrivals(
+        &mut self,
+        creator: usize,
+        other: usize,
+        transactions: Vec<Data>,
+        tx_arrivals: Vec<EventHash>,
+    ) -> TestEvent {
+        let self_parent = self.latest(creator).clone();
+        let other_parent = self.latest(other).clone();
+        let lamport_time = self_parent
+            .lamport_timestamp
+            .max(other_parent.lamport_timestamp)
+            + 1;
+        let timestamp = self_parent
+            .timestamp
+            .max(other_parent.timestamp)
+            .successor();
+        let mut event = TestEvent::new(
+            self.creator(creator),
+            self_parent.height + 1,
+            self_parent.hash,
+            other_parent.hash,
+            lamport_time,
+            timestamp,
+            transactions,
+            tx_arrivals,
+            Vec::new(),
+        );
+        let hash = event.event_hash().unwrap();
+        let (pk, sk) = self.keys[creator].clone();
+        let signature = Sig::sign(hash, pk.clone(), sk).unwrap();
+        event.signatures.insert(pk, signature);
+        self.latest[creator] = self.events.len();
+        self.events.push(event.clone());
+        event
+    }
+
+    // Adds rounds of gossip where every creator in turn syncs with the next one
+    pub(crate) fn gossip(&mut self, rounds: usize) {
+        let n = self.keys.len();
+        for r in 0..rounds {
+            for i in 0..n {
+                let other = (i + 1 + r % (n - 1)) % n;
+                self.add(i, other, Vec::new());
+            }
+        }
+    }
+}
+
+pub(crate) type Store = dyn DAGstore<Data, Id, PublicKey, Sig>;
+pub(crate) type Engine = dyn FinalityEngine<Data, Id, PublicKey, Sig>;
+
+pub(crate) fn mem_store() -> MemStore {
+    <MemStore as DAGstore<Data, Id, PublicKey, Sig>>::new(
+        std::path::Path::new(""),
+        &StoreOptions::default(),
+    )
+    .unwrap()
+}
+
+// Adds events to the engine and the store as DAGcore does and stores
+// the batches finalised; returns frame numbers of the batches with hashes
+// of their events in the order given by the engine
+pub(crate) fn feed(
+    engine: &mut Engine,
+    store: &mut Store,
+    participants: &Participants<Id>,
+    events: &[TestEvent],
+) -> Vec<(FrameNumber, Vec<EventHash>)> {
+    let mut finalised = Vec::new();
+    for event in events.iter() {
+        let mut event = event.clone();
+        if event.self_parent == EventHash::default() {
+            engine.add_leaf_event(store, &event, participants).unwrap();
+            store.set_event(event).unwrap();
+            continue;
+        }
+        engine.add_event(store, &mut event, participants).unwrap();
+        store.set_event(event).unwrap();
+        for batch in engine
+            .finalised_batches(store, participants)
+            .unwrap()
+            .into_iter()
+        {
+            let hashes: Vec<EventHash> = batch.events.iter().map(|r| r.hash).collect();
+            let frame = Frame {
+                events: batch.events,
+                tx_order: Vec::new(),
+            };
+            store.set_finalised_frame(batch.frame, frame).unwrap();
+            finalised.push((batch.frame, hashes));
+        }
+    }
+    finalised
+}
+
+// Creates the configuration of creator `i` of the DAG with a sled store in the
+// directory, or with an in-memory store if no directory is given
+pub(crate) fn new_conf(
+    dag: &RecordedDag,
+    i: usize,
+    store_dir: Option<&Path>,
+) -> DAGconfig<Id, Data, SecretKey, PublicKey> {
+    let mut conf = DAGconfig::<Id, Data, SecretKey, PublicKey>::new();
+    conf.creator = dag.creator(i);
+    conf.public_key = dag.keys[i].0.clone();
+    conf.secret_key = dag.keys[i].1.clone();
+    conf.peers = dag.peers.clone();
+    conf.store_profile = false;
+    match store_dir {
+        Some(dir) => {
+            conf.store_type = DAGstoreType::Sled;
+            conf.store_dir = dir.to_path_buf();
+        }
+        None => conf.store_type = DAGstoreType::Memory,
+    }
+    conf
+}
+
+// Creates the core of creator `i` of the DAG as configured by new_conf()
+pub(crate) fn new_core(dag: &RecordedDag, i: usize, store_dir: Option<&Path>) -> TestCore {
+    DAGcore::new(new_conf(dag, i, store_dir)).unwrap()
+}
+
+// Inserts recorded events other than leaf events into the core as gossip does
+pub(crate) fn insert_events(core: &mut TestCore, events: &[TestEvent]) {
+    for event in events
+        .iter()
+        .filter(|e| e.self_parent != EventHash::default())
+    {
+        core.insert_event(event.clone()).unwrap();
+        if let Ok(peer) = core
+            .conf
+            .write()
+            .unwrap()
+            .peers
+            .find_peer_mut(&event.creator)
+        {
+            peer.update_lamport_time_and_height(event.lamport_timestamp, event.height);
+        }
+    }
+}
+
+// Returns a finalised frame of the events
+pub(crate) fn frame_of(events: &[&TestEvent]) -> Frame {
+    Frame {
+        events: events
+            .iter()
+            .map(|e| FrameRecord {
+                hash: e.hash,
+                lamport_time: e.lamport_timestamp,
+                timestamp: e.timestamp,
+                received_time: None,
+                consensus_timestamp: HybridTime::default(),
+            })
+            .collect(),
+        tx_order: Vec::new(),
+    }
+}
+
+// Checks pruning of finalised frames by the store: the latest events of
+// creators are kept until their next events are stored and a later frame
+// is pruned; events removed stay marked finalised
+pub(crate) fn check_pruning(store: &mut Store) {
+    let mut dag = RecordedDag::new(2);
+    dag.gossip(1);
+    for event in dag.events.iter() {
+        store.set_event(event.clone()).unwrap();
+    }
+    let first: Vec<&TestEvent> = dag.events.iter().collect();
+    store.set_finalised_frame(0, frame_of(&first)).unwrap();
+    store.prune_finalised_frame(0).unwrap();
+    let (leaf, latest) = (dag.events[0].clone(), dag.events[2].clone());
+    assert!(store.get_event(&leaf.hash).is_err());
+    assert!(store.is_finalised(&leaf.hash).unwrap());
+    assert_eq!(store.get_event(&latest.hash).unwrap(), latest);
+    let records: Vec<EventHash> = store
+        .get_frame(0)
+        .unwrap()
+        .events
+        .iter()
+        .map(|r| r.hash)
+        .collect();
+    assert!(!records.contains(&leaf.hash));
+    assert!(records.contains(&latest.hash));
+    assert!(store.get_finalised_frame(0).is_err());
+    // the next events of creators are stored; an empty frame is pruned
+    dag.gossip(1);
+    for event in dag.events[4..].iter() {
+        store.set_event(event.clone()).unwrap();
+    }
+    store.set_finalised_frame(1, frame_of(&[])).unwrap();
+    store.prune_finalised_frame(1).unwrap();
+    assert!(store.get_event(&latest.hash).is_err());
+    assert!(store.is_finalised(&latest.hash).unwrap());
+    let records: Vec<EventHash> = store
+        .get_frame(0)
+        .unwrap()
+        .events
+        .iter()
+        .map(|r| r.hash)
+        .collect();
+    assert!(!records.contains(&latest.hash));
+    assert!(records.contains(&dag.events[4].hash));
+}
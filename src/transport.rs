@@ -0,0 +1,299 @@
+// Length-prefixed TCP transport of Sync Requests and Sync Replies
+//
+// Every message is sent over its own connection as its length, a big-endian
+// u64, followed by the bincode encoded message. The receiver checks the length
+// before reading the message, so messages above the maximal message size are
+// dropped without being read into memory; addresses of their senders are
+// reported, so the peers can be held responsible.
+//
+// Connecting, writing and reading are bounded in time, so an unreachable or
+// slow peer holds neither the sender nor the receiver for long, and every host
+// may keep only a few connections open, so a single host cannot take all
+// connections of the receiver.
+
+use crate::errors::{Error, Result};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Delay between checks for incoming connections and the quit signal
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+// Time allowed for a peer to send the whole message
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+// Time allowed to connect to a peer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Time allowed for a single write to a peer
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// Maximal number of connections read at the same time
+const MAX_CONNECTIONS: usize = 64;
+// Maximal number of connections of a single host read at the same time
+const MAX_HOST_CONNECTIONS: usize = 8;
+
+// Sends the message to the address
+pub(crate) fn send_message<M>(address: &str, message: &M) -> Result<()>
+where
+    M: Serialize,
+{
+    let bytes = bincode::serialize(message)?;
+    let mut stream = connect(address)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.write_all(&(bytes.len() as u64).to_be_bytes())?;
+    stream.write_all(&bytes)?;
+    Ok(())
+}
+
+// Connects to the first address the address resolves to which accepts
+// the connection in time
+fn connect(address: &str) -> Result<TcpStream> {
+    let mut last_error = None;
+    for socket in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(match last_error {
+        Some(e) => e.into(),
+        None => Error::NoneError.into(),
+    })
+}
+
+// Reads exactly enough bytes to fill the buffer before the deadline
+fn read_exact_by(stream: &mut TcpStream, buf: &mut [u8], deadline: Instant) -> Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(std::io::Error::from(ErrorKind::TimedOut).into());
+        }
+        stream.set_read_timeout(Some(deadline - now))?;
+        match stream.read(&mut buf[read..]) {
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+// Reads a single message from the connection; messages whose length exceeds
+// `max_message_size` are rejected before they are read
+fn read_message<M>(mut stream: TcpStream, max_message_size: u64) -> Result<M>
+where
+    M: DeserializeOwned,
+{
+    stream.set_nonblocking(false)?;
+    let deadline = Instant::now() + READ_TIMEOUT;
+    let mut length = [0u8; 8];
+    read_exact_by(&mut stream, &mut length, deadline)?;
+    let length = u64::from_be_bytes(length);
+    if length > max_message_size {
+        return Err(Error::OversizedMessage(length, max_message_size).into());
+    }
+    let mut bytes = vec![0u8; length as usize];
+    read_exact_by(&mut stream, &mut bytes, deadline)?;
+    // lengths of collections within the message are bounded as well
+    Ok(bincode::config()
+        .limit(max_message_size)
+        .deserialize(&bytes)?)
+}
+
+// Stream of messages received on the bind address
+pub(crate) struct FramedReceiver<M> {
+    rx: UnboundedReceiver<M>,
+    quit_tx: Sender<()>,
+    // hosts which have sent oversized messages
+    rejected_rx: Receiver<IpAddr>,
+}
+
+impl<M> FramedReceiver<M>
+where
+    M: DeserializeOwned + Send + 'static,
+{
+    pub(crate) fn new(bind_address: String, max_message_size: u64) -> Result<FramedReceiver<M>> {
+        let listener = TcpListener::bind(bind_address)?;
+        listener.set_nonblocking(true)?;
+        let (tx, rx) = unbounded();
+        let (quit_tx, quit_rx) = mpsc::channel();
+        let (rejected_tx, rejected_rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("receiver".to_string())
+            .spawn(move || accept(listener, tx, rejected_tx, quit_rx, max_message_size))?;
+        Ok(FramedReceiver {
+            rx,
+            quit_tx,
+            rejected_rx,
+        })
+    }
+    // Returns the channel stopping the receiver
+    pub(crate) fn get_quit_tx(&self) -> Sender<()> {
+        self.quit_tx.clone()
+    }
+}
+
+impl<M> FramedReceiver<M> {
+    // Returns hosts which have sent oversized messages since the last call
+    pub(crate) fn take_rejected(&self) -> Vec<IpAddr> {
+        self.rejected_rx.try_iter().collect()
+    }
+}
+
+impl<M> Stream for FramedReceiver<M> {
+    type Item = M;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<M>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+// Connections being read, in total and by host
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    by_host: HashMap<IpAddr, usize>,
+}
+
+impl Connections {
+    // Registers a new connection of the host unless caps are reached
+    fn open(&mut self, host: IpAddr) -> bool {
+        let of_host = self.by_host.entry(host).or_insert(0);
+        if self.total >= MAX_CONNECTIONS || *of_host >= MAX_HOST_CONNECTIONS {
+            return false;
+        }
+        *of_host += 1;
+        self.total += 1;
+        true
+    }
+    fn close(&mut self, host: IpAddr) {
+        if let Some(of_host) = self.by_host.get_mut(&host) {
+            *of_host -= 1;
+            self.total -= 1;
+            if *of_host == 0 {
+                self.by_host.remove(&host);
+            }
+        }
+    }
+}
+
+// Accepts connections until the quit signal; every connection is read
+// in its own thread
+fn accept<M>(
+    listener: TcpListener,
+    tx: UnboundedSender<M>,
+    rejected_tx: Sender<IpAddr>,
+    quit_rx: Receiver<()>,
+    max_message_size: u64,
+) where
+    M: DeserializeOwned + Send + 'static,
+{
+    let connections = Arc::new(Mutex::new(Connections::default()));
+    loop {
+        match quit_rx.try_recv() {
+            Ok(_) | Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {}
+        }
+        let (stream, from) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                error!("transport: accept error: {:?}", e);
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+        };
+        if !connections.lock().unwrap().open(from.ip()) {
+            warn!("transport: too many connections; {} dropped", from);
+            continue;
+        }
+        let tx = tx.clone();
+        let rejected_tx = rejected_tx.clone();
+        let open = connections.clone();
+        let spawned = thread::Builder::new()
+            .name("connection".to_string())
+            .spawn(move || {
+                match read_message(stream, max_message_size) {
+                    Ok(message) => {
+                        let _ = tx.unbounded_send(message);
+                    }
+                    Err(e) => {
+                        warn!("transport: message from {} dropped: {:?}", from, e);
+                        if let Some(Error::OversizedMessage(_, _)) = e.downcast_ref::<Error>() {
+                            let _ = rejected_tx.send(from.ip());
+                        }
+                    }
+                }
+                open.lock().unwrap().close(from.ip());
+            });
+        if let Err(e) = spawned {
+            error!("transport: connection thread error: {:?}", e);
+            connections.lock().unwrap().close(from.ip());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream::StreamExt;
+
+    #[test]
+    fn test_oversized_message_dropped_before_reading() {
+        let address = "127.0.0.1:19431";
+        let mut receiver = FramedReceiver::<Vec<u8>>::new(address.to_string(), 64).unwrap();
+        // the connection may be closed by the receiver while the message is written
+        let _ = send_message(address, &vec![0u8; 1024]);
+        send_message(address, &vec![1u8; 8]).unwrap();
+        assert_eq!(block_on(receiver.next()), Some(vec![1u8; 8]));
+        assert_eq!(
+            receiver.take_rejected(),
+            vec!["127.0.0.1".parse::<IpAddr>().unwrap()]
+        );
+        receiver.get_quit_tx().send(()).unwrap();
+    }
+
+    #[test]
+    fn test_connections_capped_by_host() {
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let mut connections = Connections::default();
+        for _ in 0..MAX_HOST_CONNECTIONS {
+            assert!(connections.open(a));
+        }
+        // a host at its cap leaves connections to the others
+        assert!(!connections.open(a));
+        assert!(connections.open(b));
+        connections.close(a);
+        assert!(connections.open(a));
+        assert_eq!(connections.total, MAX_HOST_CONNECTIONS + 1);
+        // a host with no connections is forgotten
+        connections.close(b);
+        assert!(!connections.by_host.contains_key(&b));
+    }
+
+    #[test]
+    fn test_slow_sender_cut_off() {
+        let address = "127.0.0.1:19433";
+        let listener = TcpListener::bind(address).unwrap();
+        let mut sender = TcpStream::connect(address).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        // a part of the length only
+        sender.write_all(&[0u8; 4]).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let mut length = [0u8; 8];
+        assert!(read_exact_by(&mut stream, &mut length, deadline).is_err());
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,273 @@
+// Offline integrity check of a sled store
+//
+// The check covers primary data, events, and data derived from it: frame
+// records and flag tables. Derived data can be repaired from events; problems
+// with events themselves are reported only. Missing parents are not reported
+// for stores pruned by a retention policy.
+
+use crate::engine::{FinalityEngine, FinalityType, Participants};
+use crate::errors::Result;
+use crate::event::Event;
+use crate::frame::FrameRecord;
+use crate::hlc::HybridTime;
+use crate::lachesis::Lachesis;
+use crate::peer::DAGPeerList;
+use crate::store::{DAGstore, Durability, StoreOptions};
+use crate::store_sled::SledStore;
+use bincode::serialize;
+use libcommon_rs::data::DataType;
+use libcommon_rs::peer::{Peer, PeerId, PeerList};
+use libhash_sha3::Hash as EventHash;
+use libsignature::{PublicKey, Signature};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use to_vec::ToVec;
+
+// Result of the store integrity check
+#[derive(Clone, Debug, Default)]
+pub struct IntegrityReport {
+    // number of distinct events found
+    pub events: usize,
+    // problems found, one per line
+    pub problems: Vec<String>,
+    // repairs made
+    pub repaired: Vec<String>,
+}
+
+impl IntegrityReport {
+    // Checks if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks integrity of the store in the directory of a node which is not running
+/// and, if requested, repairs frame records, flag tables and creator-height keys.
+pub fn verify_store<Data, P, PK, Sig>(
+    store_dir: &Path,
+    peers: &DAGPeerList<P, PK>,
+    finality: &FinalityType,
+    repair: bool,
+) -> Result<IntegrityReport>
+where
+    Data: DataType,
+    P: PeerId,
+    PK: PublicKey,
+    Sig: Signature<Hash = EventHash, PublicKey = PK>,
+{
+    let options = StoreOptions {
+        durability: Durability::EveryWrite,
+        cache_capacity: 64 * 1024 * 1024,
+        profile: false,
+        cache_entries: 0,
+    };
+    // the store is written only when it is to be repaired
+    let mut store = if repair {
+        <SledStore as DAGstore<Data, P, PK, Sig>>::new(store_dir, &options)?
+    } else {
+        SledStore::open_read_only(store_dir, &options)?
+    };
+    let mut report = IntegrityReport::default();
+    let pruned = store.is_pruned();
+
+    // events by hash key and creator-height keys
+    let mut events: HashMap<EventHash, Event<Data, P, PK, Sig>> = HashMap::new();
+    let mut height_keys: HashMap<Vec<u8>, EventHash> = HashMap::new();
+    for (key, event) in store.event_entries::<Data, P, PK, Sig>()?.into_iter() {
+        if key == event.hash.to_vec() {
+            events.insert(event.hash, event);
+        } else {
+            let expected = format!("{}-{}", event.creator, event.height).into_bytes();
+            if key != expected {
+                report.problems.push(format!(
+                    "event {} stored under key {}",
+                    event.hash,
+                    String::from_utf8_lossy(&key)
+                ));
+            }
+            height_keys.insert(expected, event.hash);
+        }
+    }
+    report.events = events.len();
+    for (key, ex) in height_keys.iter() {
+        if !events.contains_key(ex) {
+            report.problems.push(format!(
+                "key {} refers to event {} missing under its hash",
+                String::from_utf8_lossy(key),
+                ex
+            ));
+        }
+    }
+
+    for (ex, event) in events.iter() {
+        let mut rehashed = event.clone();
+        if rehashed.event_hash()? != *ex {
+            report
+                .problems
+                .push(format!("event {} does not match its hash", ex));
+        }
+        for (signatory, signature) in event.signatures.iter() {
+            match peers.find_peer(signatory) {
+                Ok(peer) => {
+                    if !signature.verify(*ex, peer.get_public_key())? {
+                        report
+                            .problems
+                            .push(format!("event {}: invalid signature of {}", ex, signatory));
+                    }
+                }
+                Err(_) => report
+                    .problems
+                    .push(format!("event {}: unknown signatory {}", ex, signatory)),
+            }
+        }
+        for parent in [event.self_parent, event.other_parent].iter() {
+            if *parent != EventHash::default() && !events.contains_key(parent) && !pruned {
+                report
+                    .problems
+                    .push(format!("event {}: parent {} missing", ex, parent));
+            }
+        }
+        let key = format!("{}-{}", event.creator, event.height).into_bytes();
+        if !height_keys.contains_key(&key) {
+            report.problems.push(format!(
+                "event {}: no key {}",
+                ex,
+                String::from_utf8_lossy(&key)
+            ));
+            if repair {
+                store.set_event_entry(key, serialize(event)?)?;
+                report
+                    .repaired
+                    .push(format!("event {}: creator-height key restored", ex));
+            }
+        }
+    }
+
+    // frame records
+    let mut recorded: HashSet<EventHash> = HashSet::new();
+    for (frame, record) in store.frame_records()?.into_iter() {
+        if !events.contains_key(&record.hash) {
+            report.problems.push(format!(
+                "frame {}: record of missing event {}",
+                frame, record.hash
+            ));
+            if repair {
+                store.remove_frame_record(frame, &record.hash)?;
+                report.repaired.push(format!(
+                    "frame {}: record of {} removed",
+                    frame, record.hash
+                ));
+            }
+        }
+        recorded.insert(record.hash);
+    }
+    for (ex, event) in events.iter() {
+        if recorded.contains(ex) {
+            continue;
+        }
+        report
+            .problems
+            .push(format!("event {}: no frame record", ex));
+        if repair {
+            let record = FrameRecord {
+                hash: *ex,
+                lamport_time: event.lamport_timestamp,
+                timestamp: event.timestamp,
+                received_time: None,
+                consensus_timestamp: HybridTime::default(),
+            };
+            store.set_frame_record(event.frame_number, &record)?;
+            report
+                .repaired
+                .push(format!("event {}: frame record restored", ex));
+        }
+    }
+
+    // flag tables are kept by Lachesis finality engine only
+    if *finality != FinalityType::Lachesis {
+        return Ok(report);
+    }
+    let mut missing = 0;
+    for ex in events.keys() {
+        if !store.has_flag_table(ex)? {
+            report.problems.push(format!("event {}: no flag table", ex));
+            missing += 1;
+        }
+    }
+    if missing == 0 || !repair {
+        return Ok(report);
+    }
+    if pruned {
+        report
+            .problems
+            .push("flag tables cannot be rebuilt in a pruned store".to_string());
+        return Ok(report);
+    }
+    // rebuild flag tables replaying events; parents precede their children
+    // in Lamport time order
+    let participants = Participants {
+        creators: peers.iter().map(|p| p.get_id()).collect(),
+        supermajority: peers.root_majority(),
+    };
+    let mut ordered: Vec<Event<Data, P, PK, Sig>> = events.values().cloned().collect();
+    ordered.sort_by(|a, b| a.lamport_timestamp.cmp(&b.lamport_timestamp));
+    let mut engine = Lachesis::default();
+    let store: &mut dyn DAGstore<Data, P, PK, Sig> = &mut store;
+    for event in ordered.iter_mut() {
+        let frame = event.frame_number;
+        if event.self_parent == EventHash::default() {
+            engine.add_leaf_event(store, event, &participants)?;
+        } else {
+            engine.add_event(store, event, &participants)?;
+            if event.frame_number != frame {
+                report.problems.push(format!(
+                    "event {}: stored frame {}, computed frame {}",
+                    event.hash, frame, event.frame_number
+                ));
+            }
+        }
+    }
+    report
+        .repaired
+        .push(format!("{} flag tables rebuilt", ordered.len()));
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{insert_events, new_core, temp_store_dir, Data, Id, RecordedDag, Sig};
+
+    #[test]
+    fn test_verify_and_repair_frame_records() {
+        let mut dag = RecordedDag::new(4);
+        dag.gossip(12);
+        let dir = temp_store_dir("verify");
+        {
+            let mut core = new_core(&dag, 0, Some(&dir));
+            insert_events(&mut core, &dag.events);
+        }
+        let path = dir.join(dag.creator(0).to_string());
+        let verify = |repair: bool| {
+            verify_store::<Data, Id, Id, Sig>(&path, &dag.peers, &FinalityType::Lachesis, repair)
+                .unwrap()
+        };
+        let report = verify(false);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.events, dag.events.len());
+        {
+            let mut store =
+                <SledStore as DAGstore<Data, Id, Id, Sig>>::new(&path, &StoreOptions::default())
+                    .unwrap();
+            let (frame, record) = store.frame_records().unwrap()[0];
+            store.remove_frame_record(frame, &record.hash).unwrap();
+        }
+        let report = verify(false);
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.repaired.is_empty());
+        let report = verify(true);
+        assert_eq!(report.repaired.len(), 1);
+        let report = verify(false);
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
+}